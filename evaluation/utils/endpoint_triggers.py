@@ -1,18 +1,18 @@
 import asyncio
+import json
 import aiohttp
 from evaluation.setup import *
 from evaluation.utils.common import send_post_request
-from evaluation.utils.create_payload import get_keygen_payloads, create_sign_payload, get_payloads_layout
+from evaluation.utils.create_payload import create_sign_payload, get_payloads_layout
 
 
 async def trigger_keygen_endpoint(n):
     urls = get_endpoint_urls(n)
-    payloads = get_keygen_payloads(n, IS_DOCKER)
 
     async with aiohttp.ClientSession() as session:
         tasks = []
         for i in range(n):
-            tasks.append(send_post_request(session, f"{urls[i]}/key_gen/1", payloads[i]))
+            tasks.append(send_post_request(session, f"{urls[i]}/key_gen/1", ""))
 
         return await asyncio.gather(*tasks)
 
@@ -46,7 +46,6 @@ async def trigger_sign_endpoint_in_multiple_rooms(session,
         payloads = {}
         for pl_key, pl_val in payloads_layout.items():
             payloads[pl_key] = create_sign_payload([x[0] for x in pl_val],
-                                                   [x[1] for x in pl_val],
                                                    data,
                                                    timestamp)
 
@@ -57,7 +56,10 @@ async def trigger_sign_endpoint_in_multiple_rooms(session,
 
 
 async def trigger_verify_endpoint(url, data_to_sign, signature, timestamp):
-    data_to_sign = data_to_sign.encode().hex()
-    payload = f"{signature.hex()},{data_to_sign},{timestamp}"
+    payload = json.dumps({
+        "signature": signature.hex(),
+        "data": data_to_sign.encode().hex(),
+        "timestamp": str(timestamp),
+    })
     async with aiohttp.ClientSession() as session:
         return await send_post_request(session, f"{url}/verify", payload)
@@ -2,28 +2,29 @@
 set -xeuo pipefail
 
 # First generate the SSL certificates
-bash examples/certs_creation.sh
+bash examples/certs_creation.sh 3
 
-# Start the three servers
-cargo run -- 1 8000 3000 &
+# Start the three servers, the key shares are encrypted with this passphrase
+export MPC_KEY_SHARE_PASSPHRASE="${MPC_KEY_SHARE_PASSPHRASE:-development passphrase}"
+cargo run -- --server-id 1 --threshold 1 --number-of-parties 3 --peer 2=127.0.0.1:3002,3=127.0.0.1:3003 &
 server1_pid=$!
-cargo run -- 2 8001 3001 &
+cargo run -- --server-id 2 --threshold 1 --number-of-parties 3 --peer 1=127.0.0.1:3001,3=127.0.0.1:3003 &
 server2_pid=$!
-cargo run -- 3 8002 3002 &
+cargo run -- --server-id 3 --threshold 1 --number-of-parties 3 --peer 1=127.0.0.1:3001,2=127.0.0.1:3002 &
 server3_pid=$!
 
 # Wait for servers to start
 sleep 5
 
 # Initialize the servers in the background
-curl -X POST localhost:8000/key_gen/1 -d "127.0.0.1:3001,127.0.0.1:3002" &
-curl -X POST localhost:8001/key_gen/1 -d "127.0.0.1:3002,127.0.0.1:3000" &
-curl -X POST localhost:8002/key_gen/1 -d "127.0.0.1:3001,127.0.0.1:3000" &
+curl -X POST localhost:8001/key_gen/1 &
+curl -X POST localhost:8002/key_gen/1 &
+curl -X POST localhost:8003/key_gen/1 &
 
 sleep 60
 
-curl -X POST localhost:8000/sign/2 -d "2,127.0.0.1:3001,sign_this_data,$(date +%s)" &
-curl -X POST localhost:8001/sign/2 -d "1,127.0.0.1:3000,sign_this_data,$(date +%s)" &
+curl -X POST localhost:8001/sign/2 -H "Content-Type: application/json" \
+  -d "{\"participants\":[1,2],\"data_to_sign\":\"$(echo -n sign_this_data | xxd -p)\",\"timestamp\":\"$(date +%s)\"}"
 
 sleep 100
 
@@ -24,14 +24,20 @@ if (Test-Path -Path $absolutePath -PathType Container) {
     $programArguments = @()
 
     for ($i = 1; $i -le $numServers; $i++) {
-        $programArguments += "$i", "800$i", "300$i", "$threshold", "$numServers"
+        $peers = (1..$numServers | Where-Object { $_ -ne $i } | ForEach-Object { "$_=127.0.0.1:300$_" }) -join ","
+        $programArguments += "--server-id", "$i", "--port", "800$i", "--tls-port", "300$i", "--threshold", "$threshold", "--number-of-parties", "$numServers", "--peer", "$peers"
+    }
+
+    # The key shares are encrypted with this passphrase, only for development
+    if (-not $env:MPC_KEY_SHARE_PASSPHRASE) {
+        $env:MPC_KEY_SHARE_PASSPHRASE = "development passphrase"
     }
 
     # Start the "log-signing-mpc.exe" processes
     for ($i = 1; $i -le $numServers; $i++) {
         $exePath = "log-signing-mpc.exe"
-        Write-Host "Running program: $exePath $($programArguments[(($i - 1) * 5)..($i * 5 - 1)])"
-        Start-Process -FilePath $exePath -ArgumentList $programArguments[(($i - 1) * 5)..($i * 5 - 1)]
+        Write-Host "Running program: $exePath $($programArguments[(($i - 1) * 12)..($i * 12 - 1)])"
+        Start-Process -FilePath $exePath -ArgumentList $programArguments[(($i - 1) * 12)..($i * 12 - 1)]
     }
 
     # Return the current working directory back to the previous version
@@ -39,7 +39,15 @@ case "$ACTION" in
       exit 0
     fi
     echo "Starting server with index $INDEX on http://localhost:$PORT"
-    cargo run -- "$INDEX" "$PORT" "$PORT_INTERNAL" &
+    PEERS=""
+    for i in 1 2 3; do
+      if [ "$i" -ne "$INDEX" ]; then
+        PEERS="$PEERS${PEERS:+,}$i=127.0.0.1:$(("$PORT_BASE" + "$i" - 5000))"
+      fi
+    done
+    # only for development, the key shares of a real deployment need a secret passphrase or keyfile
+    export MPC_KEY_SHARE_PASSPHRASE="${MPC_KEY_SHARE_PASSPHRASE:-development passphrase}"
+    cargo run -- --server-id "$INDEX" --port "$PORT" --tls-port "$PORT_INTERNAL" --threshold 1 --number-of-parties 3 --peer "$PEERS" &
     PID="$!"
     echo "$PID" > "$PID_FILE"
     ;;
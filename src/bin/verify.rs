@@ -0,0 +1,150 @@
+use std::path::PathBuf;
+
+use anyhow::{bail, Context, Error, Result};
+use clap::{ArgGroup, Parser};
+
+use log_signing_mpc::mpc::operations::check_signature::{signed_digest, verify_signature, DigestVersion};
+use log_signing_mpc::mpc::utils::log_ingestion::{verify_sidecar, LogFormat};
+use log_signing_mpc::mpc::utils::merkle_tree::InclusionProof;
+use log_signing_mpc::mpc::utils::parse_signature_json::SigningMode;
+use log_signing_mpc::mpc::utils::parse_verify_json::verify_chained_log;
+use log_signing_mpc::mpc::utils::public_key_formats::parse_public_key;
+use log_signing_mpc::mpc::utils::signature_formats::SignatureEncoding;
+
+/// Verifies a signature of the servers, a whole log of chained records, or an ingested log file with its sidecar,
+/// with the public key only, no server has to be running. Exits with 0 if the signature is valid, 1 if it is invalid and 2 if the input cannot be read
+#[derive(Parser, Debug)]
+#[command(name = "verify", version, about = "Offline verification of threshold ECDSA signatures")]
+#[command(group(ArgGroup::new("key").required(true).args(["public_key", "public_key_file"])))]
+#[command(group(ArgGroup::new("signed").required(true).args(["data", "data_hex", "data_file", "digest", "log"])))]
+struct Args {
+    /// Public key, hex encoded SEC1 as listed by /keys
+    #[arg(long)]
+    public_key: Option<String>,
+
+    /// File with the PEM returned by /public_key, or the hex encoded public key
+    #[arg(long)]
+    public_key_file: Option<PathBuf>,
+
+    /// The signed data as text
+    #[arg(long)]
+    data: Option<String>,
+
+    /// The signed data, hex encoded as it was sent to /sign
+    #[arg(long)]
+    data_hex: Option<String>,
+
+    /// File with the signed data, e.g. a binary log segment signed in the base64 mode
+    #[arg(long)]
+    data_file: Option<PathBuf>,
+
+    /// Digest the client sent in the digest mode, hex encoded. It is signed together with the key id and the timestamp
+    #[arg(long)]
+    digest: Option<String>,
+
+    /// Log of chained records, one JSON request of /verify with its chain per line.
+    /// All the signatures are checked, and gaps, forks and reordered records of the streams are reported
+    #[arg(long)]
+    log: Option<PathBuf>,
+
+    /// Sidecar of signatures returned by /ingest, the log file it signs is given with --data-file.
+    /// Every record must be signed, and the chained records must form unbroken chains
+    #[arg(long, requires = "data_file")]
+    sidecar: Option<PathBuf>,
+
+    /// Format of the log file of the sidecar: text or jsonl
+    #[arg(long, default_value = "text", requires = "sidecar")]
+    format: LogFormat,
+
+    /// Timestamp the data was signed with
+    #[arg(long, required_unless_present_any = ["log", "sidecar"])]
+    timestamp: Option<String>,
+
+    /// Key the data was signed with, it is part of the digest
+    #[arg(long, default_value = "default")]
+    key_id: String,
+
+    /// Version of the digest: v1, or legacy for the signatures of older versions
+    #[arg(long, default_value = "v1")]
+    digest_version: DigestVersion,
+
+    /// Hex encoded signature
+    #[arg(long, required_unless_present_any = ["log", "sidecar"])]
+    signature: Option<String>,
+
+    /// Encoding of the signature: der, compact, recoverable or json [default: detected]
+    #[arg(long)]
+    encoding: Option<SignatureEncoding>,
+
+    /// Inclusion proof of the entry as returned by /sign_batch, the signature is of the root of the batch
+    #[arg(long)]
+    proof: Option<String>,
+}
+
+fn verify(args: &Args) -> Result<bool> {
+    let public_key = match (&args.public_key, &args.public_key_file) {
+        (Some(public_key), _) => parse_public_key(public_key)?,
+        (None, Some(path)) => parse_public_key(&std::fs::read_to_string(path).with_context(|| format!("read {}", path.display()))?)?,
+        (None, None) => unreachable!("clap requires a public key"),
+    };
+
+    if let Some(path) = &args.log {
+        let log = std::fs::read_to_string(path).with_context(|| format!("read {}", path.display()))?;
+        let issues = verify_chained_log(&log, &args.key_id, &public_key);
+        for issue in &issues {
+            println!("{}", issue);
+        }
+        return Ok(issues.is_empty());
+    }
+    if let (Some(path), Some(log_path)) = (&args.sidecar, &args.data_file) {
+        let sidecar = std::fs::read_to_string(path).with_context(|| format!("read {}", path.display()))?;
+        let log = std::fs::read_to_string(log_path).with_context(|| format!("read {}", log_path.display()))?;
+        let issues = verify_sidecar(&log, args.format, &sidecar, &public_key)?;
+        for issue in &issues {
+            println!("{}", issue);
+        }
+        return Ok(issues.is_empty());
+    }
+
+    let data = match (&args.data, &args.data_hex, &args.data_file) {
+        (Some(data), _, _) => Some(data.as_bytes().to_vec()),
+        (None, Some(data_hex), _) => Some(hex::decode(data_hex).context("The data is not hex encoded")?),
+        (None, None, Some(path)) => Some(std::fs::read(path).with_context(|| format!("read {}", path.display()))?),
+        (None, None, None) => None,
+    };
+    // clap requires the timestamp unless a log is verified
+    let timestamp = args.timestamp.as_deref().unwrap_or_default();
+    let digest = match (data, &args.digest) {
+        (Some(data), _) => signed_digest(args.digest_version, &args.key_id, &data, timestamp),
+        (None, Some(digest)) => SigningMode::Digest.signed_digest(args.digest_version, &args.key_id, digest, timestamp).map_err(Error::msg)?,
+        (None, None) => unreachable!("clap requires the data or the digest"),
+    };
+    let digest = match &args.proof {
+        None => digest,
+        Some(_) if args.digest_version == DigestVersion::Legacy => bail!("Batches are only signed with the v1 digest"),
+        Some(proof) => {
+            let proof: InclusionProof = serde_json::from_str(proof).context("Parse the inclusion proof")?;
+            proof.signed_digest(&args.key_id, &digest, timestamp)?
+        }
+    };
+
+    // clap requires the signature unless a log is verified
+    let signature = hex::decode(args.signature.as_deref().unwrap_or_default()).context("The signature is not hex encoded")?;
+    verify_signature(&signature, args.encoding, &digest, &public_key)
+}
+
+fn main() {
+    let args = Args::parse();
+    let verified = if args.log.is_some() || args.sidecar.is_some() { "log" } else { "signature" };
+    match verify(&args) {
+        Ok(true) => println!("Valid {}", verified),
+        Ok(false) => {
+            println!("Invalid {}", verified);
+            std::process::exit(1);
+        }
+        Err(e) => {
+            eprintln!("Unable to verify: {:#}", e);
+            std::process::exit(2);
+        }
+    }
+}
@@ -1,84 +1,129 @@
 use std::{
     collections::HashMap,
-    sync::Arc,
-    fs::File,
-    io::BufReader,
-    io::Read,
+    sync::{Arc, Mutex},
+    path::Path,
+    time::Duration,
 };
 use futures::{
-    Sink, SinkExt, Stream, StreamExt,
+    Future, Sink, SinkExt, Stream, StreamExt,
 };
-use tokio::sync::RwLock;
-use reqwest::{Client, Certificate, Identity};
+use tokio::sync::{Notify, RwLock};
+use reqwest::Client;
 
-use rustls_pemfile::{certs};
 use round_based::Msg;
 
-use anyhow::{Context, Result};
+use anyhow::{anyhow, Context, Result};
 use serde::{de::DeserializeOwned, Serialize};
 use tokio::spawn;
 
 
+use crate::communication::peer_registry::PeerRegistry;
+use crate::communication::pending_messages::PendingMessages;
+use crate::communication::phase::Phase;
+use crate::communication::phase_timeout::{unresponsive_parties, PhaseTimeout};
+use crate::communication::ready_parties::ReadyParties;
 use crate::communication::room::Room;
+use crate::communication::tls::TlsIdentity;
+use crate::mpc_config::ProtocolTimeouts;
 
-pub fn create_tls_config(server_id: u16, dir: &str) -> Client {
-    // Load CA certificate
-    let ca_cert = File::open(format!("{dir}/ca_cert.pem"));
-
-    let mut client = Client::builder()
-        .use_rustls_tls()
-        .danger_accept_invalid_certs(true)
-        ;
-
-    if let Ok(ca_cert) = ca_cert {
-        let mut ca_cert_reader = BufReader::new(ca_cert);
-        if let Ok(ca_certs) = certs(&mut ca_cert_reader) {
-            for ca_cert in ca_certs {
-                let cert = Certificate::from_der(&*ca_cert);
-                client = client.add_root_certificate(cert.unwrap());
-            }
-        }
-    }
+// how long to wait before announcing readiness again to a server that could not be reached
+const READY_RETRY_DELAY: Duration = Duration::from_millis(200);
 
-    // Load public certificates
-    let mut buf = Vec::new();
-    let _ = File::open(format!("{dir}/private/cert_and_key_{server_id}.pem"))
-        .unwrap()
-        .read_to_end(&mut buf)
-        .unwrap();
+// limits for the messages that arrive before their room is created
+const MAX_PENDING_MESSAGES_PER_ROOM: usize = 256;
+const MAX_PENDING_ROOMS: usize = 128;
+const PENDING_MESSAGE_TIME_TO_LIVE: Duration = Duration::from_secs(60);
 
-    let identity = Identity::from_pem(&buf).unwrap();
-    client = client.identity(identity);
-    client.build().unwrap()
+/// Address of a peer and the client that only accepts the certificate of that peer
+#[derive(Clone)]
+pub struct PeerConnection {
+    url: String,
+    client: Client,
+}
 
+impl PeerConnection {
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
 }
 
 pub struct Db {
-    client: Client,
-    rooms: RwLock<HashMap<String, Arc<Room>>>
+    // connections to the configured peers
+    connections: HashMap<u16, PeerConnection>,
+    // identifies the sender of a message by its client certificate
+    peers: PeerRegistry,
+    rooms: RwLock<HashMap<String, Arc<Room>>>,
+    // messages that arrived before the room was created, with the server that sent them
+    pending_messages: Mutex<PendingMessages<(u16, String)>>,
+    // servers that announced they joined the room, the announcements expire with the join timeout
+    ready_parties: Mutex<ReadyParties>,
+    ready_notify: Notify,
+    timeouts: ProtocolTimeouts,
+}
+
+fn room_key(room_id: u16, phase: Phase) -> String {
+    format!("{}-{}", phase.as_str(), room_id)
 }
 
 impl Db {
-    pub fn empty(server_id: u16) -> Self {
-        Self {
-            rooms: RwLock::new(HashMap::new()),
-            client: create_tls_config(server_id, "certs"),
+    /// Fails if the certificates of this server or of its peers cannot be loaded
+    pub fn empty(server_id: u16, timeouts: ProtocolTimeouts, cert_dir: &Path, peers: &PeerRegistry) -> Result<Self> {
+        let identity = TlsIdentity::load(server_id, cert_dir)?;
+        let mut connections = HashMap::new();
+        for peer in peers.iter() {
+            connections.insert(peer.server_id(), PeerConnection {
+                url: peer.address().to_string(),
+                client: identity.client_for(peer)?,
+            });
         }
+
+        Ok(Self {
+            rooms: RwLock::new(HashMap::new()),
+            connections,
+            peers: peers.clone(),
+            pending_messages: Mutex::new(PendingMessages::new(MAX_PENDING_MESSAGES_PER_ROOM,
+                                                              MAX_PENDING_ROOMS,
+                                                              PENDING_MESSAGE_TIME_TO_LIVE)),
+            ready_parties: Mutex::new(ReadyParties::new(timeouts.join())),
+            ready_notify: Notify::new(),
+            timeouts,
+        })
+    }
+
+    fn connections(&self, peers: &[u16]) -> Result<Vec<PeerConnection>> {
+        peers.iter()
+            .map(|id| self.connections.get(id)
+                .cloned()
+                .ok_or(anyhow!("Server {} is not among the configured peers", id)))
+            .collect()
     }
 
+    /// The configured peer that presents the given client certificate
+    pub fn authenticate_peer(&self, certificate_der: &[u8]) -> Option<u16> {
+        self.peers.find_by_certificate(certificate_der).map(|peer| peer.server_id())
+    }
+
+    /// Creates the room and returns once all the expected parties joined it as well,
+    /// so that no message of the protocol is sent before everybody listens.
+    /// The messages are sent to the given peers (server id to their index in this protocol run),
+    /// their addresses come from the peer registry. Only messages that a peer sends under its own index are accepted
     pub async fn create_room<SerializableMessage: Serialize + DeserializeOwned>(
-        &self, server_id: u16, room_id: u16, server_urls: &Vec<String>) -> (
+        &self, server_id: u16, room_id: u16, phase: Phase, peers: &HashMap<u16, u16>, expected_parties: &[u16]) -> Result<(
         impl Stream<Item = Result<Msg<SerializableMessage>>>,
         impl Sink<Msg<SerializableMessage>, Error = anyhow::Error>,
-    ) {
-        // if room already exists, delete it first
-        self.delete_room(room_id).await;
+    )> {
+        let mut peer_ids: Vec<u16> = peers.keys().copied().collect();
+        peer_ids.sort();
+        let connections = self.connections(&peer_ids)?;
 
         let (receiving_sink,  receiving_stream) = futures::channel::mpsc::unbounded();
         let (outgoing_sink, outgoing_stream) = futures::channel::mpsc::unbounded();
 
-        let room = Room::new(server_id, room_id, Box::new(receiving_sink),
-                             Box::new(outgoing_stream), self.client.clone());
+        let room = Room::new(server_id, room_id, phase, peers.clone(), Box::new(receiving_sink),
+                             Box::new(outgoing_stream));
 
         let receiving_stream = receiving_stream.map(move |msg| {
             let msg_value: serde_json::Value = serde_json::from_str(&msg).context("parse message as JSON value")?;
@@ -101,23 +146,169 @@ impl Db {
         });
 
         let room = Arc::new(room);
-        self.rooms.write().await.insert(room_id.to_string(), Arc::clone(&room));
+        let key = room_key(room_id, phase);
+        {
+            // if room already exists, it is replaced
+            // the lock is held until the pending messages are delivered so that they keep their order
+            let mut rooms = self.rooms.write().await;
+            rooms.insert(key.clone(), Arc::clone(&room));
+
+            let pending = self.pending_messages.lock().unwrap().take(&key);
+            for (from, message) in pending {
+                if let Err(e) = room.receive(from, message).await {
+                    eprintln!("Rejected a message kept for room {}: {}", key, e);
+                }
+            }
+        }
 
         let room_clone = Arc::clone(&room);
 
-        let urls_clone = server_urls.clone();
+        let connections_clone = connections.clone();
         spawn(async move {
-            room_clone.init_room(&urls_clone).await;
+            room_clone.init_room(&connections_clone).await;
         });
 
-        (receiving_stream, outgoing_sink)
+        // the announcements are authenticated by the client certificate, so they name the server and not its index
+        let expected_servers: Vec<u16> = peers.iter()
+            .filter(|(_, index)| expected_parties.contains(index))
+            .map(|(peer, _)| *peer)
+            .collect();
+
+        let joined = tokio::time::timeout(self.timeouts.join(), futures::future::join(
+            self.announce_ready(room_id, phase, &connections),
+            self.wait_for_ready_parties(&key, &expected_servers),
+        )).await;
+
+        if joined.is_err() {
+            let mut missing_parties: Vec<u16> = self.ready_parties.lock().unwrap()
+                .take_missing(&key, &expected_servers)
+                .iter()
+                .map(|server_id| peers[server_id])
+                .collect();
+            missing_parties.sort();
+
+            self.delete_room(room_id, phase).await;
+            return Err(PhaseTimeout::Join { phase, missing_parties }.into());
+        }
+
+        Ok((receiving_stream, outgoing_sink))
+    }
+
+    /// Runs the protocol in the room within the deadline of its phase and tears the room down afterwards
+    pub async fn run_in_room<T>(&self,
+                                room_id: u16,
+                                phase: Phase,
+                                expected_parties: &[u16],
+                                protocol: impl Future<Output = Result<T>>) -> Result<T> {
+        let result = match tokio::time::timeout(self.timeouts.for_phase(phase), protocol).await {
+            Ok(result) => result,
+            Err(_) => {
+                let received_messages = match self.get_room(room_id, phase).await {
+                    Some(room) => room.received_messages(),
+                    None => HashMap::new(),
+                };
+                let missing_parties = unresponsive_parties(expected_parties, &received_messages);
+
+                Err(PhaseTimeout::Run { phase, missing_parties }.into())
+            }
+        };
+
+        self.delete_room(room_id, phase).await;
+        result
+    }
+
+    /// Delivers the message of the given peer to the room, or keeps it until the room is created.
+    /// Fails if the message is not one the peer may send in the room
+    pub async fn deliver_message(&self, from: u16, room_id: u16, phase: Phase, message: String) -> Result<()> {
+        let key = room_key(room_id, phase);
+        let rooms = self.rooms.read().await;
+
+        match rooms.get(&key) {
+            Some(room) => room.receive(from, message).await,
+            None => {
+                if self.pending_messages.lock().unwrap().push(&key, (from, message)) {
+                    println!("Room {} does not exist yet, keeping the message for later", key);
+                } else {
+                    eprintln!("Room {} does not exist yet and too many messages are pending, dropping the message", key);
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Records that the given server joined the room, the room itself does not have to exist yet
+    pub fn mark_ready(&self, room_id: u16, phase: Phase, server_id: u16) {
+        self.ready_parties.lock().unwrap().mark(&room_key(room_id, phase), server_id);
+        self.ready_notify.notify_waiters();
+    }
+
+    async fn announce_ready(&self, room_id: u16, phase: Phase, connections: &[PeerConnection]) {
+        let announcements = connections.iter().map(|connection| async move {
+            let url = connection.url();
+            let endpoint = format!("https://{}/ready/{}/{}", url, room_id, phase.as_str());
+            loop {
+                match connection.client().post(&endpoint).send().await {
+                    Ok(response) if response.status().is_success() => break,
+                    Ok(response) => eprintln!("{} refused the readiness announcement with status {}", url, response.status()),
+                    Err(e) => eprintln!("Error announcing readiness to {}: {}", url, e),
+                }
+                tokio::time::sleep(READY_RETRY_DELAY).await;
+            }
+        });
+
+        futures::future::join_all(announcements).await;
+    }
+
+    async fn wait_for_ready_parties(&self, key: &str, expected_servers: &[u16]) {
+        loop {
+            // created before the check so that a notification in between is not missed
+            let notified = self.ready_notify.notified();
+            // the next run in the same room has to gather its own announcements
+            if self.ready_parties.lock().unwrap().take_all(key, expected_servers) {
+                break;
+            }
+            notified.await;
+        }
+
+        println!("All parties joined room {}", key);
+    }
+
+    /// Invites the peer to a protocol run, e.g. to the `sign_invitation` endpoint
+    pub async fn send_invitation(&self, peer: u16, invitation_endpoint: &str, room_id: u16, invitation: String) -> Result<()> {
+        let connection = self.connections(&[peer])?.remove(0);
+        let url = connection.url();
+        let endpoint = format!("https://{}/{}/{}", url, invitation_endpoint, room_id);
+        let response = connection.client().post(&endpoint).body(invitation).send().await
+            .with_context(|| format!("send {} to {}", invitation_endpoint, url))?;
+
+        if !response.status().is_success() {
+            return Err(anyhow::anyhow!("{} refused the {} with status {}", url, invitation_endpoint, response.status()));
+        }
+
+        Ok(())
+    }
+
+    /// Fetches the path from the peer, e.g. `chain/nginx?key_id=default`. None if the peer answers 404
+    pub async fn get_from_peer(&self, peer: u16, path: &str) -> Result<Option<String>> {
+        let connection = self.connections(&[peer])?.remove(0);
+        let url = connection.url();
+        let response = connection.client().get(format!("https://{}/{}", url, path)).send().await
+            .with_context(|| format!("get {} from {}", path, url))?;
+
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        if !response.status().is_success() {
+            return Err(anyhow!("{} refused {} with status {}", url, path, response.status()));
+        }
+        Ok(Some(response.text().await?))
     }
 
-    pub async fn get_room(&self, room_id: u16) -> Option<Arc<Room>> {
-        self.rooms.read().await.get(&room_id.to_string()).cloned()
+    pub async fn get_room(&self, room_id: u16, phase: Phase) -> Option<Arc<Room>> {
+        self.rooms.read().await.get(&room_key(room_id, phase)).cloned()
     }
 
-    pub async fn delete_room(&self, room_id: u16) {
-        self.rooms.write().await.remove(&room_id.to_string());
+    pub async fn delete_room(&self, room_id: u16, phase: Phase) {
+        self.rooms.write().await.remove(&room_key(room_id, phase));
     }
 }
@@ -1,2 +1,8 @@
 pub mod create_communication_channel;
+pub mod peer_registry;
+pub mod pending_messages;
+pub mod phase;
+pub mod phase_timeout;
+pub mod ready_parties;
 pub mod room;
+pub mod tls;
@@ -0,0 +1,118 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::BufReader,
+    path::Path,
+};
+
+use anyhow::{anyhow, Context, Result};
+use rustls_pemfile::certs;
+
+use crate::server_config::PeerConfig;
+
+/// Another server, its mTLS address and the fingerprint of the certificate it has to present
+#[derive(Debug, Clone, PartialEq)]
+pub struct Peer {
+    server_id: u16,
+    address: String,
+    certificate_fingerprint: String,
+}
+
+impl Peer {
+    pub fn new(server_id: u16, address: &str, certificate_der: &[u8]) -> Peer {
+        Peer {
+            server_id,
+            address: address.to_string(),
+            certificate_fingerprint: certificate_fingerprint(certificate_der),
+        }
+    }
+
+    pub fn server_id(&self) -> u16 {
+        self.server_id
+    }
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+    /// Hex encoded SHA-256 of the DER encoded certificate
+    pub fn certificate_fingerprint(&self) -> &str {
+        &self.certificate_fingerprint
+    }
+}
+
+/// The static table of the other servers, the only place where their addresses come from
+#[derive(Debug, Clone, Default)]
+pub struct PeerRegistry {
+    peers: HashMap<u16, Peer>,
+}
+
+impl PeerRegistry {
+    pub fn new(peers: Vec<Peer>) -> PeerRegistry {
+        PeerRegistry {
+            peers: peers.into_iter().map(|p| (p.server_id, p)).collect(),
+        }
+    }
+
+    /// Reads the certificates of the configured peers, by default `public/cert_<id>.pem` of the certificate directory
+    pub fn load(peers: &[PeerConfig], cert_dir: &Path) -> Result<PeerRegistry> {
+        let mut result = Vec::new();
+        for peer in peers {
+            let certificate = match peer.certificate() {
+                Some(path) => path.to_path_buf(),
+                None => cert_dir.join(format!("public/cert_{}.pem", peer.server_id())),
+            };
+            let der = read_certificate(&certificate)
+                .with_context(|| format!("Unable to load the certificate of peer {}", peer.server_id()))?;
+            result.push(Peer::new(peer.server_id(), peer.address(), &der));
+        }
+
+        Ok(PeerRegistry::new(result))
+    }
+
+    pub fn get(&self, server_id: u16) -> Option<&Peer> {
+        self.peers.get(&server_id)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Peer> {
+        self.peers.values()
+    }
+
+    /// The peer that presents the given certificate
+    pub fn find_by_certificate(&self, certificate_der: &[u8]) -> Option<&Peer> {
+        let fingerprint = certificate_fingerprint(certificate_der);
+        self.peers.values().find(|peer| peer.certificate_fingerprint == fingerprint)
+    }
+}
+
+pub fn certificate_fingerprint(certificate_der: &[u8]) -> String {
+    sha256::digest(certificate_der)
+}
+
+// the first certificate of the PEM file, in DER
+fn read_certificate(path: &Path) -> Result<Vec<u8>> {
+    let file = File::open(path).with_context(|| format!("Unable to open {}", path.display()))?;
+    certs(&mut BufReader::new(file))
+        .with_context(|| format!("Unable to parse {}", path.display()))?
+        .into_iter()
+        .next()
+        .ok_or(anyhow!("{} contains no certificate", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::communication::peer_registry::{Peer, PeerRegistry};
+
+    #[test]
+    fn known_peers() {
+        let registry = PeerRegistry::new(vec![
+            Peer::new(2, "127.0.0.1:3002", b"second"),
+            Peer::new(3, "127.0.0.1:3003", b"third"),
+        ]);
+
+        assert_eq!(registry.get(3).unwrap().address(), "127.0.0.1:3003");
+        assert_eq!(registry.get(2).unwrap().certificate_fingerprint(), sha256::digest("second"));
+        assert!(registry.get(1).is_none());
+        assert_eq!(registry.iter().count(), 2);
+        assert_eq!(registry.find_by_certificate(b"third").unwrap().server_id(), 3);
+        assert!(registry.find_by_certificate(b"first").is_none());
+    }
+}
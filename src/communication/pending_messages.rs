@@ -0,0 +1,124 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+struct PendingMessage<M> {
+    message: M,
+    received_at: Instant,
+}
+
+/// Messages that arrived for rooms which were not created yet.
+/// The queue of every room is bounded and its messages expire, so that messages
+/// for rooms that are never created cannot exhaust the memory.
+pub struct PendingMessages<M> {
+    rooms: HashMap<String, VecDeque<PendingMessage<M>>>,
+    max_messages_per_room: usize,
+    max_rooms: usize,
+    time_to_live: Duration,
+}
+
+impl<M> PendingMessages<M> {
+    pub fn new(max_messages_per_room: usize, max_rooms: usize, time_to_live: Duration) -> Self {
+        Self {
+            rooms: HashMap::new(),
+            max_messages_per_room,
+            max_rooms,
+            time_to_live,
+        }
+    }
+
+    /// Keeps the message for the room, returns false if the message was dropped
+    pub fn push(&mut self, room: &str, message: M) -> bool {
+        self.push_at(room, message, Instant::now())
+    }
+
+    /// Removes and returns the messages kept for the room that did not expire yet
+    pub fn take(&mut self, room: &str) -> Vec<M> {
+        self.take_at(room, Instant::now())
+    }
+
+    fn push_at(&mut self, room: &str, message: M, now: Instant) -> bool {
+        self.remove_expired(now);
+
+        if !self.rooms.contains_key(room) && self.rooms.len() >= self.max_rooms {
+            return false;
+        }
+
+        let queue = self.rooms.entry(room.to_string()).or_default();
+        if queue.len() >= self.max_messages_per_room {
+            return false;
+        }
+
+        queue.push_back(PendingMessage { message, received_at: now });
+        true
+    }
+
+    fn take_at(&mut self, room: &str, now: Instant) -> Vec<M> {
+        self.remove_expired(now);
+
+        self.rooms.remove(room)
+            .map(|queue| queue.into_iter().map(|pending| pending.message).collect())
+            .unwrap_or_default()
+    }
+
+    fn remove_expired(&mut self, now: Instant) {
+        let time_to_live = self.time_to_live;
+        for queue in self.rooms.values_mut() {
+            queue.retain(|pending| now.duration_since(pending.received_at) < time_to_live);
+        }
+        self.rooms.retain(|_, queue| !queue.is_empty());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+    use crate::communication::pending_messages::PendingMessages;
+
+    const MINUTE: Duration = Duration::from_secs(60);
+
+    #[test]
+    fn messages_are_taken_in_order() {
+        let mut pending = PendingMessages::new(10, 10, MINUTE);
+        assert!(pending.push("offline-1", String::from("first")));
+        assert!(pending.push("offline-1", String::from("second")));
+        assert!(pending.push("online-1", String::from("other room")));
+
+        assert_eq!(pending.take("offline-1"), vec!["first", "second"]);
+        assert!(pending.take("offline-1").is_empty());
+        assert_eq!(pending.take("online-1"), vec!["other room"]);
+    }
+
+    #[test]
+    fn room_queue_is_bounded() {
+        let mut pending = PendingMessages::new(2, 10, MINUTE);
+        assert!(pending.push("offline-1", String::from("first")));
+        assert!(pending.push("offline-1", String::from("second")));
+        assert!(!pending.push("offline-1", String::from("third")));
+
+        assert_eq!(pending.take("offline-1"), vec!["first", "second"]);
+    }
+
+    #[test]
+    fn number_of_rooms_is_bounded() {
+        let mut pending = PendingMessages::new(10, 1, MINUTE);
+        assert!(pending.push("offline-1", String::from("first")));
+        assert!(!pending.push("offline-2", String::from("second")));
+        assert!(pending.push("offline-1", String::from("third")));
+    }
+
+    #[test]
+    fn expired_messages_are_dropped() {
+        let mut pending = PendingMessages::new(10, 1, MINUTE);
+        let start = Instant::now();
+        assert!(pending.push_at("offline-1", String::from("old"), start));
+        assert!(pending.push_at("offline-1", String::from("new"), start + Duration::from_secs(30)));
+
+        assert_eq!(pending.take_at("offline-1", start + Duration::from_secs(61)), vec!["new"]);
+
+        // an expired room does not count towards the limit
+        assert!(pending.push_at("offline-2", String::from("old"), start));
+        assert!(pending.push_at("offline-3", String::from("new"), start + Duration::from_secs(61)));
+    }
+}
@@ -0,0 +1,60 @@
+/// Protocol phase a room is used for.
+/// One operation uses the same room id for all of its phases, the phase keeps their messages apart.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Phase {
+    KeyGen,
+    // all the parties confirm that the key generation gave them the same key
+    KeyConfirmation,
+    Offline,
+    Online,
+    // offline stage that precomputes a presignature for later signing
+    Presign,
+    // re-randomization of the key shares
+    Refresh,
+    // move of a key to another threshold and set of parties
+    Reshare,
+}
+
+impl Phase {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Phase::KeyGen => "keygen",
+            Phase::KeyConfirmation => "key_confirmation",
+            Phase::Offline => "offline",
+            Phase::Online => "online",
+            Phase::Presign => "presign",
+            Phase::Refresh => "refresh",
+            Phase::Reshare => "reshare",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Phase> {
+        match name {
+            "keygen" => Some(Phase::KeyGen),
+            "key_confirmation" => Some(Phase::KeyConfirmation),
+            "offline" => Some(Phase::Offline),
+            "online" => Some(Phase::Online),
+            "presign" => Some(Phase::Presign),
+            "refresh" => Some(Phase::Refresh),
+            "reshare" => Some(Phase::Reshare),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::communication::phase::Phase;
+
+    #[test]
+    fn phase_name_roundtrip() {
+        for phase in [Phase::KeyGen, Phase::KeyConfirmation, Phase::Offline, Phase::Online, Phase::Presign, Phase::Refresh, Phase::Reshare] {
+            assert_eq!(Phase::from_name(phase.as_str()), Some(phase));
+        }
+    }
+
+    #[test]
+    fn unknown_phase_name() {
+        assert_eq!(Phase::from_name("resign"), None);
+    }
+}
@@ -0,0 +1,102 @@
+use std::{
+    collections::HashMap,
+    fmt,
+};
+
+use crate::communication::phase::Phase;
+
+/// Error returned when the other parties of a room do not join it or do not finish the phase in time.
+/// The parties are identified by their index in the room.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PhaseTimeout {
+    Join { phase: Phase, missing_parties: Vec<u16> },
+    Run { phase: Phase, missing_parties: Vec<u16> },
+}
+
+impl PhaseTimeout {
+    pub fn missing_parties(&self) -> &[u16] {
+        match self {
+            PhaseTimeout::Join { missing_parties, .. } | PhaseTimeout::Run { missing_parties, .. } => missing_parties,
+        }
+    }
+
+    /// Translates the indices of the missing parties, e.g. from the indices used by the protocol to server ids
+    pub fn map_parties(self, f: impl Fn(u16) -> u16) -> PhaseTimeout {
+        match self {
+            PhaseTimeout::Join { phase, missing_parties } =>
+                PhaseTimeout::Join { phase, missing_parties: missing_parties.into_iter().map(f).collect() },
+            PhaseTimeout::Run { phase, missing_parties } =>
+                PhaseTimeout::Run { phase, missing_parties: missing_parties.into_iter().map(f).collect() },
+        }
+    }
+}
+
+impl fmt::Display for PhaseTimeout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let parties = self.missing_parties()
+            .iter()
+            .map(|p| p.to_string())
+            .collect::<Vec<String>>()
+            .join(", ");
+
+        match self {
+            PhaseTimeout::Join { phase, .. } =>
+                write!(f, "parties [{}] did not join the {} phase in time", parties, phase.as_str()),
+            PhaseTimeout::Run { phase, .. } =>
+                write!(f, "{} phase timed out, parties [{}] did not respond", phase.as_str(), parties),
+        }
+    }
+}
+
+impl std::error::Error for PhaseTimeout {}
+
+/// Parties that sent fewer messages than the most active party.
+/// Every party sends the same number of messages in each round, so these are the ones the protocol waits for.
+/// If all of them sent as many messages, the round they stalled in cannot be told, and all of them are named
+pub fn unresponsive_parties(expected_parties: &[u16], message_counts: &HashMap<u16, usize>) -> Vec<u16> {
+    let count = |party: &u16| message_counts.get(party).copied().unwrap_or(0);
+    let most_messages = expected_parties.iter().map(count).max().unwrap_or(0);
+
+    let behind: Vec<u16> = expected_parties.iter()
+        .filter(|party| count(party) < most_messages)
+        .copied()
+        .collect();
+    if behind.is_empty() {
+        return expected_parties.to_vec();
+    }
+    behind
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use crate::communication::phase::Phase;
+    use crate::communication::phase_timeout::{unresponsive_parties, PhaseTimeout};
+
+    #[test]
+    fn nobody_responded() {
+        assert_eq!(unresponsive_parties(&[2, 3], &HashMap::new()), vec![2, 3]);
+    }
+
+    #[test]
+    fn party_behind_the_others() {
+        let counts = HashMap::from([(2, 3), (3, 2), (4, 3)]);
+        assert_eq!(unresponsive_parties(&[2, 3, 4], &counts), vec![3]);
+    }
+
+    #[test]
+    fn parties_stalled_in_the_same_round() {
+        let counts = HashMap::from([(2, 3), (3, 3)]);
+        assert_eq!(unresponsive_parties(&[2, 3], &counts), vec![2, 3]);
+    }
+
+    #[test]
+    fn parties_are_translated() {
+        let timeout = PhaseTimeout::Run { phase: Phase::Offline, missing_parties: vec![1, 3] };
+        let signing_parties = [2, 3, 4];
+
+        let timeout = timeout.map_parties(|index| signing_parties[index as usize - 1]);
+        assert_eq!(timeout.missing_parties(), &[2, 4]);
+        assert_eq!(timeout.to_string(), "offline phase timed out, parties [2, 4] did not respond");
+    }
+}
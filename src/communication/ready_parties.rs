@@ -0,0 +1,138 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+struct Announcement {
+    server_id: u16,
+    received_at: Instant,
+}
+
+/// The servers that announced they joined a room, by room. Every announcement belongs to one run in the room
+/// and is consumed by it, so that a fast server that already announced the next run is not lost.
+/// Announcements expire, so that those for rooms which are never created do not pile up
+pub struct ReadyParties {
+    rooms: HashMap<String, VecDeque<Announcement>>,
+    time_to_live: Duration,
+}
+
+impl ReadyParties {
+    pub fn new(time_to_live: Duration) -> Self {
+        Self {
+            rooms: HashMap::new(),
+            time_to_live,
+        }
+    }
+
+    /// Records that the server joined the room, the room itself does not have to exist yet
+    pub fn mark(&mut self, room: &str, server_id: u16) {
+        self.mark_at(room, server_id, Instant::now())
+    }
+
+    /// Consumes one announcement of each of the servers if all of them announced, returns false otherwise
+    pub fn take_all(&mut self, room: &str, servers: &[u16]) -> bool {
+        self.take_all_at(room, servers, Instant::now())
+    }
+
+    /// Consumes the announcements of the servers that announced and returns the servers that did not
+    pub fn take_missing(&mut self, room: &str, servers: &[u16]) -> Vec<u16> {
+        self.remove_expired(Instant::now());
+
+        servers.iter()
+            .filter(|server_id| !self.take_one(room, **server_id))
+            .copied()
+            .collect()
+    }
+
+    fn mark_at(&mut self, room: &str, server_id: u16, now: Instant) {
+        self.remove_expired(now);
+        self.rooms.entry(room.to_string()).or_default().push_back(Announcement { server_id, received_at: now });
+    }
+
+    fn take_all_at(&mut self, room: &str, servers: &[u16], now: Instant) -> bool {
+        self.remove_expired(now);
+
+        let all_ready = match self.rooms.get(room) {
+            None => servers.is_empty(),
+            Some(announcements) => servers.iter()
+                .all(|server_id| announcements.iter().any(|announcement| announcement.server_id == *server_id)),
+        };
+        if all_ready {
+            for server_id in servers {
+                self.take_one(room, *server_id);
+            }
+        }
+        all_ready
+    }
+
+    // removes the oldest announcement of the server for the room
+    fn take_one(&mut self, room: &str, server_id: u16) -> bool {
+        let announcements = match self.rooms.get_mut(room) {
+            None => return false,
+            Some(announcements) => announcements,
+        };
+        let position = match announcements.iter().position(|announcement| announcement.server_id == server_id) {
+            None => return false,
+            Some(position) => position,
+        };
+
+        announcements.remove(position);
+        if announcements.is_empty() {
+            self.rooms.remove(room);
+        }
+        true
+    }
+
+    fn remove_expired(&mut self, now: Instant) {
+        let time_to_live = self.time_to_live;
+        for announcements in self.rooms.values_mut() {
+            announcements.retain(|announcement| now.duration_since(announcement.received_at) < time_to_live);
+        }
+        self.rooms.retain(|_, announcements| !announcements.is_empty());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+    use crate::communication::ready_parties::ReadyParties;
+
+    const MINUTE: Duration = Duration::from_secs(60);
+
+    #[test]
+    fn every_run_consumes_its_announcements() {
+        let mut ready = ReadyParties::new(MINUTE);
+        ready.mark("online-1", 2);
+        assert!(!ready.take_all("online-1", &[2, 3]));
+
+        // server 2 announced the next run already
+        ready.mark("online-1", 3);
+        ready.mark("online-1", 2);
+        assert!(ready.take_all("online-1", &[2, 3]));
+        assert!(!ready.take_all("online-1", &[2, 3]));
+        assert!(ready.take_all("online-1", &[2]));
+        assert!(!ready.take_all("offline-1", &[2]));
+        assert!(ready.take_all("offline-1", &[]));
+    }
+
+    #[test]
+    fn missing_servers_are_reported() {
+        let mut ready = ReadyParties::new(MINUTE);
+        ready.mark("online-1", 2);
+        assert_eq!(ready.take_missing("online-1", &[2, 3]), vec![3]);
+        assert_eq!(ready.take_missing("online-1", &[2, 3]), vec![2, 3]);
+    }
+
+    #[test]
+    fn expired_announcements_are_dropped() {
+        let mut ready = ReadyParties::new(MINUTE);
+        let start = Instant::now();
+        ready.mark_at("online-1", 2, start);
+        ready.mark_at("online-2", 2, start);
+        ready.mark_at("online-1", 3, start + Duration::from_secs(30));
+
+        assert!(!ready.take_all_at("online-1", &[2, 3], start + Duration::from_secs(61)));
+        assert!(ready.take_all_at("online-1", &[3], start + Duration::from_secs(61)));
+        assert!(ready.rooms.is_empty());
+    }
+}
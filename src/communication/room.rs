@@ -1,38 +1,50 @@
-use std::{sync::Arc};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
 use futures::{
     channel::mpsc::SendError,
     Sink, SinkExt, Stream, StreamExt,
 };
 use tokio::sync::RwLock;
-use reqwest::Client;
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+
+use crate::communication::create_communication_channel::PeerConnection;
+use crate::communication::phase::Phase;
 
 pub struct Room {
     server_id: u16,
     room_id: u16,
+    phase: Phase,
+    // the index of every peer in this protocol run, by server id
+    peer_indices: HashMap<u16, u16>,
     receiving_sink: Arc<RwLock<Box<dyn Sink<String, Error = SendError> + Send + Sync + Unpin>>>,
     outgoing_stream: Arc<RwLock<Box<dyn Stream<Item = Result<String>> + Send + Sync + Unpin>>>,
-    client: Client,
+    // number of messages received from each party, used to find out who the protocol waits for
+    received_messages: Mutex<HashMap<u16, usize>>,
 }
 
 impl Room {
     pub fn new(
         server_id: u16,
         room_id: u16,
+        phase: Phase,
+        peer_indices: HashMap<u16, u16>,
         sink: Box<dyn Sink<String, Error = SendError> + Send + Sync + Unpin>,
         stream: Box<dyn Stream<Item = Result<String>> + Send + Sync + Unpin>,
-        client: Client,
     ) -> Self {
         Self {
             server_id,
             room_id,
+            phase,
+            peer_indices,
             receiving_sink: Arc::new(RwLock::new(sink)),
             outgoing_stream: Arc::new(RwLock::new(stream)),
-            client,
+            received_messages: Mutex::new(HashMap::new()),
         }
     }
 
-    pub async fn init_room(&self, server_urls: &Vec<String>) {
+    pub async fn init_room(&self, connections: &[PeerConnection]) {
         let mut counter = 0;
 
         loop {
@@ -40,9 +52,10 @@ impl Room {
                 Some(Ok(message)) => {
                     counter += 1;
                     println!("Sending: {}  in round {}\n", message, counter);
-                    for url in server_urls {
-                        let endpoint = format!("https://{}/receive_broadcast/{}", url, self.room_id);
-                        match self.client.post(&endpoint).body(message.clone()).send().await {
+                    for connection in connections {
+                        let url = connection.url();
+                        let endpoint = format!("https://{}/receive_broadcast/{}/{}", url, self.room_id, self.phase.as_str());
+                        match connection.client().post(&endpoint).body(message.clone()).send().await {
                             Ok(_response) => {
                                 println!("Successfully sent message to {}", url);
                             }
@@ -58,9 +71,18 @@ impl Room {
         }
     }
 
-    // called by receive broadcast endpoint to receive messages from other servers
-    pub async fn receive(&self, message: String) {
-        let msg_value: serde_json::Value = serde_json::from_str(&message).unwrap();
+    // called by receive broadcast endpoint to receive messages from other servers.
+    // `from` is the server that sent the message, as authenticated by its client certificate,
+    // the message is rejected unless its sender is the index of that server in this protocol run
+    pub async fn receive(&self, from: u16, message: String) -> Result<()> {
+        let msg_value: serde_json::Value = serde_json::from_str(&message)?;
+        let sender = msg_value["sender"].as_u64().ok_or(anyhow!("Invalid 'sender' field"))?;
+        match self.peer_indices.get(&from) {
+            Some(index) if u64::from(*index) == sender => {}
+            Some(index) => return Err(anyhow!("Server {} sent a message as party {}, its index is {}", from, sender, index)),
+            None => return Err(anyhow!("Server {} does not take part in {}-{}", from, self.phase.as_str(), self.room_id)),
+        }
+
         let receiver: Option<u16> = msg_value["receiver"].as_u64().map(|r| r as u16);
 
         // Filter out messages based on the receiver ID
@@ -68,17 +90,61 @@ impl Room {
             if receiver_id != self.server_id {
                 println!("Filtered out a message meant for {}",
                          receiver_id);
-                return;
+                return Ok(());
             }
         }
 
         println!("Received message {}", message);
 
+        *self.received_messages.lock().unwrap().entry(sender as u16).or_insert(0) += 1;
+
         let mut guard = self.receiving_sink.write().await;
         let sink = guard.as_mut();
 
         if let Err(e) = sink.send(message).await {
             eprintln!("Failed to forward received message to sink: {:?}", e);
         }
+
+        Ok(())
+    }
+
+    pub fn received_messages(&self) -> HashMap<u16, usize> {
+        self.received_messages.lock().unwrap().clone()
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use futures::StreamExt;
+    use crate::communication::phase::Phase;
+    use crate::communication::room::Room;
+
+    fn offline_room() -> (Room, futures::channel::mpsc::UnboundedReceiver<String>) {
+        // servers 2 and 5 sign with this server (3), so they are the parties 1 and 3
+        let (sink, received) = futures::channel::mpsc::unbounded();
+        let room = Room::new(2, 1, Phase::Offline, HashMap::from([(2, 1), (5, 3)]),
+                             Box::new(sink), Box::new(futures::stream::empty()));
+        (room, received)
+    }
+
+    #[tokio::test]
+    async fn message_from_its_sender_is_accepted() {
+        let (room, mut received) = offline_room();
+        let message = String::from(r#"{"sender":3,"receiver":null,"body":{}}"#);
+
+        assert!(room.receive(5, message.clone()).await.is_ok());
+        assert_eq!(received.next().await, Some(message));
+        assert_eq!(room.received_messages(), HashMap::from([(3, 1)]));
+    }
+
+    #[tokio::test]
+    async fn impersonating_another_party_is_rejected() {
+        let (room, _received) = offline_room();
+
+        assert!(room.receive(2, String::from(r#"{"sender":3,"receiver":null,"body":{}}"#)).await.is_err());
+        assert!(room.receive(4, String::from(r#"{"sender":2,"receiver":null,"body":{}}"#)).await.is_err());
+        assert!(room.receive(2, String::from(r#"{"receiver":null,"body":{}}"#)).await.is_err());
+        assert!(room.received_messages().is_empty());
+    }
+}
@@ -0,0 +1,152 @@
+use std::{
+    fs::File,
+    io::BufReader,
+    path::Path,
+    sync::Arc,
+    time::SystemTime,
+};
+
+use anyhow::{anyhow, Context, Result};
+use reqwest::Client;
+use rustls::{
+    client::{ServerCertVerified, ServerCertVerifier, WebPkiVerifier},
+    Certificate, CertificateError, ClientConfig, PrivateKey, RootCertStore, ServerName,
+};
+use rustls_pemfile::Item;
+
+use crate::communication::peer_registry::{certificate_fingerprint, Peer};
+
+/// The CA and the client identity of this server, loaded from the certificate directory
+pub struct TlsIdentity {
+    roots: Arc<RootCertStore>,
+    certificate_chain: Vec<Certificate>,
+    private_key: PrivateKey,
+}
+
+impl TlsIdentity {
+    /// Loads `ca_cert.pem` and `private/cert_and_key_<server_id>.pem`
+    pub fn load(server_id: u16, cert_dir: &Path) -> Result<TlsIdentity> {
+        let ca_file = cert_dir.join("ca_cert.pem");
+        let mut roots = RootCertStore::empty();
+        for item in read_pem(&ca_file)? {
+            if let Item::X509Certificate(der) = item {
+                roots.add(&Certificate(der))
+                    .with_context(|| format!("Invalid CA certificate in {}", ca_file.display()))?;
+            }
+        }
+        if roots.is_empty() {
+            return Err(anyhow!("{} contains no CA certificate", ca_file.display()));
+        }
+
+        let identity_file = cert_dir.join(format!("private/cert_and_key_{}.pem", server_id));
+        let mut certificate_chain = Vec::new();
+        let mut private_key = None;
+        for item in read_pem(&identity_file)? {
+            match item {
+                Item::X509Certificate(der) => certificate_chain.push(Certificate(der)),
+                Item::PKCS8Key(der) | Item::RSAKey(der) | Item::ECKey(der) => {
+                    private_key.get_or_insert(PrivateKey(der));
+                }
+                _ => {}
+            }
+        }
+        if certificate_chain.is_empty() {
+            return Err(anyhow!("{} contains no certificate", identity_file.display()));
+        }
+        let private_key = private_key
+            .ok_or(anyhow!("{} contains no private key", identity_file.display()))?;
+
+        Ok(TlsIdentity {
+            roots: Arc::new(roots),
+            certificate_chain,
+            private_key,
+        })
+    }
+
+    /// Client that presents this server's certificate and accepts only the certificate of the given peer
+    pub fn client_for(&self, peer: &Peer) -> Result<Client> {
+        let verifier = PinnedPeerVerifier {
+            ca: WebPkiVerifier::new(self.roots.clone(), None),
+            server_id: peer.server_id(),
+            expected_fingerprint: peer.certificate_fingerprint().to_string(),
+        };
+
+        let tls_config = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_custom_certificate_verifier(Arc::new(verifier))
+            .with_client_auth_cert(self.certificate_chain.clone(), self.private_key.clone())
+            .context("Invalid client certificate or private key")?;
+
+        Client::builder()
+            .use_preconfigured_tls(tls_config)
+            .build()
+            .with_context(|| format!("Unable to create the client for peer {}", peer.server_id()))
+    }
+}
+
+/// Verifies that the certificate is issued by the CA and that it is the configured certificate of the peer.
+/// The host name is not checked, the peers are addressed by names and ports their certificates do not list
+/// and the pinned certificate identifies the peer better anyway.
+struct PinnedPeerVerifier {
+    ca: WebPkiVerifier,
+    server_id: u16,
+    expected_fingerprint: String,
+}
+
+impl ServerCertVerifier for PinnedPeerVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        server_name: &ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        // the name is checked after the chain, so a name mismatch means the chain is valid
+        match self.ca.verify_server_cert(end_entity, intermediates, server_name, scts, ocsp_response, now) {
+            Ok(_) | Err(rustls::Error::InvalidCertificate(CertificateError::NotValidForName)) => {}
+            Err(e) => return Err(e),
+        }
+
+        check_pinned_certificate(&end_entity.0, self.server_id, &self.expected_fingerprint)?;
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+fn check_pinned_certificate(certificate_der: &[u8], server_id: u16, expected_fingerprint: &str) -> Result<(), rustls::Error> {
+    if certificate_fingerprint(certificate_der) != expected_fingerprint {
+        return Err(rustls::Error::General(
+            format!("the presented certificate is not the configured certificate of server {}", server_id)));
+    }
+
+    Ok(())
+}
+
+fn read_pem(path: &Path) -> Result<Vec<Item>> {
+    let file = File::open(path).with_context(|| format!("Unable to open {}", path.display()))?;
+    rustls_pemfile::read_all(&mut BufReader::new(file))
+        .with_context(|| format!("Unable to parse {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use crate::communication::peer_registry::certificate_fingerprint;
+    use crate::communication::tls::{check_pinned_certificate, TlsIdentity};
+
+    #[test]
+    fn only_the_pinned_certificate_is_accepted() {
+        let expected = certificate_fingerprint(b"certificate of server 2");
+
+        assert!(check_pinned_certificate(b"certificate of server 2", 2, &expected).is_ok());
+        assert!(check_pinned_certificate(b"certificate of server 3", 2, &expected).is_err());
+    }
+
+    #[test]
+    fn missing_certificates_are_reported() {
+        let error = TlsIdentity::load(1, Path::new("no-such-cert-dir")).err().unwrap();
+
+        assert!(format!("{:#}", error).contains("ca_cert.pem"));
+    }
+}
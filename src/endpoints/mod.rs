@@ -1 +1,2 @@
-pub mod pub_endpoints;
\ No newline at end of file
+pub mod presigning;
+pub mod pub_endpoints;
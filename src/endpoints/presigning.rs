@@ -0,0 +1,185 @@
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+use rocket::{
+    State,
+    response::status,
+    http::Status,
+    mtls::Certificate,
+};
+use serde::{Deserialize, Serialize};
+use tokio::sync::RwLock;
+
+use crate::communication::phase::Phase;
+use crate::endpoints::pub_endpoints::run_offline_stage;
+use crate::mpc::operations::{
+    presignature_pool::is_valid_presignature_id,
+    signing::{Signer, DEFAULT_KEY_ID},
+};
+use crate::rocket_instances;
+
+// how often the presignature pools are checked and refilled
+const REFILL_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Invitation to precompute a presignature, sent by the server with the lowest id among the participants
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PresignInvitation {
+    presignature_id: String,
+    participants: Vec<u16>,
+    // the key of the presignature, invitations of servers that do not know keys are for the default key
+    #[serde(default = "default_key_id")]
+    key_id: String,
+}
+
+fn default_key_id() -> String {
+    String::from(DEFAULT_KEY_ID)
+}
+
+impl PresignInvitation {
+    fn other_participants(&self, server_id: u16) -> Vec<u16> {
+        self.participants.iter()
+            .filter(|p| **p != server_id)
+            .copied()
+            .collect()
+    }
+}
+
+// ids start with the time of their creation, so that the oldest presignatures are used first
+fn new_presignature_id(server_id: u16) -> String {
+    let since_the_epoch = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards");
+
+    format!("{:024}-{}", since_the_epoch.as_nanos(), server_id)
+}
+
+/// Keeps the presignature pools of all known keys and participant sets filled up to the given depth
+pub fn spawn_presignature_refill(db: rocket_instances::SharedDb, signer: Arc<RwLock<Signer>>, server_id: u16, depth: usize) {
+    if depth == 0 {
+        return;
+    }
+
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(REFILL_INTERVAL).await;
+
+            let participant_sets = signer.read().await.participant_sets();
+            for (key_id, participants) in participant_sets {
+                // only the server with the lowest id refills the pool, so that the others do not start the same runs
+                if participants.iter().min() != Some(&server_id) {
+                    continue;
+                }
+
+                let other_ids: Vec<u16> = participants.iter()
+                    .filter(|id| **id != server_id)
+                    .copied()
+                    .collect();
+
+                while signer.read().await.presignature_count(&key_id, &other_ids) < depth {
+                    if let Err(e) = precompute_presignature(&db, &signer, server_id, &key_id, &participants).await {
+                        println!("Unable to precompute a presignature of key {} for {:?}: {:#}", key_id, other_ids, e);
+                        break;
+                    }
+                }
+            }
+        }
+    });
+}
+
+async fn precompute_presignature(
+    db: &rocket_instances::SharedDb,
+    signer: &Arc<RwLock<Signer>>,
+    server_id: u16,
+    key_id: &str,
+    participants: &[u16]
+) -> Result<()> {
+    let invitation = PresignInvitation {
+        presignature_id: new_presignature_id(server_id),
+        participants: participants.to_vec(),
+        key_id: key_id.to_string(),
+    };
+    let body = serde_json::to_string(&invitation)?;
+
+    // a server refills its pools one presignature at a time, so its id identifies the room
+    let room_id = server_id;
+
+    let invitations = invitation.other_participants(server_id).into_iter()
+        .map(|peer| db.send_invitation(peer, "presign_invitation", room_id, body.clone()));
+    for result in futures::future::join_all(invitations).await {
+        result?;
+    }
+
+    presign(db, signer, server_id, room_id, &invitation).await
+}
+
+async fn presign(
+    db: &rocket_instances::SharedDb,
+    signer: &Arc<RwLock<Signer>>,
+    server_id: u16,
+    room_id: u16,
+    invitation: &PresignInvitation
+) -> Result<()> {
+    let other_ids = invitation.other_participants(server_id);
+
+    let offline_stage = run_offline_stage(db, signer, server_id, room_id, Phase::Presign, &invitation.key_id, &other_ids).await?;
+    signer.write().await.add_presignature(&invitation.key_id, &other_ids, &invitation.presignature_id, offline_stage)?;
+
+    println!("Presignature {} of key {} for {:?} is ready", invitation.presignature_id, invitation.key_id, other_ids);
+    Ok(())
+}
+
+// Called by the server that refills the presignature pool of the participants. Only that server,
+// the one with the lowest id among the participants, may invite this one, as told by its client certificate
+#[rocket::post("/presign_invitation/<room_id>", data = "<data>")]
+pub async fn presign_invitation(
+    db: &State<rocket_instances::SharedDb>,
+    config_state: &State<rocket_instances::ServerConfigState>,
+    signer: &State<Arc<RwLock<Signer>>>,
+    certificate: Certificate<'_>,
+    data: String,
+    room_id: u16
+) -> Result<Status, status::Custom<&'static str>> {
+    let server_id: u16 = config_state.config().lock().unwrap().server_id();
+
+    let sender = match db.authenticate_peer(certificate.as_bytes()) {
+        None => return Err(status::Custom(Status::Forbidden, "The sender is not a configured peer")),
+        Some(sender) => sender
+    };
+
+    let invitation = match serde_json::from_str::<PresignInvitation>(data.as_str()) {
+        Ok(i) => i,
+        Err(_) => return Err(status::Custom(Status::BadRequest, "Unable to parse json data"))
+    };
+
+    // the refilling server runs its presignatures in the room of its own id
+    if invitation.participants.iter().min() != Some(&sender) || room_id != sender {
+        return Err(status::Custom(Status::Forbidden, "Only the participant with the lowest id invites the others, to the room of its id"));
+    }
+
+    if !is_valid_presignature_id(&invitation.presignature_id) {
+        return Err(status::Custom(Status::BadRequest, "Invalid presignature id"));
+    }
+
+    let other_ids = invitation.other_participants(server_id);
+    if !signer.read().await.has_key(&invitation.key_id) {
+        return Err(status::Custom(Status::BadRequest, "Unknown key"));
+    }
+    if other_ids.len() == invitation.participants.len() ||
+        !signer.read().await.are_participants_valid(&invitation.key_id, &other_ids) {
+        return Err(status::Custom(Status::BadRequest, "Invalid participants"));
+    }
+
+    let db = db.inner().clone();
+    let signer = signer.inner().clone();
+
+    tokio::spawn(async move {
+        if let Err(e) = presign(&db, &signer, server_id, room_id, &invitation).await {
+            println!("Precomputing presignature {} failed: {:#}", invitation.presignature_id, e);
+        }
+    });
+
+    Ok(Status::Accepted)
+}
@@ -2,118 +2,291 @@ extern crate core;
 extern crate hex;
 
 use crate::rocket_instances;
+use crate::communication::phase::Phase;
+use crate::communication::phase_timeout::PhaseTimeout;
 
 use crate::mpc::operations::{
-    check_signature,
+    check_signature::DigestVersion,
+    log_chain::{is_valid_stream_id, ChainHead, ChainLink, HeadRecord},
     signing,
-    key_generation,
+    key_generation::{self, KeyConfirmation},
+    key_refresh::{self, RefreshMessage},
+    key_resharing::{self, ReshareMessage, ReshareRequest},
 };
 
 use crate::mpc::utils::{
-    hex2string,
-    local_share_utils,
-    check_timestamp
+    check_timestamp,
+    log_ingestion::{parse_log, write_sidecar, LogFormat, SidecarEntry},
+    public_key_formats::PublicKeyFormats,
+    parse_verify_json::{VerifyRequest, VerifyResponse},
 };
 
-use sha256;
 use std::{
-    path::Path,
+    collections::HashMap,
     sync::{Arc},
-    thread,
-    time::Duration,
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
-use curv::arithmetic::Converter;
-use curv::BigInt;
 
 use futures::StreamExt;
-use anyhow::Result;
+use anyhow::{anyhow, Context, Result};
 
 use rocket::{
     State,
     response::status,
     http::Status,
+    mtls::Certificate,
+    serde::json::Json,
     data::ToByteUnit,
     Data,
 };
 
 use multi_party_ecdsa::protocols::multi_party_ecdsa::gg_2020::state_machine::{
     keygen::ProtocolMessage,
-    sign::{OfflineProtocolMessage, PartialSignature},
+    sign::{CompletedOfflineStage, OfflineProtocolMessage, PartialSignature},
 };
 use tokio::sync::RwLock;
 use tokio::io::AsyncReadExt;
 
-use crate::mpc::utils::parse_signature_json::EndpointSignatureData;
+use crate::mpc::utils::parse_signature_json::{BatchSignResponse, EndpointSignatureData, SignResponse};
+use crate::mpc::utils::signature_formats::SignatureFormats;
 
-#[rocket::post("/key_gen/<room_id>", data = "<data>")]
+// All the configured parties take part in the key generation, each of them is called with the same key id
+#[rocket::post("/key_gen/<room_id>?<key_id>")]
 pub async fn key_gen(
     db: &State<rocket_instances::SharedDb>,
     config_state: &State<rocket_instances::ServerConfigState>,
-    data: String,
+    signer: &State<Arc<RwLock<signing::Signer>>>,
     room_id: u16,
-) -> Result<&'static str, status::Forbidden<&'static str>> {
+    key_id: Option<&str>,
+) -> Result<&'static str, status::Custom<String>> {
+    let key_id = key_id.unwrap_or(signing::DEFAULT_KEY_ID);
 
-    let urls: Vec<String> = data.split(',').map(|s| s.to_string()).collect();
     let mpc_config = config_state.config().lock().unwrap().clone();
+    if mpc_config.server_id() > mpc_config.number_of_parties() {
+        return Err(status::Custom(Status::Forbidden,
+                                  format!("Servers 1 to {} generate keys, this server receives them by resharing", mpc_config.number_of_parties())));
+    }
+    if let Err(e) = signer.read().await.check_no_local_share(key_id) {
+        return Err(protocol_error(Status::Forbidden, "Keys could NOT be generated", e));
+    }
 
-    let (receiving_stream, outgoing_sink) =
-        db.create_room::<ProtocolMessage>(mpc_config.server_id(), room_id, &urls).await;
+    let expected_parties: Vec<u16> = (1..=mpc_config.number_of_parties())
+        .filter(|p| *p != mpc_config.server_id())
+        .collect();
+
+    let (receiving_stream, outgoing_sink) = match
+        db.create_room::<ProtocolMessage>(mpc_config.server_id(), room_id, Phase::KeyGen, &keygen_party_indices(&expected_parties), &expected_parties).await {
+        Ok(channel) => channel,
+        Err(e) => return Err(protocol_error(Status::Forbidden, "Keys could NOT be generated", e)),
+    };
 
     let receiving_stream = receiving_stream.fuse();
     tokio::pin!(receiving_stream);
     tokio::pin!(outgoing_sink);
 
-    let generation_result =
+    let generation = async {
         key_generation::generate_keys(mpc_config.server_id(),
                                       receiving_stream,
                                       outgoing_sink,
                                       mpc_config.threshold(),
-                                      mpc_config.number_of_parties()).await;
+                                      mpc_config.number_of_parties())
+            .await
+            .map_err(anyhow::Error::msg)
+    };
+    let local_share = match db.run_in_room(room_id, Phase::KeyGen, &expected_parties, generation).await {
+        Ok(local_share) => local_share,
+        Err(e) => return Err(protocol_error(Status::Forbidden, "Keys could NOT be generated", e)),
+    };
 
-    let status = match generation_result {
-        Ok(_) => "Ok".to_string(),
-        Err(e) => e,
+    // the key share is stored, and used, only once every party confirmed the same key
+    let (receiving_stream, outgoing_sink) = match
+        db.create_room::<KeyConfirmation>(mpc_config.server_id(), room_id, Phase::KeyConfirmation, &keygen_party_indices(&expected_parties), &expected_parties).await {
+        Ok(channel) => channel,
+        Err(e) => return Err(protocol_error(Status::Forbidden, "Keys could NOT be confirmed", e)),
     };
 
-    return if status == "Ok" {
-        println!("Keys were successfully generated");
-        Ok("Keys were successfully generated")
-    } else {
-        println!("Keys could NOT be generated");
-        Err(status::Forbidden("Keys could NOT be generated"))
+    tokio::pin!(receiving_stream);
+    tokio::pin!(outgoing_sink);
+
+    let confirmation = async {
+        key_generation::confirm_key(&local_share, receiving_stream, outgoing_sink).await?;
+        signer.write().await.store_local_share(key_id, local_share)
+    };
+
+    match db.run_in_room(room_id, Phase::KeyConfirmation, &expected_parties, confirmation).await {
+        Ok(_) => {
+            println!("Keys of {} were successfully generated", key_id);
+            Ok("Keys were successfully generated")
+        }
+        Err(e) => Err(protocol_error(Status::Forbidden, "Keys could NOT be confirmed", e)),
     }
 }
 
-#[rocket::post("/verify", data = "<data>")]
-pub async fn verify(config_state: &State<rocket_instances::ServerConfigState>,
-                    data: String) -> Result<&'static str, status::BadRequest<&'static str>> {
-    let split_data = data.split(',').map(|s| s.to_string()).collect::<Vec<String>>();
-    let signature_hex = split_data[0].clone();
+// All the parties of the key re-randomize their shares, each of them is called with the same key id.
+// The public key stays the same, a share that leaked before the refresh cannot be combined with the new ones,
+// and its Paillier key does not decrypt the messages of later signings, since the Paillier keys are replaced as well.
+// A refresh that failed on some parties after others confirmed it is completed by the next refresh
+#[rocket::post("/refresh/<room_id>?<key_id>")]
+pub async fn refresh(
+    db: &State<rocket_instances::SharedDb>,
+    config_state: &State<rocket_instances::ServerConfigState>,
+    signer: &State<Arc<RwLock<signing::Signer>>>,
+    room_id: u16,
+    key_id: Option<&str>,
+) -> Result<&'static str, status::Custom<String>> {
+    let key_id = key_id.unwrap_or(signing::DEFAULT_KEY_ID);
+    let server_id = config_state.config().lock().unwrap().server_id();
 
-    let signature = hex2string::hex_to_string(signature_hex);
-    let data = hex2string::hex_to_string(split_data[1].clone());
-    let timestamp = &split_data[2];
-    let signed_data = sha256::digest(data + timestamp);
+    let key_share = match signer.read().await.key_share(key_id) {
+        None => return Err(status::Custom(Status::BadRequest, format!("Key {} does not exist", key_id))),
+        Some(key_share) => key_share.clone(),
+    };
+    let local_share = key_share.local_key();
+
+    // the parties are identified by their index in the key, which is not the server id after a resharing
+    let peers: HashMap<u16, u16> = key_share.parties().iter()
+        .zip(1..)
+        .filter(|(peer, _)| **peer != server_id)
+        .map(|(peer, index)| (*peer, index))
+        .collect();
+    let expected_parties: Vec<u16> = (1..=local_share.n)
+        .filter(|p| *p != local_share.i)
+        .collect();
+
+    let (receiving_stream, outgoing_sink) = match
+        db.create_room::<RefreshMessage>(local_share.i, room_id, Phase::Refresh, &peers, &expected_parties).await {
+        Ok(channel) => channel,
+        Err(e) => return Err(protocol_error(Status::BadRequest, "Key could NOT be refreshed", e)),
+    };
 
-    let (r,s) = check_signature::extract_rs(signature.as_str());
-    let msg = BigInt::from_bytes(&hex::decode(signed_data).unwrap());
+    tokio::pin!(receiving_stream);
+    tokio::pin!(outgoing_sink);
 
+    let refresh = async {
+        let stage = |active, pending| async move {
+            signer.write().await.stage_refreshed_share(key_id, active, pending)
+        };
+        let refreshed = key_refresh::refresh_key(local_share, key_share.pending(), receiving_stream, outgoing_sink, stage).await?;
+        signer.write().await.replace_local_share(key_id, refreshed)
+    };
+
+    match db.run_in_room(room_id, Phase::Refresh, &expected_parties, refresh).await {
+        Ok(_) => Ok("Key was successfully refreshed"),
+        Err(e) => Err(protocol_error(Status::BadRequest, "Key could NOT be refreshed", e)),
+    }
+}
+
+// Moves the key to another threshold and set of parties with the same public key. Every dealer and every receiver
+// of the request is called with the same request. A dealer that is not a receiver deletes its share afterwards
+#[rocket::post("/reshare/<room_id>", data = "<data>")]
+pub async fn reshare(
+    db: &State<rocket_instances::SharedDb>,
+    config_state: &State<rocket_instances::ServerConfigState>,
+    signer: &State<Arc<RwLock<signing::Signer>>>,
+    room_id: u16,
+    data: String,
+) -> Result<&'static str, status::Custom<String>> {
     let server_id = config_state.config().lock().unwrap().server_id();
-    let local_share_file_name = format!("local-share{}.json", server_id);
-    let file_contents = local_share_utils::read_file(Path::new(&local_share_file_name));
-    match file_contents {
-        None => return Err(status::BadRequest("local-share.json is missing. Generate it first with the /keygen endpoint")),
-        _ => {}
+
+    let request = match serde_json::from_str::<ReshareRequest>(data.as_str()) {
+        Ok(request) => request,
+        Err(_) => return Err(status::Custom(Status::BadRequest, String::from("Unable to parse json data"))),
+    };
+    if let Err(e) = request.validate() {
+        return Err(status::Custom(Status::BadRequest, format!("Invalid resharing: {:#}", e)));
+    }
+    let participants = request.participants();
+    if !participants.contains(&server_id) {
+        return Err(status::Custom(Status::BadRequest, String::from("Server is neither a dealer nor a receiver of the resharing")));
+    }
+
+    let key_id = request.key_id();
+    let old_share = signer.read().await.key_share(key_id).cloned();
+    if request.is_dealer(server_id) && old_share.is_none() {
+        return Err(status::Custom(Status::BadRequest, format!("Key {} does not exist", key_id)));
+    }
+
+    // the servers take part under their server ids, the indices of the new key follow from the receivers
+    let expected_parties: Vec<u16> = participants.into_iter()
+        .filter(|p| *p != server_id)
+        .collect();
+
+    let (receiving_stream, outgoing_sink) = match
+        db.create_room::<ReshareMessage>(server_id, room_id, Phase::Reshare, &keygen_party_indices(&expected_parties), &expected_parties).await {
+        Ok(channel) => channel,
+        Err(e) => return Err(protocol_error(Status::BadRequest, "Key could NOT be reshared", e)),
+    };
+
+    tokio::pin!(receiving_stream);
+    tokio::pin!(outgoing_sink);
+
+    let resharing = async {
+        let new_share = key_resharing::reshare_key(&request, server_id, old_share.as_ref(), receiving_stream, outgoing_sink).await?;
+        match new_share {
+            Some(new_share) => signer.write().await.install_reshared_share(key_id, new_share),
+            None => signer.write().await.delete_local_share(key_id),
+        }
+    };
+
+    match db.run_in_room(room_id, Phase::Reshare, &expected_parties, resharing).await {
+        Ok(_) => Ok("Key was successfully reshared"),
+        Err(e) => Err(protocol_error(Status::BadRequest, "Key could NOT be reshared", e)),
+    }
+}
+
+// The keys this server holds a share of, with their public keys and thresholds
+#[rocket::get("/keys")]
+pub async fn keys(signer: &State<Arc<RwLock<signing::Signer>>>) -> Json<Vec<signing::KeyInfo>> {
+    Json(signer.read().await.keys())
+}
+
+// The public key in the formats that auditors and verifiers use, none of them needs access to a key share
+#[rocket::get("/public_key/<key_id>")]
+pub async fn public_key(signer: &State<Arc<RwLock<signing::Signer>>>,
+                        key_id: &str) -> Result<Json<PublicKeyFormats>, status::NotFound<String>> {
+    match signer.read().await.public_key(key_id) {
+        None => Err(status::NotFound(format!("Key {} does not exist", key_id))),
+        Some(public_key) => Ok(Json(PublicKeyFormats::new(&public_key))),
+    }
+}
+
+// The last record of a stream that this server signed, verifiers compare it with the end of the log
+#[rocket::get("/chain/<stream_id>?<key_id>")]
+pub async fn chain_head(signer: &State<Arc<RwLock<signing::Signer>>>,
+                        stream_id: &str,
+                        key_id: Option<&str>) -> Result<Json<ChainHead>, status::NotFound<String>> {
+    let key_id = key_id.unwrap_or(signing::DEFAULT_KEY_ID);
+    match signer.read().await.chains().head(key_id, stream_id) {
+        None => Err(status::NotFound(format!("Stream {} of key {} has no signed record", stream_id, key_id))),
+        Some(head) => Ok(Json(head.clone())),
     }
-    let file_contents = file_contents.unwrap();
+}
+
+// Verifies a JSON request, or the comma separated signature, data and timestamp of older versions.
+// The response is JSON as well, malformed requests and invalid signatures are answered with 400
+#[rocket::post("/verify?<key_id>", data = "<data>")]
+pub async fn verify(signer: &State<Arc<RwLock<signing::Signer>>>,
+                    key_id: Option<&str>,
+                    data: String) -> Result<Json<VerifyResponse>, status::Custom<Json<VerifyResponse>>> {
+    let request = match VerifyRequest::parse(&data) {
+        Ok(request) => request,
+        Err(e) => return Err(status::Custom(Status::BadRequest, Json(VerifyResponse::rejected(e)))),
+    };
 
-    let public_key = check_signature::get_public_key(file_contents.as_str());
+    let key_id = request.key_id(key_id);
+    let public_key = match signer.read().await.public_key(key_id) {
+        None => return Err(status::Custom(Status::NotFound, Json(VerifyResponse::rejected(
+            format!("Key {} does not exist. Generate it first with the /key_gen endpoint", key_id)))
+        )),
+        Some(public_key) => public_key
+    };
 
-    return if check_signature::check_sig(&r, &s, &msg, &public_key) {
-        Ok("Valid signature")
+    let response = request.verify(key_id, &public_key);
+    if response.is_valid() {
+        Ok(Json(response))
     } else {
-        Err(status::BadRequest("Invalid signature"))
+        Err(status::Custom(Status::BadRequest, Json(response)))
     }
 }
 
@@ -124,89 +297,589 @@ pub async fn sign(
     signer: &State<Arc<RwLock<signing::Signer>>>,
     data: String,
     room_id: u16
-) -> Result<String, status::BadRequest<&'static str>> {
+) -> Result<Json<SignResponse>, status::Custom<String>> {
+    let esig_data = match serde_json::from_str::<EndpointSignatureData>(data.as_str()) {
+        Ok(ed) => ed,
+        Err(_) => return Err(status::Custom(Status::BadRequest, String::from("Unable to parse json data")))
+    };
+    if esig_data.is_batch() {
+        return Err(status::Custom(Status::BadRequest, String::from("Entries are signed with /sign_batch")));
+    }
+
+    let (signature, esig_data) = sign_request(db, config_state, signer, &esig_data, room_id).await?;
+    let response = SignResponse::new(signature, esig_data.mode(), DigestVersion::default());
+    Ok(Json(response.with_chain(esig_data.chain().cloned())))
+}
+
+// Signs the Merkle root of the entries, the client gets a proof for each entry
+// that it is part of the signed batch
+#[rocket::post("/sign_batch/<room_id>", data = "<data>")]
+pub async fn sign_batch(
+    db: &State<rocket_instances::SharedDb>,
+    config_state: &State<rocket_instances::ServerConfigState>,
+    signer: &State<Arc<RwLock<signing::Signer>>>,
+    data: String,
+    room_id: u16
+) -> Result<Json<BatchSignResponse>, status::Custom<String>> {
+    let esig_data = match serde_json::from_str::<EndpointSignatureData>(data.as_str()) {
+        Ok(ed) => ed,
+        Err(_) => return Err(status::Custom(Status::BadRequest, String::from("Unable to parse json data")))
+    };
+    let tree = match esig_data.batch_tree(DigestVersion::default()) {
+        Ok(tree) => tree,
+        Err(e) => return Err(status::Custom(Status::BadRequest, e))
+    };
+
+    let (signature, _) = sign_request(db, config_state, signer, &esig_data, room_id).await?;
+    let signature = SignResponse::new(signature, esig_data.mode(), DigestVersion::default());
+    Ok(Json(BatchSignResponse::new(signature, &tree)))
+}
+
+// The query of /ingest, the participants are repeated as in participants=1&participants=2
+#[derive(rocket::FromForm)]
+pub struct IngestOptions<'r> {
+    participants: Vec<u16>,
+    // text if not given
+    format: Option<&'r str>,
+    // if not given, a few records are signed one by one and more of them in a single batch
+    batch_size: Option<usize>,
+    // chains the records, which are signed one by one then
+    stream_id: Option<&'r str>,
+    key_id: Option<&'r str>,
+}
+
+// the most signatures of one ingestion, every one of them is a run of the protocol within the same request
+// and occupies the room until it is done
+const MAX_INGEST_SIGNATURES: usize = 16;
+
+// Signs the records of a JSON-lines or plain-text log file in their canonical form, one by one or in batches,
+// and returns the detached signatures as a sidecar file with one JSON line per record. The server coordinates
+// every signing with the current time, one after another in the given room
+#[rocket::post("/ingest/<room_id>?<options..>", data = "<data>")]
+pub async fn ingest(
+    db: &State<rocket_instances::SharedDb>,
+    config_state: &State<rocket_instances::ServerConfigState>,
+    signer: &State<Arc<RwLock<signing::Signer>>>,
+    data: String,
+    room_id: u16,
+    options: IngestOptions<'_>
+) -> Result<String, status::Custom<String>> {
+    let IngestOptions { participants, format, batch_size, stream_id, key_id } = options;
+    let server_id: u16 = config_state.config().lock().unwrap().server_id();
+    if !participants.contains(&server_id) {
+        return Err(status::Custom(Status::BadRequest, String::from("The server ingesting the log must be one of the participants")));
+    }
+
+    let format = match format.map(str::parse::<LogFormat>).transpose() {
+        Ok(format) => format.unwrap_or_default(),
+        Err(e) => return Err(status::Custom(Status::BadRequest, e))
+    };
+    let records = match parse_log(&data, format) {
+        Ok(records) if records.is_empty() => return Err(status::Custom(Status::BadRequest, String::from("The log has no records"))),
+        Ok(records) => records,
+        Err(e) => return Err(status::Custom(Status::BadRequest, e))
+    };
+    let batch_size = match (batch_size, stream_id) {
+        (Some(0), _) => return Err(status::Custom(Status::BadRequest, String::from("A batch needs at least one entry"))),
+        (Some(_), Some(_)) => return Err(status::Custom(Status::BadRequest, String::from("Batches cannot be chained"))),
+        (Some(batch_size), None) => batch_size,
+        (None, Some(_)) => 1,
+        (None, None) if records.len() <= MAX_INGEST_SIGNATURES => 1,
+        (None, None) => records.len(),
+    };
+    let signatures = records.len().div_ceil(batch_size);
+    if signatures > MAX_INGEST_SIGNATURES {
+        return Err(status::Custom(Status::PayloadTooLarge, format!(
+            "The log needs {} signatures, at most {} are made at once. Split the log or sign it in larger batches", signatures, MAX_INGEST_SIGNATURES)));
+    }
+    let key_id = key_id.unwrap_or(signing::DEFAULT_KEY_ID);
+
+    let mut sidecar = Vec::new();
+    for batch in records.chunks(batch_size) {
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)
+            .map_err(|_| status::Custom(Status::InternalServerError, String::from("The clock of the server is before 1970")))?
+            .as_secs()
+            .to_string();
+        let canonical: Vec<&[u8]> = batch.iter().map(|record| record.canonical.as_slice()).collect();
+        let esig_data = match (batch_size, stream_id) {
+            (1, None) => EndpointSignatureData::for_data(&participants, canonical[0], &timestamp, key_id),
+            (1, Some(stream_id)) => EndpointSignatureData::for_data(&participants, canonical[0], &timestamp, key_id)
+                .with_chain(ChainLink::next(stream_id)),
+            _ => EndpointSignatureData::for_entries(&participants, &canonical, &timestamp, key_id),
+        };
+
+        let (signature, esig_data) = sign_request(db, config_state, signer, &esig_data, room_id)
+            .await
+            .map_err(|e| status::Custom(e.0, format!("Line {} of the log: {}", batch[0].line, e.1)))?;
+        if esig_data.is_batch() {
+            let tree = esig_data.batch_tree(DigestVersion::default()).map_err(|e| status::Custom(Status::BadRequest, e))?;
+            sidecar.extend(batch.iter().zip(tree.proofs()).map(|(record, proof)|
+                SidecarEntry::new(record.line, signature.der(), &timestamp, key_id).with_proof(proof)));
+        } else {
+            sidecar.push(SidecarEntry::new(batch[0].line, signature.der(), &timestamp, key_id).with_chain(esig_data.chain().cloned()));
+        }
+    }
+
+    write_sidecar(&sidecar).map_err(|e| status::Custom(Status::InternalServerError, e.to_string()))
+}
+
+// Signs the data or the batch of a client request, the other participants are either invited
+// by this server or were called by the client as well. Returns the request with the position
+// of a chained record filled in
+async fn sign_request(
+    db: &State<rocket_instances::SharedDb>,
+    config_state: &State<rocket_instances::ServerConfigState>,
+    signer: &State<Arc<RwLock<signing::Signer>>>,
+    esig_data: &EndpointSignatureData,
+    room_id: u16
+) -> Result<(SignatureFormats, EndpointSignatureData), status::Custom<String>> {
+    let server_id: u16 = config_state.config().lock().unwrap().server_id();
+    if let Some(link) = esig_data.chain() {
+        catch_up_with_participants(db, signer, server_id, esig_data, link).await?;
+    }
+
+    let pending_timeout = config_state.config().lock().unwrap().timeouts().signing();
+    let (esig_data, hash) = prepare_signing(signer, esig_data, pending_timeout).await?;
+    let signed = sign_prepared(db, config_state, signer, &esig_data, &hash, room_id).await;
+    finish_chain_link(signer, &esig_data, &hash, signed.as_ref().ok()).await;
+
+    signed.map(|signature| (signature, esig_data))
+}
+
+async fn sign_prepared(
+    db: &State<rocket_instances::SharedDb>,
+    config_state: &State<rocket_instances::ServerConfigState>,
+    signer: &State<Arc<RwLock<signing::Signer>>>,
+    esig_data: &EndpointSignatureData,
+    hash: &str,
+    room_id: u16
+) -> Result<SignatureFormats, status::Custom<String>> {
+    let server_id: u16 = config_state.config().lock().unwrap().server_id();
+
+    // if the server is listed among the participants, it coordinates the signing
+    // and the other participants are invited over the mTLS channel
+    if esig_data.contains_participant(server_id) {
+        let others = esig_data.without_participant(server_id);
+
+        // the participants sign with a precomputed presignature if there is one
+        let (presignature, chain_head) = {
+            let mut signer = signer.write().await;
+            if let Err(e) = signer.register_participant_set(esig_data.key_id(), esig_data.participants()) {
+                return Err(protocol_error(Status::BadRequest, "Other participants or the key are invalid", e));
+            }
+            let chain_head = esig_data.chain()
+                .and_then(|link| signer.chains().head(esig_data.key_id(), link.stream_id()))
+                .cloned();
+            (signer.take_any_presignature(esig_data.key_id(), &others.participant_ids()), chain_head)
+        };
+        let (presignature_id, presignature) = presignature.unzip();
+
+        // the head of a chained record goes along, so that a participant that missed records catches up with it
+        let invitation = esig_data.with_presignature_id(presignature_id.clone()).with_chain_head(chain_head);
+        let mut refused = invite_participants(db, &invitation, &others, room_id).await;
+        let presignature = match presignature {
+            // an invitee that refused does not hold the presignature, so it is not put back into the pool.
+            // If nobody accepted, nobody signs with it and the participants run a new offline stage instead
+            Some(_) if refused.len() == others.participant_ids().len() => {
+                println!("Discarded presignature {}, the invitees refused it: {:#}",
+                         presignature_id.unwrap_or_default(), refused[0]);
+                refused = invite_participants(db, &invitation.with_presignature_id(None), &others, room_id).await;
+                None
+            }
+            presignature => presignature,
+        };
+        if !refused.is_empty() {
+            return Err(protocol_error(Status::BadRequest, "Unable to invite other participants", refused.remove(0)));
+        }
+
+        return sign_with_participants(db, signer, server_id, room_id, &others, hash, presignature)
+            .await
+            .map_err(|e| protocol_error(Status::BadRequest, "Message could not be signed", e));
+    }
+
+    sign_with_participants(db, signer, server_id, room_id, esig_data, hash, None)
+        .await
+        .map_err(|e| protocol_error(Status::BadRequest, "Message could not be signed", e))
+}
+
+// Called by the coordinator of the signing. The participant joins the signing in the background,
+// and the coordinator is the one that returns the final signature to the client.
+#[rocket::post("/sign_invitation/<room_id>", data = "<data>")]
+pub async fn sign_invitation(
+    db: &State<rocket_instances::SharedDb>,
+    config_state: &State<rocket_instances::ServerConfigState>,
+    signer: &State<Arc<RwLock<signing::Signer>>>,
+    data: String,
+    room_id: u16
+) -> Result<Status, status::Custom<String>> {
     let server_id: u16 = config_state.config().lock().unwrap().server_id();
 
     let esig_data = match serde_json::from_str::<EndpointSignatureData>(data.as_str()) {
         Ok(ed) => ed,
-        Err(_) => return Err(status::BadRequest("Unable to parse json data"))
+        Err(_) => return Err(status::Custom(Status::BadRequest, String::from("Unable to parse json data")))
+    };
+
+    if !esig_data.contains_participant(server_id) {
+        return Err(status::Custom(Status::BadRequest, String::from("Server is not a participant of the signing")));
+    }
+
+    if let (Some(link), Some(head)) = (esig_data.chain(), esig_data.chain_head()) {
+        catch_up(signer, esig_data.key_id(), link.stream_id(), head).await;
+    }
+    let pending_timeout = config_state.config().lock().unwrap().timeouts().signing();
+    let (esig_data, hash) = prepare_signing(signer, &esig_data, pending_timeout).await?;
+
+    let others = esig_data.without_participant(server_id);
+
+    let presignature = match esig_data.presignature_id() {
+        None => None,
+        Some(id) => match signer.write().await.take_presignature(esig_data.key_id(), &others.participant_ids(), id) {
+            None => {
+                finish_chain_link(signer, &esig_data, &hash, None).await;
+                return Err(status::Custom(Status::Conflict, String::from("Presignature is not available")));
+            }
+            Some(p) => Some(p),
+        }
     };
 
-    let original_data = hex2string::hex_to_string(String::from(esig_data.data_to_sign()));
+    let db = db.inner().clone();
+    let signer = signer.inner().clone();
+
+    tokio::spawn(async move {
+        let signed = sign_with_participants(&db, &signer, server_id, room_id, &others, &hash, presignature).await;
+        finish_chain_link(&signer, &esig_data, &hash, signed.as_ref().ok()).await;
+        match signed {
+            Ok(_) => println!("Signing in room {} as an invited participant finished", room_id),
+            Err(e) => println!("Signing in room {} as an invited participant failed: {:#}", room_id, e),
+        }
+    });
+
+    Ok(Status::Accepted)
+}
+
+// Timeouts of the other parties are reported as 504, everything else with the given status
+fn protocol_error(status: Status, context: &str, e: anyhow::Error) -> status::Custom<String> {
+    let status = if e.downcast_ref::<PhaseTimeout>().is_some() {
+        Status::GatewayTimeout
+    } else {
+        status
+    };
+
+    let message = format!("{}: {:#}", context, e);
+    println!("{}", message);
+    status::Custom(status, message)
+}
+
+// Computes the digest to sign. The position of a chained record is the one right after the chain head of this server,
+// a position given by the client or the coordinator has to be the same. The stream is reserved, so that no other record
+// of the stream is signed until this one is finished, a record waits at most as long as a signature takes for the one before
+async fn prepare_signing(
+    signer: &RwLock<signing::Signer>,
+    esig_data: &EndpointSignatureData,
+    pending_timeout: Duration
+) -> Result<(EndpointSignatureData, String), status::Custom<String>> {
+    let link = match esig_data.chain() {
+        None => return hash_signature_data(esig_data)
+            .map(|hash| (esig_data.clone(), hash))
+            .map_err(|e| status::Custom(Status::BadRequest, e)),
+        Some(link) => link,
+    };
+    check_chainable(&*signer.read().await, esig_data.key_id()).map_err(|e| status::Custom(Status::BadRequest, e))?;
+
+    // a participant may still store the previous record of the stream when the coordinator already invites it to the next one
+    let deadline = tokio::time::Instant::now() + pending_timeout;
+    loop {
+        let signer_guard = signer.read().await;
+        let finished = match signer_guard.chains().finished(esig_data.key_id(), link.stream_id()) {
+            None => break,
+            Some(finished) => finished,
+        };
+        // registered before the lock is released, the stream is only freed under the write lock
+        let notified = finished.notified();
+        drop(signer_guard);
+        if tokio::time::timeout_at(deadline, notified).await.is_err() {
+            break;
+        }
+    }
+
+    let mut signer = signer.write().await;
+    let link = match signer.chains().next_link(esig_data.key_id(), link) {
+        Ok(link) => link,
+        Err(e) => return Err(protocol_error(Status::Conflict, "The record cannot be chained", e)),
+    };
+    let esig_data = esig_data.with_chain(link.clone());
+    let hash = hash_signature_data(&esig_data).map_err(|e| status::Custom(Status::BadRequest, e))?;
+    if let Err(e) = signer.chains_mut().reserve(esig_data.key_id(), &link) {
+        return Err(protocol_error(Status::Conflict, "The record cannot be chained", e));
+    }
+
+    Ok((esig_data, hash))
+}
+
+// Two sets of participants only share a server that keeps them from forking a stream if every set is more than half of the parties
+fn check_chainable(signer: &signing::Signer, key_id: &str) -> Result<(), String> {
+    let local_share = signer.local_share(key_id).ok_or(format!("Key {} does not exist", key_id))?;
+    if 2 * (local_share.t + 1) <= local_share.n {
+        return Err(format!("Records cannot be chained with key {}, {} of its {} parties sign, which is not more than half of them",
+                           key_id, local_share.t + 1, local_share.n));
+    }
+    Ok(())
+}
+
+// Moves the chain head of this server forward to the head of another server, if that is ahead and signed with the key.
+// A head that cannot be used is only logged, the record is refused later if this server is still behind
+async fn catch_up(signer: &RwLock<signing::Signer>, key_id: &str, stream_id: &str, head: &ChainHead) {
+    let mut signer = signer.write().await;
+    let public_key = match signer.public_key(key_id) {
+        None => return,
+        Some(public_key) => public_key,
+    };
+    match signer.chains_mut().fast_forward(key_id, stream_id, head, &public_key) {
+        Ok(true) => println!("Stream {} caught up with sequence {}", stream_id, head.sequence()),
+        Ok(false) => {}
+        Err(e) => println!("Unable to catch up with the head of stream {}: {:#}", stream_id, e),
+    }
+}
+
+// A server that did not take part in the last records of a stream catches up with the heads of the other participants
+async fn catch_up_with_participants(
+    db: &rocket_instances::SharedDb,
+    signer: &RwLock<signing::Signer>,
+    server_id: u16,
+    esig_data: &EndpointSignatureData,
+    link: &ChainLink
+) -> Result<(), status::Custom<String>> {
+    if !is_valid_stream_id(link.stream_id()) {
+        return Err(status::Custom(Status::BadRequest, format!("Invalid stream id {}", link.stream_id())));
+    }
+    let path = format!("chain/{}?key_id={}", link.stream_id(), esig_data.key_id());
+
+    let heads = esig_data.without_participant(server_id).participant_ids()
+        .into_iter()
+        .map(|peer| {
+            let path = path.clone();
+            async move { (peer, db.get_from_peer(peer, &path).await) }
+        });
+    for (peer, head) in futures::future::join_all(heads).await {
+        match head.map(|head| head.map(|head| serde_json::from_str::<ChainHead>(&head))) {
+            Ok(None) => {}
+            Ok(Some(Ok(head))) => catch_up(signer, esig_data.key_id(), link.stream_id(), &head).await,
+            Ok(Some(Err(e))) => println!("Unable to parse the head of stream {} of server {}: {}", link.stream_id(), peer, e),
+            Err(e) => println!("Unable to get the head of stream {} from server {}: {:#}", link.stream_id(), peer, e),
+        }
+    }
+    Ok(())
+}
+
+// Advances the chain head once the record is signed, a record that failed frees the stream for the next try
+async fn finish_chain_link(
+    signer: &RwLock<signing::Signer>,
+    esig_data: &EndpointSignatureData,
+    hash: &str,
+    signature: Option<&SignatureFormats>
+) {
+    let link = match esig_data.chain() {
+        None => return,
+        Some(link) => link,
+    };
+
+    let mut signer = signer.write().await;
+    let (signature, entry_digest, (_, previous)) = match (signature, esig_data.entry_digest(DigestVersion::default()), link.position()) {
+        (Some(signature), Ok(entry_digest), Ok(position)) => (signature, entry_digest, position),
+        _ => {
+            signer.chains_mut().release(esig_data.key_id(), link.stream_id());
+            return;
+        }
+    };
+    let record = HeadRecord::new(&previous, esig_data.timestamp(), &entry_digest, signature.der());
+    if let Err(e) = signer.chains_mut().commit(esig_data.key_id(), link, &hex::decode(hash).unwrap(), record) {
+        println!("Unable to store the chain head of stream {}: {:#}", link.stream_id(), e);
+    }
+}
 
+fn hash_signature_data(esig_data: &EndpointSignatureData) -> Result<String, String> {
     let window = Duration::from_secs(600);
-    let timestamp = match esig_data.timestamp().clone().parse::<u64>() {
+    let timestamp = match esig_data.timestamp().parse::<u64>() {
         Ok(v) => v,
-        Err(_) => return Err(status::BadRequest("TIMESTAMP IN BAD FORMAT"))
+        Err(_) => return Err(String::from("TIMESTAMP IN BAD FORMAT"))
     };
     if !check_timestamp::verify_timestamp_10_minute_window(timestamp, window) {
         let too_old_timestamp: &str = "TIMESTAMP IS OLDER THAN 10 MINUTES";
         println!("{}", too_old_timestamp);
-        return Err(status::BadRequest(too_old_timestamp));
+        return Err(String::from(too_old_timestamp));
     }
 
-    let hash = sha256::digest(original_data + esig_data.timestamp());
-
-    let participant_ids = esig_data.participant_ids();
-    let participant_urls = esig_data.participant_urls();
+    // all the participants sign the current version of the digest
+    let digest = esig_data.signed_digest(DigestVersion::default())?;
+    Ok(hex::encode(digest))
+}
 
-    println!(
-        "My ID: {}\n\
-         Other server IDs: {:?}\n\
-         Other server URLs: {:?}\n\
-         Data to sign: {}\n", server_id, &participant_ids, &participant_urls, hash
-    );
+// The errors of the invitees that refused the invitation, the others joined the signing
+async fn invite_participants(
+    db: &rocket_instances::SharedDb,
+    esig_data: &EndpointSignatureData,
+    others: &EndpointSignatureData,
+    room_id: u16
+) -> Vec<anyhow::Error> {
+    let invitation = match serde_json::to_string(esig_data) {
+        Ok(invitation) => invitation,
+        Err(e) => return vec![e.into()],
+    };
 
-    if !signer.read().await.is_offline_stage_complete(&participant_ids) {
-        let arbitrary_server_id = match signer.read().await.
-            real_to_arbitrary_index(&participant_ids) {
-            None => return Err(status::BadRequest("Other participants are invalid")),
-            Some(asi) => asi
-        };
+    let invitations = others.participant_ids()
+        .into_iter()
+        .map(|peer| {
+            let invitation = invitation.clone();
+            async move { db.send_invitation(peer, "sign_invitation", room_id, invitation).await }
+        });
+
+    futures::future::join_all(invitations).await
+        .into_iter()
+        .filter_map(|result| result.err())
+        .collect()
+}
 
-        let (receiving_stream, outgoing_sink)
-            = db.create_room::<OfflineProtocolMessage>(arbitrary_server_id, room_id, &participant_urls).await;
+// In the key generation, the index of every party is its server id
+fn keygen_party_indices(peers: &[u16]) -> HashMap<u16, u16> {
+    peers.iter().map(|peer| (*peer, *peer)).collect()
+}
 
-        let receiving_stream = receiving_stream.fuse();
-        tokio::pin!(receiving_stream);
-        tokio::pin!(outgoing_sink);
+// The offline and online stages identify the parties by their position among the sorted participants
+fn signing_party_indices(signing_parties: &[u16], peers: &[u16]) -> HashMap<u16, u16> {
+    signing_parties.iter()
+        .zip(1..)
+        .filter(|(server_id, _)| peers.contains(server_id))
+        .map(|(server_id, index)| (*server_id, index))
+        .collect()
+}
 
-        println!("Beginning offline stage");
+// The indices of the other parties in the offline or online stage, which the room waits for
+fn expected_signing_parties(signing_parties: &[u16], server_id: u16) -> Vec<u16> {
+    signing_parties.iter()
+        .zip(1..)
+        .filter(|(party, _)| **party != server_id)
+        .map(|(_, index)| index)
+        .collect()
+}
 
-        let offline_stage_result = signer.write().await.do_offline_stage(receiving_stream, outgoing_sink, &participant_ids).await;
-        match offline_stage_result {
-            Err(e) => {
-                println!("{}", e.to_string());
-                return Err(status::BadRequest("Offline stage failed"));
-            },
-            _ => {}
-        }
+// The offline and online stages identify the parties by their position among the sorted participants
+fn offline_error_with_server_ids(e: anyhow::Error, signing_parties: &[u16]) -> anyhow::Error {
+    match e.downcast::<PhaseTimeout>() {
+        Ok(timeout) => timeout.map_parties(|index| signing_parties[index as usize - 1]).into(),
+        Err(e) => e,
     }
+}
 
-    let (receiving_stream, outgoing_sink)
-        = db.create_room::<PartialSignature>(server_id, room_id, &participant_urls).await;
+// Runs the offline stage with the other participants in the room of the given phase
+pub(crate) async fn run_offline_stage(
+    db: &rocket_instances::SharedDb,
+    signer: &Arc<RwLock<signing::Signer>>,
+    server_id: u16,
+    room_id: u16,
+    phase: Phase,
+    key_id: &str,
+    participant_ids: &Vec<u16>
+) -> Result<CompletedOfflineStage> {
+    let arbitrary_server_id = signer.read().await
+        .real_to_arbitrary_index(participant_ids)
+        .ok_or_else(|| anyhow!("Other participants are invalid"))?;
+
+    let mut signing_parties = participant_ids.clone();
+    signing_parties.push(server_id);
+    signing_parties.sort();
+    let expected_parties = expected_signing_parties(&signing_parties, server_id);
+
+    let (receiving_stream, outgoing_sink) = db
+        .create_room::<OfflineProtocolMessage>(arbitrary_server_id, room_id, phase,
+                                               &signing_party_indices(&signing_parties, participant_ids), &expected_parties)
+        .await
+        .map_err(|e| offline_error_with_server_ids(e, &signing_parties))?;
 
-    thread::sleep(Duration::from_secs(2)); // wait for others to finish offline stage
+    let receiving_stream = receiving_stream.fuse();
+    tokio::pin!(receiving_stream);
+    tokio::pin!(outgoing_sink);
+
+    println!("Beginning offline stage");
+
+    // the key share is copied out of the signer, which must not stay locked for the whole protocol
+    let signing = signer.read().await.new_offline_stage(key_id, participant_ids)?;
+    let offline_stage = signing::Signer::do_offline_stage(signing, receiving_stream, outgoing_sink);
+    db.run_in_room(room_id, phase, &expected_parties, offline_stage)
+        .await
+        .map_err(|e| offline_error_with_server_ids(e, &signing_parties))
+        .context("Offline stage failed")
+}
+
+// Runs the online stage with the other participants. Without a presignature, a new offline stage is done first,
+// an offline stage is never reused for another signature.
+async fn sign_with_participants(
+    db: &rocket_instances::SharedDb,
+    signer: &Arc<RwLock<signing::Signer>>,
+    server_id: u16,
+    room_id: u16,
+    others: &EndpointSignatureData,
+    hash: &str,
+    presignature: Option<CompletedOfflineStage>
+) -> Result<SignatureFormats> {
+    let participant_ids = others.participant_ids();
+
+    println!(
+        "My ID: {}\n\
+         Other server IDs: {:?}\n\
+         Data to sign: {}\n", server_id, &participant_ids, hash
+    );
+
+    let offline_stage = match presignature {
+        Some(offline_stage) => offline_stage,
+        None => run_offline_stage(db, signer, server_id, room_id, Phase::Offline, others.key_id(), &participant_ids).await?,
+    };
+
+    let online_index = signer.read().await.online_index(others.key_id(), &participant_ids)?;
+    let mut signing_parties = participant_ids.clone();
+    signing_parties.push(server_id);
+    signing_parties.sort();
+    let expected_parties = expected_signing_parties(&signing_parties, server_id);
+
+    let (receiving_stream, outgoing_sink) = db
+        .create_room::<PartialSignature>(online_index, room_id, Phase::Online,
+                                         &signing_party_indices(&signing_parties, &participant_ids), &expected_parties)
+        .await
+        .map_err(|e| offline_error_with_server_ids(e, &signing_parties))?;
 
     println!("Beginning online stage");
 
     tokio::pin!(receiving_stream);
     tokio::pin!(outgoing_sink);
 
-    let signature = signer.read().await.sign_hash(&hash, receiving_stream, outgoing_sink, participant_ids)
+    let online_stage = signing::Signer::sign_hash(online_index, hash, offline_stage, receiving_stream, outgoing_sink, participant_ids.clone());
+    db.run_in_room(room_id, Phase::Online, &expected_parties, online_stage)
         .await
-        .expect("Message could not be signed");
-
-    Ok(signature)
+        .map_err(|e| offline_error_with_server_ids(e, &signing_parties))
 }
 
 // This function creates the communication channels between the servers
 // The messages sent to the outgoing sink will be received by other servers in their receiving_stream
 // And vice versa, the messages sent by other servers to their outgoing sink will be received by this server in its receiving_stream
-#[rocket::post("/receive_broadcast/<room_id>", data = "<data>")]
+// The sender of a message has to be the party of the server that presented the client certificate
+#[rocket::post("/receive_broadcast/<room_id>/<phase>", data = "<data>")]
 pub async fn receive_broadcast(db: &State<rocket_instances::SharedDb>,
+                               certificate: Certificate<'_>,
                                room_id: u16,
+                               phase: &str,
                                data: Data<'_>) -> Result<Status, std::io::Error> {
+    let phase = match Phase::from_name(phase) {
+        None => return Ok(Status::NotFound),
+        Some(p) => p
+    };
+
+    let from = match db.authenticate_peer(certificate.as_bytes()) {
+        None => {
+            eprintln!("Rejected a message for {}-{} from a server that is not a configured peer", phase.as_str(), room_id);
+            return Ok(Status::Forbidden);
+        }
+        Some(server_id) => server_id
+    };
+
     let mut buffer = Vec::new();
     let data_length = data.open(1.mebibytes()).read_to_end(&mut buffer).await?;
 
@@ -214,9 +887,63 @@ pub async fn receive_broadcast(db: &State<rocket_instances::SharedDb>,
 
     let message = String::from_utf8(buffer).unwrap_or_else(|_| String::from("Invalid UTF-8"));
 
-    if let Some(room) = db.get_room(room_id).await {
-        room.receive(message).await;
+    match db.deliver_message(from, room_id, phase, message).await {
+        Ok(_) => Ok(Status::Ok),
+        Err(e) => {
+            eprintln!("Rejected a message for {}-{}: {}", phase.as_str(), room_id, e);
+            Ok(Status::Forbidden)
+        }
+    }
+}
+
+// Other servers announce here that they joined the room and listen for the protocol messages.
+// The announcing server is the one that presented the client certificate
+#[rocket::post("/ready/<room_id>/<phase>")]
+pub async fn ready(db: &State<rocket_instances::SharedDb>,
+                   certificate: Certificate<'_>,
+                   room_id: u16,
+                   phase: &str) -> Status {
+    let phase = match Phase::from_name(phase) {
+        None => return Status::NotFound,
+        Some(p) => p
+    };
+
+    match db.authenticate_peer(certificate.as_bytes()) {
+        None => {
+            eprintln!("Rejected a readiness announcement for {}-{} from a server that is not a configured peer", phase.as_str(), room_id);
+            Status::Forbidden
+        }
+        Some(server_id) => {
+            db.mark_ready(room_id, phase, server_id);
+            Status::Ok
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::endpoints::pub_endpoints::{expected_signing_parties, signing_party_indices};
+
+    // the servers a room waits for, found from the expected indices as create_room does
+    fn expected_servers(signing_parties: &[u16], server_id: u16) -> Vec<u16> {
+        let others: Vec<u16> = signing_parties.iter().copied().filter(|party| *party != server_id).collect();
+        let expected_parties = expected_signing_parties(signing_parties, server_id);
+        let mut servers: Vec<u16> = signing_party_indices(signing_parties, &others).into_iter()
+            .filter(|(_, index)| expected_parties.contains(index))
+            .map(|(server, _)| server)
+            .collect();
+        servers.sort();
+        servers
     }
 
-    Ok(Status::Ok)
-}
\ No newline at end of file
+    #[test]
+    fn rooms_wait_for_every_other_signer() {
+        assert_eq!(expected_signing_parties(&[1, 3], 1), vec![2]);
+        assert_eq!(expected_servers(&[1, 3], 1), vec![3]);
+        assert_eq!(expected_servers(&[1, 3], 3), vec![1]);
+
+        assert_eq!(expected_signing_parties(&[2, 3, 4], 3), vec![1, 3]);
+        assert_eq!(expected_servers(&[2, 3, 4], 2), vec![3, 4]);
+        assert_eq!(expected_servers(&[2, 3, 4], 3), vec![2, 4]);
+    }
+}
@@ -0,0 +1,3 @@
+//! The key shares and the threshold signing of the server. The signatures are verified with the public key only,
+//! see [`mpc::operations::check_signature`], so they can be checked without a running server.
+pub mod mpc;
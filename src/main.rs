@@ -1,75 +1,118 @@
 extern crate core;
 extern crate hex;
 
-mod mpc;
+use log_signing_mpc::mpc;
 use mpc::operations;
+use mpc::key_store::FileKeyStore;
+use mpc::operations::presignature_pool::PresignaturePool;
+use mpc::operations::log_chain::ChainStore;
+use mpc::utils::sealing::Sealer;
 
 mod communication;
 use communication::create_communication_channel;
+use communication::peer_registry::PeerRegistry;
 pub mod endpoints;
 mod rocket_instances;
 mod mpc_config;
+mod server_config;
 
-use mpc_config::MPCconfig;
+use server_config::{Cli, ServerConfig};
 
 use std::{
     sync::{Arc},
 };
 
 use anyhow::Result;
+use clap::Parser;
 
 use rocket::{
-    data::{ByteUnit, Limits},
+    data::Limits,
 };
 
 use tokio::sync::RwLock;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let args: Vec<String> = std::env::args().collect();
-
-    // id that will be used to filter out messages
-    let server_id = args.get(1).and_then(|s| s.parse::<u16>().ok()).unwrap_or(1);
-    let port = args.get(2).and_then(|s| s.parse::<u16>().ok()).unwrap_or(8001);
-    let port_mutual_auth = args.get(3).and_then(|s| s.parse::<u16>().ok()).unwrap_or(3001);
-    let threshold = args.get(4).and_then(|s| s.parse::<u16>().ok()).unwrap_or(1);
-    let number_of_parties = args.get(5).and_then(|s| s.parse::<u16>().ok()).unwrap_or(3);
-
-    let config = MPCconfig::new(server_id, threshold, number_of_parties);
+    let server_config = match ServerConfig::load(Cli::parse()) {
+        Ok(server_config) => server_config,
+        Err(e) => {
+            eprintln!("Invalid configuration: {:#}", e);
+            std::process::exit(2);
+        }
+    };
+    let config = server_config.mpc().clone();
+    let peers = match PeerRegistry::load(server_config.peers(), server_config.cert_dir()) {
+        Ok(peers) => peers,
+        Err(e) => {
+            eprintln!("Invalid configuration: {:#}", e);
+            std::process::exit(2);
+        }
+    };
+    for peer in peers.iter() {
+        println!("Peer {} at {}, certificate SHA-256 {}", peer.server_id(), peer.address(), peer.certificate_fingerprint());
+    }
 
     // TODO: might be good idea to adjust for development and production (https://rocket.rs/v0.4/guide/configuration/)
     // Create a figment with the desired configuration
     let figment = rocket::Config::figment()
-        .merge(("address", "0.0.0.0"))
-        .merge(("workers", 4))
+        .merge(("address", server_config.bind_address()))
+        .merge(("workers", server_config.workers()))
         .merge(("log_level", "normal"))
-        .merge(("limits", Limits::new().limit("json", ByteUnit::from(1048576 * 1024))));
-
-
-    let shared_db = rocket_instances::SharedDb(
-        Arc::new(
-            create_communication_channel::Db::empty(config.threshold())
-        )
-    );
+        // the bodies of /sign, /sign_batch and /verify are read as strings before they are parsed as JSON
+        .merge(("limits", Limits::new()
+            .limit("json", server_config.json_limit())
+            .limit("string", server_config.json_limit())));
+
+
+    let db = match create_communication_channel::Db::empty(config.server_id(),
+                                                           config.timeouts().clone(),
+                                                           server_config.cert_dir(),
+                                                           &peers) {
+        Ok(db) => db,
+        Err(e) => {
+            eprintln!("Unable to set up the connections to the peers: {:#}", e);
+            std::process::exit(2);
+        }
+    };
+    let shared_db = rocket_instances::SharedDb(Arc::new(db));
 
     // Create two Rocket instances with different ports and TLS settings
     let rocket_instance_protected =
         rocket_instances::rocket_with_client_auth(figment.clone(),
                                                   config.clone(),
                                                   shared_db.clone(),
-                                                  port_mutual_auth);
+                                                  server_config.tls_port(),
+                                                  server_config.cert_dir());
     let rocket_instance_public =
         rocket_instances::rocket_without_client_auth(figment.clone(),
                                                      config.clone(),
                                                      shared_db.clone(),
-                                                     port);
-
-    let signer = Arc::new(RwLock::new(
-        operations::signing::Signer::new(
-            config.server_id(),
-            config.threshold(),
-            config.number_of_parties()
-        )));
+                                                     server_config.port());
+
+    let sealer = match server_config.key_share_secret().read().and_then(Sealer::new) {
+        Ok(sealer) => Arc::new(sealer),
+        Err(e) => {
+            eprintln!("Invalid configuration: {:#}", e);
+            std::process::exit(2);
+        }
+    };
+    let presignatures = PresignaturePool::load(&config.presignature_dir(), sealer.clone())?;
+    let chains = ChainStore::load(&config.chain_file())?;
+    let mut signer = operations::signing::Signer::new(config.server_id())
+        .with_key_store(Arc::new(FileKeyStore::new(config.key_share_dir())))
+         .with_sealer(sealer)
+         .with_presignatures(presignatures)
+         .with_chains(chains);
+    if let Err(e) = signer.load_local_shares() {
+        eprintln!("Unable to load the key shares: {:#}", e);
+        std::process::exit(2);
+    }
+    let signer = Arc::new(RwLock::new(signer));
+
+    endpoints::presigning::spawn_presignature_refill(shared_db.clone(),
+                                                     signer.clone(),
+                                                     config.server_id(),
+                                                     config.presignature_pool_depth());
 
     let rocket_instance_protected = rocket_instance_protected.manage(signer.clone());
     let rocket_instance_public = rocket_instance_public.manage(signer.clone());
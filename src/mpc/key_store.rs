@@ -0,0 +1,235 @@
+use std::{
+    collections::HashMap,
+    fs,
+    io::{ErrorKind, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use anyhow::{anyhow, Context, Result};
+
+/// Storage of the (encrypted) key shares, keyed by key id.
+/// The protocol code only talks to this trait, so other backends can be added without touching it.
+pub trait KeyStore: Send + Sync {
+    /// The stored content, None if there is no key with the id
+    fn load(&self, key_id: &str) -> Result<Option<Vec<u8>>>;
+    /// Stores the content, replacing the previous one
+    fn store(&self, key_id: &str, content: &[u8]) -> Result<()>;
+    /// Ids of all the stored keys, sorted
+    fn list(&self) -> Result<Vec<String>>;
+    fn delete(&self, key_id: &str) -> Result<()>;
+    fn exists(&self, key_id: &str) -> Result<bool>;
+}
+
+/// Key ids are used as file names, so only a safe subset of characters is allowed
+pub fn is_valid_key_id(key_id: &str) -> bool {
+    !key_id.is_empty() && key_id.len() <= 64
+        && key_id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+fn check_key_id(key_id: &str) -> Result<()> {
+    if !is_valid_key_id(key_id) {
+        return Err(anyhow!("Invalid key id {}", key_id));
+    }
+    Ok(())
+}
+
+const FILE_EXTENSION: &str = "json";
+
+/// Every key is the file `<key id>.json` of the directory, readable only by the owner.
+/// A key is written to a temporary file first and renamed, so a crash never leaves a partially written key behind
+pub struct FileKeyStore {
+    directory: PathBuf,
+}
+
+impl FileKeyStore {
+    pub fn new(directory: &Path) -> FileKeyStore {
+        FileKeyStore {
+            directory: directory.to_path_buf(),
+        }
+    }
+
+    fn file(&self, key_id: &str) -> PathBuf {
+        self.directory.join(format!("{}.{}", key_id, FILE_EXTENSION))
+    }
+}
+
+impl KeyStore for FileKeyStore {
+    fn load(&self, key_id: &str) -> Result<Option<Vec<u8>>> {
+        check_key_id(key_id)?;
+        let file = self.file(key_id);
+        match fs::read(&file) {
+            Ok(content) => Ok(Some(content)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).with_context(|| format!("read {}", file.display())),
+        }
+    }
+
+    fn store(&self, key_id: &str, content: &[u8]) -> Result<()> {
+        check_key_id(key_id)?;
+        let file = self.file(key_id);
+        let temporary_file = self.directory.join(format!(".{}.{}.tmp", key_id, std::process::id()));
+
+        let written = write_private_file(&temporary_file, content)
+            .and_then(|_| fs::rename(&temporary_file, &file));
+        if let Err(e) = written {
+            let _ = fs::remove_file(&temporary_file);
+            return Err(e).with_context(|| format!("write {}", file.display()));
+        }
+
+        Ok(())
+    }
+
+    fn list(&self) -> Result<Vec<String>> {
+        let mut key_ids = Vec::new();
+        for entry in fs::read_dir(&self.directory)
+            .with_context(|| format!("read directory {}", self.directory.display()))? {
+            let path = entry?.path();
+            let is_key_file = path.is_file() && path.extension().is_some_and(|ext| ext == FILE_EXTENSION);
+            if !is_key_file {
+                continue;
+            }
+            if let Some(key_id) = path.file_stem().and_then(|s| s.to_str()).filter(|id| is_valid_key_id(id)) {
+                key_ids.push(key_id.to_string());
+            }
+        }
+
+        key_ids.sort();
+        Ok(key_ids)
+    }
+
+    fn delete(&self, key_id: &str) -> Result<()> {
+        check_key_id(key_id)?;
+        let file = self.file(key_id);
+        match fs::remove_file(&file) {
+            Ok(_) => Ok(()),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e).with_context(|| format!("delete {}", file.display())),
+        }
+    }
+
+    fn exists(&self, key_id: &str) -> Result<bool> {
+        check_key_id(key_id)?;
+        Ok(self.file(key_id).is_file())
+    }
+}
+
+// the file is created with 0600 permissions and synced before it is renamed
+fn write_private_file(path: &Path, content: &[u8]) -> std::io::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+
+    let mut file = options.open(path)?;
+    file.write_all(content)?;
+    file.sync_all()
+}
+
+/// Keys that live only as long as the store, for tests
+#[derive(Default)]
+pub struct InMemoryKeyStore {
+    keys: Mutex<HashMap<String, Vec<u8>>>,
+}
+
+impl InMemoryKeyStore {
+    pub fn new() -> InMemoryKeyStore {
+        InMemoryKeyStore::default()
+    }
+}
+
+impl KeyStore for InMemoryKeyStore {
+    fn load(&self, key_id: &str) -> Result<Option<Vec<u8>>> {
+        check_key_id(key_id)?;
+        Ok(self.keys.lock().unwrap().get(key_id).cloned())
+    }
+
+    fn store(&self, key_id: &str, content: &[u8]) -> Result<()> {
+        check_key_id(key_id)?;
+        self.keys.lock().unwrap().insert(key_id.to_string(), content.to_vec());
+        Ok(())
+    }
+
+    fn list(&self) -> Result<Vec<String>> {
+        let mut key_ids: Vec<String> = self.keys.lock().unwrap().keys().cloned().collect();
+        key_ids.sort();
+        Ok(key_ids)
+    }
+
+    fn delete(&self, key_id: &str) -> Result<()> {
+        check_key_id(key_id)?;
+        self.keys.lock().unwrap().remove(key_id);
+        Ok(())
+    }
+
+    fn exists(&self, key_id: &str) -> Result<bool> {
+        check_key_id(key_id)?;
+        Ok(self.keys.lock().unwrap().contains_key(key_id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::PathBuf};
+    use crate::mpc::key_store::{FileKeyStore, InMemoryKeyStore, KeyStore};
+
+    fn test_directory(name: &str) -> PathBuf {
+        let directory = std::env::temp_dir().join(format!("key-store-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(&directory).unwrap();
+        directory
+    }
+
+    fn check_store(store: &dyn KeyStore) {
+        assert_eq!(store.load("local-share1").unwrap(), None);
+        assert!(!store.exists("local-share1").unwrap());
+
+        store.store("local-share1", b"first").unwrap();
+        store.store("local-share1", b"second").unwrap();
+        store.store("audit_log", b"other").unwrap();
+
+        assert_eq!(store.load("local-share1").unwrap(), Some(b"second".to_vec()));
+        assert!(store.exists("local-share1").unwrap());
+        assert_eq!(store.list().unwrap(), vec!["audit_log", "local-share1"]);
+
+        store.delete("local-share1").unwrap();
+        store.delete("local-share1").unwrap();
+        assert!(!store.exists("local-share1").unwrap());
+        assert_eq!(store.list().unwrap(), vec!["audit_log"]);
+
+        assert!(store.store("../local-share1", b"escape").is_err());
+        assert!(store.load("").is_err());
+    }
+
+    #[test]
+    fn in_memory_store() {
+        check_store(&InMemoryKeyStore::new());
+    }
+
+    #[test]
+    fn file_store() {
+        let directory = test_directory("files");
+        fs::write(directory.join("notes.txt"), "not a key").unwrap();
+        fs::create_dir(directory.join("presignatures1")).unwrap();
+
+        check_store(&FileKeyStore::new(&directory));
+
+        let files: Vec<String> = fs::read_dir(&directory).unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .filter(|name| name.ends_with(".tmp"))
+            .collect();
+        assert!(files.is_empty());
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(directory.join("audit_log.json")).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        let _ = fs::remove_dir_all(&directory);
+    }
+}
@@ -1,2 +1,3 @@
 pub mod operations;
 pub mod utils;
+pub mod key_store;
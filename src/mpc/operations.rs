@@ -1,3 +1,9 @@
 pub mod check_signature;
 pub mod signing;
 pub mod key_generation;
+pub mod key_refresh;
+pub mod key_resharing;
+pub mod presignature_pool;
+pub mod log_chain;
+#[cfg(test)]
+pub(crate) mod test_fixtures;
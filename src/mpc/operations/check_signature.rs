@@ -1,4 +1,6 @@
-use anyhow::Context;
+use std::str::FromStr;
+
+use anyhow::{Context, Result};
 use curv::{
     arithmetic::traits::Converter,
     elliptic::curves::{secp256_k1::Secp256k1, Point, Scalar},
@@ -7,6 +9,8 @@ use curv::{
 
 use serde::{Deserialize, Serialize};
 
+use crate::mpc::utils::signature_formats::{compact_bytes, parse_signature, SignatureEncoding};
+
 #[derive(Serialize, Deserialize)]
 struct Signature {
     r: Scalar<Secp256k1>,
@@ -14,30 +18,100 @@ struct Signature {
     recid: u32,
 }
 
-pub fn extract_rs(signature: &str) -> (Scalar<Secp256k1>, Scalar<Secp256k1>) {
-    let parsed_signature: Signature = serde_json::from_str(signature).context("Parse signature").unwrap();
-    (parsed_signature.r, parsed_signature.s)
+pub fn extract_rs(signature: &str) -> Result<(Scalar<Secp256k1>, Scalar<Secp256k1>)> {
+    let parsed_signature: Signature = serde_json::from_str(signature).context("Parse signature")?;
+    Ok((parsed_signature.r, parsed_signature.s))
+}
+
+// separates the digests of the log signatures from anything else that could be signed with the key
+const DOMAIN_TAG: &str = "log-signing-mpc/log-signature";
+// separates the roots of the batches from the single log signatures
+const BATCH_DOMAIN_TAG: &str = "log-signing-mpc/batch-root";
+// separates the records of a chain from the records that are signed on their own
+const CHAIN_DOMAIN_TAG: &str = "log-signing-mpc/chain-record";
+// separates the digests computed by clients from the digests the servers compute themselves
+const PREHASHED_DOMAIN_TAG: &str = "log-signing-mpc/v1/prehashed";
+
+/// How the signed digest is computed from the key id, the timestamp and the payload
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DigestVersion {
+    // sha256 of the payload followed by the timestamp, signed by older versions and only verified.
+    // It is ambiguous, "log1" at "23" and "log12" at "3" have the same digest
+    Legacy,
+    // sha256 of the domain tag, the version, the key id, the timestamp and the payload,
+    // each of them prefixed with its length as 8 bytes big endian. The servers sign this version
+    #[default]
+    V1,
+}
+
+impl FromStr for DigestVersion {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<DigestVersion, String> {
+        serde_json::from_value(serde_json::Value::from(name))
+            .map_err(|_| format!("Unknown digest version {}, expected v1 or legacy", name))
+    }
+}
+
+/// The digest that the servers sign for the payload at the timestamp with the key
+pub fn signed_digest(version: DigestVersion, key_id: &str, payload: &[u8], timestamp: &str) -> Vec<u8> {
+    let encoded = match version {
+        DigestVersion::Legacy => [payload, timestamp.as_bytes()].concat(),
+        DigestVersion::V1 => length_prefixed(&[DOMAIN_TAG.as_bytes(), b"v1", key_id.as_bytes(), timestamp.as_bytes(), payload]),
+    };
+    hex::decode(sha256::digest(encoded.as_slice())).unwrap()
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct YSumS {
-    curve: String,
-    point: Vec<u8>,
+/// The digest that the servers sign for the Merkle root of a batch, encoded as the v1 digest under its own domain tag
+pub fn signed_batch_digest(key_id: &str, root: &[u8], timestamp: &str) -> Vec<u8> {
+    let encoded = length_prefixed(&[BATCH_DOMAIN_TAG.as_bytes(), b"v1", key_id.as_bytes(), timestamp.as_bytes(), root]);
+    hex::decode(sha256::digest(encoded.as_slice())).unwrap()
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct MyObject {
-    y_sum_s: YSumS,
+/// The digest that the servers sign for a digest computed by the client. It is bound to the key and the timestamp
+/// under its own domain tag, so that a client cannot pass off the digest of a record, a batch or a chain as its own
+pub fn signed_prehashed_digest(key_id: &str, client_digest: &[u8], timestamp: &str) -> Vec<u8> {
+    let encoded = length_prefixed(&[PREHASHED_DOMAIN_TAG.as_bytes(), key_id.as_bytes(), timestamp.as_bytes(), client_digest]);
+    hex::decode(sha256::digest(encoded.as_slice())).unwrap()
 }
 
-pub fn get_public_key(json_str: &str) -> Point<Secp256k1> {
-    let obj: MyObject = serde_json::from_str(json_str).unwrap();
-    let data = obj.y_sum_s.point;
-    let point: Point<Secp256k1> = Point::from_bytes(&data).unwrap();
-    point
+/// The digest that the servers sign for a record of a stream, it commits to the sequence number
+/// and to the signed digest of the previous record besides the digest of the data
+pub fn signed_chain_digest(key_id: &str, stream_id: &str, sequence: u64, previous: &[u8], entry_digest: &[u8], timestamp: &str) -> Vec<u8> {
+    let encoded = length_prefixed(&[CHAIN_DOMAIN_TAG.as_bytes(), b"v1", key_id.as_bytes(), stream_id.as_bytes(),
+                                    &sequence.to_be_bytes(), previous, timestamp.as_bytes(), entry_digest]);
+    hex::decode(sha256::digest(encoded.as_slice())).unwrap()
 }
 
-// implementation from https://github.com/ZenGo-X/multi-party-ecdsa/blob/master/examples/common.rs
+fn length_prefixed(fields: &[&[u8]]) -> Vec<u8> {
+    let mut encoded = Vec::new();
+    for field in fields {
+        encoded.extend_from_slice(&(field.len() as u64).to_be_bytes());
+        encoded.extend_from_slice(field);
+    }
+    encoded
+}
+
+/// Checks an encoded signature of the digest, the encoding is detected if it is not given.
+/// Fails if the signature cannot be parsed, so that a malformed signature is told apart from an invalid one
+pub fn verify_signature(
+    signature: &[u8],
+    encoding: Option<SignatureEncoding>,
+    digest: &[u8],
+    public_key: &Point<Secp256k1>,
+) -> Result<bool> {
+    let encoding = match encoding {
+        Some(encoding) => encoding,
+        None => SignatureEncoding::detect(signature)?,
+    };
+    let (r, s) = parse_signature(signature, encoding).context("Unable to parse the signature")?;
+
+    Ok(check_sig(&r, &s, &BigInt::from_bytes(digest), public_key))
+}
+
+// implementation from https://github.com/ZenGo-X/multi-party-ecdsa/blob/master/examples/common.rs.
+// A signature with a high s is invalid, libsecp256k1 only verifies the low s
 pub fn check_sig(
     r: &Scalar<Secp256k1>,
     s: &Scalar<Secp256k1>,
@@ -58,16 +132,10 @@ pub fn check_sig(
     }
     let pk = PublicKey::from_slice(&raw_pk).unwrap();
 
-    let mut compact: Vec<u8> = Vec::new();
-    let bytes_r = &r.to_bytes().to_vec();
-    compact.extend(vec![0u8; 32 - bytes_r.len()]);
-    compact.extend(bytes_r.iter());
-
-    let bytes_s = &s.to_bytes().to_vec();
-    compact.extend(vec![0u8; 32 - bytes_s.len()]);
-    compact.extend(bytes_s.iter());
-
-    let secp_sig = Signature::from_compact(compact.as_slice()).unwrap();
+    let secp_sig = match Signature::from_compact(&compact_bytes(r, s)) {
+        Ok(secp_sig) => secp_sig,
+        Err(_) => return false,
+    };
 
     SECP256K1.verify(&msg, &secp_sig, &pk).is_ok()
 }
@@ -77,7 +145,7 @@ mod tests {
     use curv::arithmetic::Converter;
     use curv::BigInt;
     use curv::elliptic::curves::{Point, Secp256k1};
-    use crate::mpc::operations::check_signature::{check_sig, extract_rs, get_public_key};
+    use crate::mpc::operations::check_signature::{check_sig, extract_rs, signed_batch_digest, signed_digest, signed_prehashed_digest, DigestVersion};
 
     const PUBLIC_KEY_COMPRESSED: [u8; 33] = [3, 183, 191, 143, 211, 92, 155, 44, 130, 59, 29, 152, 124, 146, 233, 81, 9, 70, 219, 20, 100, 4, 243, 31, 227, 146, 20, 116, 205, 145, 227, 57, 0];
 
@@ -96,7 +164,7 @@ mod tests {
               "recid": 0
             }
             "#;
-        let (r,s) = extract_rs(signature);
+        let (r,s) = extract_rs(signature).unwrap();
         let str_num = String::from("sign_this_data1681402350");
         let msg = BigInt::from_bytes(str_num.as_bytes());
         let public_key: Point<Secp256k1> = Point::from_bytes(&PUBLIC_KEY_COMPRESSED).unwrap();
@@ -118,7 +186,7 @@ mod tests {
               "recid": 0
             }
             "#;
-        let (r,s) = extract_rs(signature);
+        let (r,s) = extract_rs(signature).unwrap();
         let str_num = String::from("sign_this_data1681402350");
         let msg = BigInt::from_bytes(str_num.as_bytes());
         let public_key: Point<Secp256k1> = Point::from_bytes(&PUBLIC_KEY_COMPRESSED).unwrap();
@@ -126,23 +194,23 @@ mod tests {
     }
 
     #[test]
-    fn extract_public_key() {
-        let json_str = r#"
-            {
-              "y_sum_s": {
-                "curve": "secp256k1",
-                "point": [
-                  2, 137, 233, 76, 83, 210, 173, 139, 125, 48, 202, 72, 69, 133, 79, 72, 137, 20, 18, 29, 235, 13, 67, 1, 76, 189, 174, 222, 34, 237, 1, 79, 188
-                ]
-              },
-              "i": 1,
-              "t": 1,
-              "n": 3
-            }
-            "#;
-        let public_compressed = get_public_key(json_str);
-        let expected: [u8; 33] = [2, 137, 233, 76, 83, 210, 173, 139, 125, 48, 202, 72, 69, 133, 79, 72, 137, 20, 18, 29, 235, 13, 67, 1, 76, 189, 174, 222, 34, 237, 1, 79, 188];
-        let expected: Point<Secp256k1> = Point::from_bytes(&expected).unwrap();
-        assert_eq!(public_compressed, expected);
+    fn digest_of_data_and_timestamp() {
+        let digest = |version, key_id, payload: &[u8], timestamp| hex::encode(signed_digest(version, key_id, payload, timestamp));
+
+        assert_eq!(digest(DigestVersion::V1, "default", b"log line", "1681402350"), "ce9bb7c7e2f09dab5d6c74ec1bdfb3fcb4058701b9af1b68c9b0cecbe3b228f1");
+        assert_ne!(digest(DigestVersion::V1, "default", b"log1", "23"), digest(DigestVersion::V1, "default", b"log12", "3"));
+        assert_ne!(digest(DigestVersion::V1, "default", b"log line", "1681402350"), digest(DigestVersion::V1, "tenant-a", b"log line", "1681402350"));
+
+        assert_eq!(digest(DigestVersion::Legacy, "default", b"log line", "1681402350"), sha256::digest("log line1681402350"));
+        assert_eq!(digest(DigestVersion::Legacy, "default", b"log1", "23"), digest(DigestVersion::Legacy, "tenant-a", b"log12", "3"));
+
+        // a root is not signed as the data of a single signature
+        let root = [7u8; 32];
+        assert_ne!(hex::encode(signed_batch_digest("default", &root, "1681402350")), digest(DigestVersion::V1, "default", &root, "1681402350"));
+        // nor is a digest of a client, even if it is the digest of a record or a root
+        let record = signed_digest(DigestVersion::V1, "default", b"log line", "1681402350");
+        assert_ne!(signed_prehashed_digest("default", &record, "1681402350"), record);
+        assert_ne!(signed_prehashed_digest("default", &root, "1681402350"), signed_batch_digest("default", &root, "1681402350"));
+        assert_ne!(signed_prehashed_digest("default", &root, "1681402350"), signed_prehashed_digest("tenant-a", &root, "1681402350"));
     }
-}
\ No newline at end of file
+}
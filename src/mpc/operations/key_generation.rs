@@ -1,70 +1,113 @@
-use std::fs::File;
-use std::io::{Write};
-use std::path::Path;
+use std::collections::BTreeMap;
 use std::pin::Pin;
-use std::{thread};
-use std::time::Duration;
-use anyhow::{anyhow, Error, Result};
+use anyhow::{anyhow, bail, Error, Result};
 use curv::elliptic::curves::Secp256k1;
-use futures::{Sink, Stream};
+use futures::{Sink, SinkExt, Stream, StreamExt};
 use multi_party_ecdsa::protocols::multi_party_ecdsa::gg_2020::state_machine::keygen::{Keygen, ProtocolMessage, LocalKey};
 use round_based::{AsyncProtocol, Msg};
+use serde::{Deserialize, Serialize};
 
 use futures::stream::Fuse;
 
-fn are_keys_already_generated(index: u16) -> Result<String, String> {
-    let file_name: String = format!("local-share{}.json", index);
-    let file_path: &Path = Path::new(file_name.as_str());
-    if file_path.exists() {
-        let error_msg = format!("{} already exists. If you want to generate keys, remove already existing ones.", file_name.clone());
-        return Err(error_msg);
-    }
-    Ok(file_name)
-}
-
+/// Runs the key generation, the caller stores the resulting key share
 pub async fn generate_keys(index: u16,
                            receiving_stream: Pin<&mut Fuse<impl Stream<Item=Result<Msg<ProtocolMessage>>>>>,
                            outgoing_sink: Pin<&mut impl Sink<Msg<ProtocolMessage>, Error=Error>>,
                            threshold: u16,
                            number_of_parties: u16
-) -> Result<(), String> {
-    let file = are_keys_already_generated(index);
-    let file = match file {
-        Ok(f) => f,
-        Err(e) => return Err(e),
-    };
-
-    // wait for other servers to catch up (if started manually)
-    // TODO: do this synchronization in a better way then sleeping
-    let five_seconds:Duration = Duration::from_secs(5);
-    thread::sleep(five_seconds);
-
+) -> Result<LocalKey<Secp256k1>, String> {
     let keygen: Keygen = Keygen::new(index, threshold, number_of_parties).unwrap();
     let results: Result<LocalKey<Secp256k1>, Error> = AsyncProtocol::new(keygen, receiving_stream, outgoing_sink)
         .run()
         .await
         .map_err(|e| anyhow!("protocol execution terminated with error: {}", e));
 
-    let local_key: LocalKey<Secp256k1> = results.unwrap();
+    results.map_err(|e| e.to_string())
+}
+
+/// What every party announces after the key generation, so that all of them know they computed the same key
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeyConfirmation {
+    // hex encoded, compressed
+    public_key: String,
+    // sha256 of the threshold, the number of parties and the public key shares,
+    // which every party computes from the VSS commitments of all the parties
+    commitments_digest: String,
+}
+
+impl KeyConfirmation {
+    pub fn of(local_key: &LocalKey<Secp256k1>) -> Result<KeyConfirmation> {
+        let commitments = serde_json::to_vec(&(local_key.t, local_key.n, &local_key.pk_vec))?;
+        Ok(KeyConfirmation {
+            public_key: hex::encode(&*local_key.y_sum_s.to_bytes(true)),
+            commitments_digest: sha256::digest(commitments.as_slice()),
+        })
+    }
+}
+
+/// Exchanges the confirmations with all the other parties of the key.
+/// Fails with the parties that computed another key, the key share must not be used then
+pub async fn confirm_key(
+    local_key: &LocalKey<Secp256k1>,
+    mut receiving_stream: Pin<&mut impl Stream<Item = Result<Msg<KeyConfirmation>>>>,
+    mut outgoing_sink: Pin<&mut impl Sink<Msg<KeyConfirmation>, Error = Error>>
+) -> Result<()> {
+    let confirmation = KeyConfirmation::of(local_key)?;
+    outgoing_sink.send(Msg {
+        sender: local_key.i,
+        receiver: None,
+        body: confirmation.clone(),
+    }).await?;
+
+    let mut received = BTreeMap::new();
+    while received.len() < usize::from(local_key.n) - 1 {
+        let message = receiving_stream.next().await
+            .ok_or(anyhow!("The key confirmation ended before all parties confirmed"))??;
+        if received.insert(message.sender, message.body).is_some() {
+            bail!("Party {} confirmed twice", message.sender);
+        }
+    }
+
+    check_confirmations(&confirmation, &received)
+}
+
+/// Fails if any party confirmed another key than this party, naming the parties and what differs
+pub fn check_confirmations(own: &KeyConfirmation, received: &BTreeMap<u16, KeyConfirmation>) -> Result<()> {
+    let diverged: Vec<String> = received.iter()
+        .filter(|(_, confirmation)| *confirmation != own)
+        .map(|(party, confirmation)| if confirmation.public_key != own.public_key {
+            format!("party {} computed public key {}", party, confirmation.public_key)
+        } else {
+            format!("party {} computed other public key shares", party)
+        })
+        .collect();
 
-    let generation_result = generate_file(&file, &local_key);
-    match generation_result {
-        Ok(_) => Ok(()),
-        Err(_) => Err("Unable to generate file".to_string()),
+    if !diverged.is_empty() {
+        bail!("The parties computed different keys, this party computed public key {}: {}", own.public_key, diverged.join(", "));
     }
+    Ok(())
 }
 
-fn generate_file(file_name: &String, result: &LocalKey<Secp256k1>) -> Result<usize> {
-    let open_result = File::create(Path::new(file_name));
-    let mut file = match open_result {
-        Ok(f) => f,
-        Err(error) => panic!("Problem with opening file {:?}", error)
-    };
-    let output = serde_json::to_vec_pretty(result);
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use crate::mpc::operations::key_generation::{check_confirmations, KeyConfirmation};
 
-    let write_result = file.write(output.unwrap().as_ref())?;
+    fn confirmation(public_key: &str, commitments_digest: &str) -> KeyConfirmation {
+        KeyConfirmation { public_key: public_key.to_string(), commitments_digest: commitments_digest.to_string() }
+    }
 
-    println!("Generated key written into {}", file_name);
+    #[test]
+    fn diverging_parties_are_named() {
+        let own = confirmation("02aa", "11");
+        let mut received = BTreeMap::from([(2, own.clone()), (3, own.clone())]);
+        assert!(check_confirmations(&own, &received).is_ok());
 
-    Ok(write_result)
-}
\ No newline at end of file
+        received.insert(3, confirmation("02aa", "22"));
+        received.insert(4, confirmation("03bb", "11"));
+        let error = check_confirmations(&own, &received).unwrap_err().to_string();
+        assert!(!error.contains("party 2"));
+        assert!(error.contains("party 3 computed other public key shares"));
+        assert!(error.contains("party 4 computed public key 03bb"));
+    }
+}
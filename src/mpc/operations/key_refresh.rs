@@ -0,0 +1,349 @@
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::pin::Pin;
+
+use anyhow::{anyhow, bail, Context, Error, Result};
+use curv::elliptic::curves::{Point, Scalar, Secp256k1};
+use futures::{Sink, SinkExt, Stream, StreamExt};
+use multi_party_ecdsa::protocols::multi_party_ecdsa::gg_2020::party_i::Keys;
+use multi_party_ecdsa::protocols::multi_party_ecdsa::gg_2020::state_machine::keygen::LocalKey;
+use round_based::Msg;
+use serde::{Deserialize, Serialize};
+
+use crate::mpc::operations::key_resharing::{receiver_keys, verify_receiver_keys, ReceiverKeys};
+use crate::mpc::utils::shares::{decrypt_share, encrypt_share, evaluate, evaluate_commitments, EncryptedShare};
+
+/// Messages of the share refresh. Every party announces the state of its share and its fresh keys first,
+/// then broadcasts its shares and confirms the result afterwards
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum RefreshMessage {
+    Start(RefreshStart),
+    Shares(RefreshShares),
+    // digest of the public part of the refreshed key
+    Confirmation(String),
+}
+
+/// The share a party refreshes from and the keys of its refreshed share
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RefreshStart {
+    // public digest of the share in use
+    active: String,
+    // public digest of the share of an earlier refresh that was not confirmed by every party
+    pending: Option<String>,
+    // the Paillier key and N~ of the refreshed share, generated for this refresh only like in the resharing.
+    // The other parties encrypt their shares to its committed point, so that an old share cannot decrypt the new ones
+    keys: ReceiverKeys,
+}
+
+/// A random polynomial with a zero constant term, distributed to all the other parties.
+/// Adding its values to the shares re-randomizes them without changing the key they share
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RefreshShares {
+    // the generator times the coefficients 1..=t, the constant term is zero and not sent
+    commitments: Vec<Point<Secp256k1>>,
+    // encrypted to the ephemeral keys of the receivers
+    shares: Vec<EncryptedShare>,
+}
+
+const PROTOCOL: &str = "refresh";
+
+/// Whether this party refreshes from its pending share. A refresh that some parties confirmed and others did not
+/// leaves the pending share of the latter equal to the active share of the former, so it is rolled forward.
+/// A pending share that no party activated is discarded. Fails unless every party ends up with the same share
+pub fn roll_forward(own: &RefreshStart, others: &BTreeMap<u16, RefreshStart>) -> Result<bool> {
+    let active: Vec<&String> = std::iter::once(&own.active)
+        .chain(others.values().map(|start| &start.active))
+        .collect();
+    let resolved = |start: &RefreshStart| match &start.pending {
+        Some(pending) if active.contains(&pending) => pending.clone(),
+        _ => start.active.clone(),
+    };
+
+    let own_share = resolved(own);
+    if let Some((party, _)) = others.iter().find(|(_, start)| resolved(start) != own_share) {
+        bail!("Party {} holds another share of the key, it cannot be refreshed", party);
+    }
+    Ok(own.pending.as_ref() == Some(&own_share))
+}
+
+/// The random coefficients 1..=t of this party and the message that distributes their polynomial
+/// to the fresh keys of the other parties
+pub fn share_zero(
+    local_key: &LocalKey<Secp256k1>,
+    receivers: &BTreeMap<u16, ReceiverKeys>
+) -> Result<(Vec<Scalar<Secp256k1>>, RefreshShares)> {
+    if local_key.t == 0 {
+        bail!("A key with threshold 0 cannot be refreshed");
+    }
+
+    let coefficients: Vec<Scalar<Secp256k1>> = (0..local_key.t).map(|_| Scalar::random()).collect();
+    let commitments = coefficients.iter().map(|a| Point::generator() * a).collect();
+
+    let mut shares = Vec::new();
+    for receiver in (1..=local_key.n).filter(|j| *j != local_key.i) {
+        let keys = receivers.get(&receiver).ok_or(anyhow!("Party {} sent no keys", receiver))?;
+        let share = evaluate_without_constant(&coefficients, receiver);
+        shares.push(encrypt_share(PROTOCOL, local_key.i, receiver, keys.share_key(), &share)?);
+    }
+
+    Ok((coefficients, RefreshShares { commitments, shares }))
+}
+
+/// The refreshed key share: the old share plus the values of all the polynomials, with the fresh Paillier keys
+/// and N~ of all the parties. Fails unless every other party sent a share that matches its commitments
+/// and keys with valid proofs
+pub fn apply_refresh(
+    local_key: &LocalKey<Secp256k1>,
+    keys: &Keys,
+    receivers: &BTreeMap<u16, ReceiverKeys>,
+    coefficients: &[Scalar<Secp256k1>],
+    received: &BTreeMap<u16, RefreshShares>
+) -> Result<LocalKey<Secp256k1>> {
+    let t = usize::from(local_key.t);
+    let others: Vec<u16> = (1..=local_key.n).filter(|j| *j != local_key.i).collect();
+    if received.keys().copied().collect::<Vec<u16>>() != others {
+        bail!("Expected the shares of parties {:?}, received the shares of {:?}", others, received.keys().collect::<Vec<_>>());
+    }
+
+    let own_commitments: Vec<Point<Secp256k1>> = coefficients.iter().map(|a| Point::generator() * a).collect();
+    let mut x_i = &local_key.keys_linear.x_i + &evaluate_without_constant(coefficients, local_key.i);
+
+    for (sender, shares) in received {
+        if shares.commitments.len() != t {
+            bail!("Party {} sent {} commitments, expected {}", sender, shares.commitments.len(), t);
+        }
+        let encrypted = shares.shares.iter()
+            .find(|share| share.receiver() == local_key.i)
+            .ok_or(anyhow!("Party {} sent no share for this party", sender))?;
+        let share = decrypt_share(PROTOCOL, *sender, &keys.u_i, encrypted)
+            .with_context(|| format!("Share of party {}", sender))?;
+
+        if Point::generator() * &share != evaluate_commitments_without_constant(&shares.commitments, local_key.i) {
+            bail!("The share of party {} does not match its commitments", sender);
+        }
+        x_i = &x_i + &share;
+    }
+
+    let all_commitments: Vec<&Vec<Point<Secp256k1>>> = std::iter::once(&own_commitments)
+        .chain(received.values().map(|shares| &shares.commitments))
+        .collect();
+
+    let receiver_keys: Vec<&ReceiverKeys> = (1..=local_key.n)
+        .map(|j| receivers.get(&j).ok_or(anyhow!("Party {} sent no keys", j)))
+        .collect::<Result<_>>()?;
+    let broadcasts = verify_receiver_keys(keys, local_key.t, &receiver_keys)?;
+
+    // the VSS scheme of the key share is the one of its own key generation polynomial,
+    // only its parameters are used for signing, so it stays as it is
+    let mut refreshed = local_key.clone();
+    refreshed.paillier_dk = keys.dk.clone();
+    refreshed.paillier_key_vec = broadcasts.iter().map(|broadcast| broadcast.e.clone()).collect();
+    refreshed.h1_h2_n_tilde_vec = broadcasts.iter().map(|broadcast| broadcast.dlog_statement.clone()).collect();
+    for (j, public_share) in (1..).zip(refreshed.pk_vec.iter_mut()) {
+        for commitments in &all_commitments {
+            *public_share = &*public_share + &evaluate_commitments_without_constant(commitments, j);
+        }
+    }
+    refreshed.keys_linear.x_i = x_i;
+
+    if Point::generator() * &refreshed.keys_linear.x_i != refreshed.pk_vec[usize::from(local_key.i) - 1] {
+        bail!("The refreshed share does not match its public share");
+    }
+
+    Ok(refreshed)
+}
+
+/// Digest of the public key, the public key shares and the Paillier keys and N~ of the parties,
+/// equal for all the parties that refreshed their shares with the same messages
+pub fn public_digest(local_key: &LocalKey<Secp256k1>) -> Result<String> {
+    let public_part = serde_json::to_vec(&(&local_key.y_sum_s, &local_key.pk_vec, &local_key.paillier_key_vec, &local_key.h1_h2_n_tilde_vec))?;
+    Ok(sha256::digest(public_part.as_slice()))
+}
+
+/// Runs the refresh with all the other parties of the key. The refreshed share is staged as pending before this party
+/// confirms it, together with the share it was refreshed from, and returned only after every party confirmed that it
+/// computed the same public key shares. A pending share of an interrupted refresh is rolled forward or discarded first
+pub async fn refresh_key<F, Fut>(
+    local_key: &LocalKey<Secp256k1>,
+    pending: Option<&LocalKey<Secp256k1>>,
+    mut receiving_stream: Pin<&mut impl Stream<Item = Result<Msg<RefreshMessage>>>>,
+    mut outgoing_sink: Pin<&mut impl Sink<Msg<RefreshMessage>, Error = Error>>,
+    stage: F
+) -> Result<LocalKey<Secp256k1>>
+where
+    F: FnOnce(LocalKey<Secp256k1>, LocalKey<Secp256k1>) -> Fut,
+    Fut: Future<Output = Result<()>>,
+{
+    let other_parties = usize::from(local_key.n) - 1;
+    let index = local_key.i;
+    let (keys, own_keys) = tokio::task::spawn_blocking(move || receiver_keys(index)).await?;
+    let start = RefreshStart {
+        active: public_digest(local_key)?,
+        pending: pending.map(public_digest).transpose()?,
+        keys: own_keys,
+    };
+
+    outgoing_sink.send(Msg {
+        sender: local_key.i,
+        receiver: None,
+        body: RefreshMessage::Start(start.clone()),
+    }).await?;
+
+    // a fast party may send its shares or confirm before a slow one started, so the later messages are kept aside
+    let mut messages = Received::default();
+    while messages.starts.len() < other_parties {
+        messages.receive(&mut receiving_stream).await?;
+    }
+
+    let base = match (roll_forward(&start, &messages.starts)?, pending) {
+        (true, Some(pending)) => pending,
+        _ => local_key,
+    };
+    let receivers: BTreeMap<u16, ReceiverKeys> = std::iter::once((local_key.i, &start))
+        .chain(messages.starts.iter().map(|(party, start)| (*party, start)))
+        .map(|(party, start)| (party, start.keys.clone()))
+        .collect();
+    let (coefficients, shares) = share_zero(base, &receivers)?;
+
+    outgoing_sink.send(Msg {
+        sender: local_key.i,
+        receiver: None,
+        body: RefreshMessage::Shares(shares),
+    }).await?;
+
+    while messages.shares.len() < other_parties {
+        messages.receive(&mut receiving_stream).await?;
+    }
+
+    let refreshed = apply_refresh(base, &keys, &receivers, &coefficients, &messages.shares)?;
+    let digest = public_digest(&refreshed)?;
+
+    // once this party confirmed, another party may activate the refreshed share, so it must not get lost
+    stage(base.clone(), refreshed.clone()).await?;
+
+    outgoing_sink.send(Msg {
+        sender: local_key.i,
+        receiver: None,
+        body: RefreshMessage::Confirmation(digest.clone()),
+    }).await?;
+
+    while messages.confirmations.len() < other_parties {
+        messages.receive(&mut receiving_stream).await?;
+    }
+    if let Some((party, _)) = messages.confirmations.iter().find(|(_, other_digest)| **other_digest != digest) {
+        bail!("Party {} refreshed its share to a different key", party);
+    }
+
+    Ok(refreshed)
+}
+
+#[derive(Default)]
+struct Received {
+    starts: BTreeMap<u16, RefreshStart>,
+    shares: BTreeMap<u16, RefreshShares>,
+    confirmations: BTreeMap<u16, String>,
+}
+
+impl Received {
+    async fn receive(&mut self, receiving_stream: &mut Pin<&mut impl Stream<Item = Result<Msg<RefreshMessage>>>>) -> Result<()> {
+        let message = receiving_stream.next().await
+            .ok_or(anyhow!("The refresh ended before all parties sent their messages"))??;
+
+        let duplicate = match message.body {
+            RefreshMessage::Start(start) => self.starts.insert(message.sender, start).is_some(),
+            RefreshMessage::Shares(shares) => self.shares.insert(message.sender, shares).is_some(),
+            RefreshMessage::Confirmation(digest) => self.confirmations.insert(message.sender, digest).is_some(),
+        };
+        if duplicate {
+            bail!("Party {} sent the same message twice", message.sender);
+        }
+        Ok(())
+    }
+}
+
+// the polynomial has a zero constant term, so its value is the index times
+// the value of the polynomial with the coefficients 1..=t
+fn evaluate_without_constant(coefficients: &[Scalar<Secp256k1>], index: u16) -> Scalar<Secp256k1> {
+    &Scalar::from(index) * &evaluate(coefficients, index)
+}
+
+fn evaluate_commitments_without_constant(commitments: &[Point<Secp256k1>], index: u16) -> Point<Secp256k1> {
+    &evaluate_commitments(commitments, index) * &Scalar::from(index)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use curv::elliptic::curves::Point;
+    use crate::mpc::operations::key_resharing::receiver_keys;
+    use crate::mpc::operations::test_fixtures::{received_by, simulate_keygen, simulate_refresh, simulate_refresh_messages};
+    use crate::mpc::operations::key_refresh::{apply_refresh, public_digest, roll_forward, share_zero, RefreshStart};
+
+    #[test]
+    fn refresh_keeps_the_public_key() {
+        let local_keys = simulate_keygen(1, 3);
+        let refreshed = simulate_refresh(&local_keys);
+
+        for (old, new) in local_keys.iter().zip(&refreshed) {
+            assert_eq!(old.public_key(), new.public_key());
+            assert_ne!(old.keys_linear.x_i, new.keys_linear.x_i);
+            assert_eq!(Point::generator() * &new.keys_linear.x_i, new.pk_vec[usize::from(new.i) - 1]);
+            assert_eq!(public_digest(new).unwrap(), public_digest(&refreshed[0]).unwrap());
+            // the Paillier keys and N~ are new as well, an old share cannot decrypt in the signings with the new one
+            assert_ne!(old.paillier_dk, new.paillier_dk);
+            assert_eq!(new.paillier_key_vec, refreshed[0].paillier_key_vec);
+            assert_ne!(old.paillier_key_vec, new.paillier_key_vec);
+            assert_ne!(serde_json::to_string(&old.h1_h2_n_tilde_vec).unwrap(), serde_json::to_string(&new.h1_h2_n_tilde_vec).unwrap());
+        }
+    }
+
+    #[test]
+    fn refresh_with_a_forged_share_fails() {
+        let local_keys = simulate_keygen(1, 3);
+        let (keys, receivers, coefficients, mut messages) = simulate_refresh_messages(&local_keys);
+
+        // the old share does not decrypt the shares, they are encrypted to the fresh keys
+        let mut old_keys = keys[0].clone();
+        old_keys.u_i = local_keys[0].keys_linear.x_i.clone();
+        assert!(apply_refresh(&local_keys[0], &old_keys, &receivers, &coefficients[0], &received_by(1, &messages)).is_err());
+        assert!(apply_refresh(&local_keys[0], &keys[0], &receivers, &coefficients[0], &received_by(1, &messages)).is_ok());
+
+        // party 3 sends keys whose commitment does not open
+        let mut forged = serde_json::to_value(&receivers[&3]).unwrap();
+        forged["decommitment"] = serde_json::to_value(&receivers[&2]).unwrap()["decommitment"].clone();
+        let mut forged_keys = receivers.clone();
+        forged_keys.insert(3, serde_json::from_value(forged).unwrap());
+        assert!(apply_refresh(&local_keys[0], &keys[0], &forged_keys, &coefficients[0], &received_by(1, &messages)).is_err());
+
+        // party 2 commits to another polynomial than the one it shared
+        messages[1].commitments = share_zero(&local_keys[1], &receivers).unwrap().1.commitments;
+        assert!(apply_refresh(&local_keys[0], &keys[0], &receivers, &coefficients[0], &received_by(1, &messages)).is_err());
+
+        // party 3 is missing
+        let mut received = received_by(1, &messages);
+        received.remove(&3);
+        assert!(apply_refresh(&local_keys[0], &keys[0], &receivers, &coefficients[0], &received).is_err());
+    }
+
+    #[test]
+    fn interrupted_refresh_is_rolled_forward_or_discarded() {
+        let (_, keys) = receiver_keys(1);
+        let start = |active: &str, pending: Option<&str>| RefreshStart {
+            active: active.to_string(),
+            pending: pending.map(|pending| pending.to_string()),
+            keys: keys.clone(),
+        };
+        let others = |starts: Vec<RefreshStart>| (2..).zip(starts).collect::<BTreeMap<u16, RefreshStart>>();
+
+        // nothing is pending
+        assert!(!roll_forward(&start("old", None), &others(vec![start("old", None)])).unwrap());
+        // party 2 activated the refreshed share before party 1 received all the confirmations
+        assert!(roll_forward(&start("old", Some("new")), &others(vec![start("new", None), start("old", Some("new"))])).unwrap());
+        assert!(!roll_forward(&start("new", None), &others(vec![start("old", Some("new"))])).unwrap());
+        // no party activated it
+        assert!(!roll_forward(&start("old", Some("new")), &others(vec![start("old", Some("new")), start("old", None)])).unwrap());
+        // the shares of the parties belong to different refreshes
+        assert!(roll_forward(&start("old", None), &others(vec![start("new", None)])).is_err());
+        assert!(roll_forward(&start("old", Some("other")), &others(vec![start("new", None)])).is_err());
+    }
+}
@@ -0,0 +1,459 @@
+use std::collections::BTreeMap;
+use std::pin::Pin;
+
+use anyhow::{anyhow, bail, Context, Error, Result};
+use curv::cryptographic_primitives::secret_sharing::feldman_vss::{ShamirSecretSharing, VerifiableSS};
+use curv::elliptic::curves::{Point, Scalar, Secp256k1};
+use futures::{Sink, SinkExt, Stream, StreamExt};
+use multi_party_ecdsa::protocols::multi_party_ecdsa::gg_2020::party_i::{
+    KeyGenBroadcastMessage1, KeyGenDecommitMessage1, Keys, Parameters, SharedKeys,
+};
+use multi_party_ecdsa::protocols::multi_party_ecdsa::gg_2020::state_machine::keygen::LocalKey;
+use round_based::Msg;
+use serde::{Deserialize, Serialize};
+
+use crate::mpc::operations::signing::DEFAULT_KEY_ID;
+use crate::mpc::utils::local_share_utils::KeyShare;
+use crate::mpc::utils::shares::{decrypt_share, encrypt_share, evaluate, evaluate_commitments, lagrange_coefficient, EncryptedShare};
+
+const PROTOCOL: &str = "reshare";
+
+/// The resharing that every participating server is called with, the same request for all of them
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ReshareRequest {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    key_id: Option<String>,
+    // server ids of the old parties that deal their shares, more than the old threshold
+    dealers: Vec<u16>,
+    // server ids of the parties of the new key, sorted. The new index of a party is its position
+    receivers: Vec<u16>,
+    // threshold of the new key
+    threshold: u16,
+}
+
+impl ReshareRequest {
+    pub fn key_id(&self) -> &str {
+        self.key_id.as_deref().unwrap_or(DEFAULT_KEY_ID)
+    }
+
+    pub fn is_dealer(&self, server_id: u16) -> bool {
+        self.dealers.contains(&server_id)
+    }
+
+    /// The index of the server in the new key, None if it is not one of its parties
+    pub fn new_index(&self, server_id: u16) -> Option<u16> {
+        (1..).zip(&self.receivers)
+            .find(|(_, receiver)| **receiver == server_id)
+            .map(|(index, _)| index)
+    }
+
+    /// All the servers that take part, sorted
+    pub fn participants(&self) -> Vec<u16> {
+        let mut participants: Vec<u16> = self.dealers.iter().chain(&self.receivers).copied().collect();
+        participants.sort();
+        participants.dedup();
+        participants
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        let is_sorted_set = |ids: &[u16]| ids.windows(2).all(|pair| pair[0] < pair[1]) && !ids.contains(&0);
+        if self.dealers.is_empty() || !is_sorted_set(&self.dealers) {
+            bail!("The dealers must be sorted server ids without duplicates");
+        }
+        if !is_sorted_set(&self.receivers) {
+            bail!("The receivers must be sorted server ids without duplicates");
+        }
+        if self.threshold == 0 || usize::from(self.threshold) >= self.receivers.len() {
+            bail!("The threshold must be at least 1 and less than the number of receivers");
+        }
+        Ok(())
+    }
+}
+
+/// Messages of the resharing. The receivers publish their Paillier keys first, the dealers share their
+/// shares of the key to the receivers afterwards, and everybody confirms the public part of the new key at the end
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum ReshareMessage {
+    Receiver(ReceiverKeys),
+    Dealer(DealerShares),
+    // digest of the public part of the new key
+    Confirmation(String),
+}
+
+/// The Paillier key of a new party with the same proofs as in the key generation.
+/// The dealers encrypt the shares for the party to the committed point
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ReceiverKeys {
+    broadcast: KeyGenBroadcastMessage1,
+    decommitment: KeyGenDecommitMessage1,
+}
+
+/// A polynomial of the new threshold whose constant term is the share of the dealer, weighted by its
+/// Lagrange coefficient among the dealers. The constant terms of all the dealers add up to the key
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DealerShares {
+    public_key: Point<Secp256k1>,
+    // the generator times the coefficients 0..=t of the new threshold
+    commitments: Vec<Point<Secp256k1>>,
+    // encrypted to the receivers
+    shares: Vec<EncryptedShare>,
+}
+
+impl ReceiverKeys {
+    /// The point that shares for the party are encrypted to, its secret is the u_i of the keys
+    pub fn share_key(&self) -> &Point<Secp256k1> {
+        &self.decommitment.y_i
+    }
+}
+
+/// The Paillier keys of a new party, slow to generate because of the safe primes
+pub fn receiver_keys(new_index: u16) -> (Keys, ReceiverKeys) {
+    let keys = Keys::create(usize::from(new_index));
+    let (broadcast, decommitment) = keys.phase1_broadcast_phase3_proof_of_correct_key_proof_of_correct_h1h2();
+    (keys, ReceiverKeys { broadcast, decommitment })
+}
+
+/// Verifies the Paillier keys of all the parties, given in the order of their indices, like in the key generation.
+/// Returns their broadcasts, which hold the Paillier keys and the N~ of the new key
+pub fn verify_receiver_keys(keys: &Keys, threshold: u16, parties: &[&ReceiverKeys]) -> Result<Vec<KeyGenBroadcastMessage1>> {
+    let broadcasts: Vec<KeyGenBroadcastMessage1> = parties.iter().map(|keys| keys.broadcast.clone()).collect();
+    let decommitments: Vec<KeyGenDecommitMessage1> = parties.iter().map(|keys| keys.decommitment.clone()).collect();
+    keys.phase1_verify_com_phase3_verify_correct_key_verify_dlog_phase2_distribute(
+        &Parameters { threshold, share_count: parties.len() as u16 },
+        &decommitments,
+        &broadcasts,
+    ).map_err(|e| anyhow!("Invalid Paillier key of a party: {:?}", e))?;
+    Ok(broadcasts)
+}
+
+/// Shares the share of this dealer to the receivers of the request
+pub fn deal(
+    request: &ReshareRequest,
+    server_id: u16,
+    key_share: &KeyShare,
+    receivers: &BTreeMap<u16, ReceiverKeys>
+) -> Result<DealerShares> {
+    let local_key = key_share.local_key();
+    let old_indices = old_indices(request, key_share)?;
+    if old_indices.len() <= usize::from(local_key.t) {
+        bail!("{} dealers cannot reshare a key with threshold {}", old_indices.len(), local_key.t);
+    }
+
+    let own_index = key_share.index_of(server_id).ok_or(anyhow!("This server is not a party of the key"))?;
+    let weighted_share = &lagrange_coefficient(own_index, &old_indices)? * &local_key.keys_linear.x_i;
+    let coefficients: Vec<Scalar<Secp256k1>> = std::iter::once(weighted_share)
+        .chain((0..request.threshold).map(|_| Scalar::random()))
+        .collect();
+    let commitments = coefficients.iter().map(|a| Point::generator() * a).collect();
+
+    let mut shares = Vec::new();
+    for (new_index, receiver) in (1..).zip(&request.receivers) {
+        let keys = receivers.get(receiver).ok_or(anyhow!("Missing the keys of receiver {}", receiver))?;
+        let share = evaluate(&coefficients, new_index);
+        shares.push(encrypt_share(PROTOCOL, server_id, *receiver, keys.share_key(), &share)?);
+    }
+
+    Ok(DealerShares { public_key: local_key.public_key(), commitments, shares })
+}
+
+// the indices of the dealers in the old key
+fn old_indices(request: &ReshareRequest, key_share: &KeyShare) -> Result<Vec<u16>> {
+    request.dealers.iter()
+        .map(|dealer| key_share.index_of(*dealer).ok_or(anyhow!("Dealer {} is not a party of the key", dealer)))
+        .collect()
+}
+
+/// Checks that the dealers shared the same key, against the old share if this server has one,
+/// and returns the public key with the commitments to the polynomial of the new key
+pub fn verify_dealers(
+    request: &ReshareRequest,
+    old_share: Option<&KeyShare>,
+    dealt: &BTreeMap<u16, DealerShares>
+) -> Result<(Point<Secp256k1>, Vec<Point<Secp256k1>>)> {
+    if dealt.keys().copied().collect::<Vec<u16>>() != request.dealers {
+        bail!("Expected the shares of dealers {:?}, received the shares of {:?}", request.dealers, dealt.keys().collect::<Vec<_>>());
+    }
+
+    let public_key = dealt.values().next().ok_or(anyhow!("No dealers"))?.public_key.clone();
+    let mut combined = vec![Point::zero(); usize::from(request.threshold) + 1];
+    for (dealer, shares) in dealt {
+        if shares.public_key != public_key {
+            bail!("Dealer {} shares another key", dealer);
+        }
+        if shares.commitments.len() != combined.len() {
+            bail!("Dealer {} sent {} commitments, expected {}", dealer, shares.commitments.len(), combined.len());
+        }
+        for (sum, commitment) in combined.iter_mut().zip(&shares.commitments) {
+            *sum = &*sum + commitment;
+        }
+    }
+    if combined[0] != public_key {
+        bail!("The shares of the dealers do not add up to the key");
+    }
+
+    // a party of the old key can check the share of every dealer against its public share
+    if let Some(key_share) = old_share {
+        let local_key = key_share.local_key();
+        if local_key.public_key() != public_key {
+            bail!("The dealers share another key than this server has");
+        }
+        let old_indices = old_indices(request, key_share)?;
+        for ((dealer, shares), old_index) in dealt.iter().zip(&old_indices) {
+            let weighted_public_share = &local_key.pk_vec[usize::from(*old_index) - 1] * &lagrange_coefficient(*old_index, &old_indices)?;
+            if shares.commitments[0] != weighted_public_share {
+                bail!("Dealer {} did not share its share of the key", dealer);
+            }
+        }
+    }
+
+    Ok((public_key, combined))
+}
+
+/// The share of this receiver, built from the shares of all the dealers.
+/// The Paillier keys of all the receivers are verified like in the key generation
+pub fn receive_share(
+    request: &ReshareRequest,
+    server_id: u16,
+    keys: &Keys,
+    receivers: &BTreeMap<u16, ReceiverKeys>,
+    dealt: &BTreeMap<u16, DealerShares>,
+    combined: &[Point<Secp256k1>]
+) -> Result<KeyShare> {
+    let new_index = request.new_index(server_id).ok_or(anyhow!("This server is not a receiver"))?;
+    let n = request.receivers.len() as u16;
+
+    let receiver_keys: Vec<&ReceiverKeys> = request.receivers.iter()
+        .map(|receiver| receivers.get(receiver).ok_or(anyhow!("Missing the keys of receiver {}", receiver)))
+        .collect::<Result<_>>()?;
+    let broadcasts = verify_receiver_keys(keys, request.threshold, &receiver_keys)
+        .context("Invalid Paillier key of a receiver")?;
+
+    let mut x_i = Scalar::zero();
+    for (dealer, shares) in dealt {
+        let encrypted = shares.shares.iter()
+            .find(|share| share.receiver() == server_id)
+            .ok_or(anyhow!("Dealer {} sent no share for this server", dealer))?;
+        let share = decrypt_share(PROTOCOL, *dealer, &keys.u_i, encrypted)
+            .with_context(|| format!("Share of dealer {}", dealer))?;
+        if Point::generator() * &share != evaluate_commitments(&shares.commitments, new_index) {
+            bail!("The share of dealer {} does not match its commitments", dealer);
+        }
+        x_i = &x_i + &share;
+    }
+
+    let pk_vec: Vec<Point<Secp256k1>> = (1..=n).map(|j| evaluate_commitments(combined, j)).collect();
+    if Point::generator() * &x_i != pk_vec[usize::from(new_index) - 1] {
+        bail!("The new share does not match its public share");
+    }
+
+    let local_key = LocalKey {
+        paillier_dk: keys.dk.clone(),
+        pk_vec,
+        keys_linear: SharedKeys { y: combined[0].clone(), x_i },
+        paillier_key_vec: broadcasts.iter().map(|broadcast| broadcast.e.clone()).collect(),
+        y_sum_s: combined[0].clone(),
+        h1_h2_n_tilde_vec: broadcasts.iter().map(|broadcast| broadcast.dlog_statement.clone()).collect(),
+        vss_scheme: VerifiableSS {
+            parameters: ShamirSecretSharing { threshold: request.threshold, share_count: n },
+            commitments: combined.to_vec(),
+        },
+        i: new_index,
+        t: request.threshold,
+        n,
+    };
+    Ok(KeyShare::new(local_key, request.receivers.clone()))
+}
+
+/// Digest of the public part of the new key, the dealers that retire compute it as well as the receivers
+pub fn public_digest(
+    request: &ReshareRequest,
+    combined: &[Point<Secp256k1>],
+    receivers: &BTreeMap<u16, ReceiverKeys>
+) -> Result<String> {
+    let paillier_keys: Vec<_> = receivers.values().map(|keys| (&keys.broadcast.e, &keys.broadcast.dlog_statement)).collect();
+    let public_part = serde_json::to_vec(&(&request.receivers, request.threshold, combined, paillier_keys))?;
+    Ok(sha256::digest(public_part.as_slice()))
+}
+
+/// Runs the resharing with all the other participants of the request. A receiver gets its share of the new key,
+/// a dealer that is not a receiver gets None and has to delete its share. Nothing is returned before every
+/// participant confirmed the same new key
+pub async fn reshare_key(
+    request: &ReshareRequest,
+    server_id: u16,
+    old_share: Option<&KeyShare>,
+    mut receiving_stream: Pin<&mut impl Stream<Item = Result<Msg<ReshareMessage>>>>,
+    mut outgoing_sink: Pin<&mut impl Sink<Msg<ReshareMessage>, Error = Error>>
+) -> Result<Option<KeyShare>> {
+    let mut received = Received::default();
+
+    let keys = match request.new_index(server_id) {
+        None => None,
+        Some(new_index) => {
+            let (keys, receiver_keys) = tokio::task::spawn_blocking(move || receiver_keys(new_index)).await?;
+            received.receivers.insert(server_id, receiver_keys.clone());
+            send(&mut outgoing_sink, server_id, ReshareMessage::Receiver(receiver_keys)).await?;
+            Some(keys)
+        }
+    };
+
+    while received.receivers.len() < request.receivers.len() {
+        received.receive(request, &mut receiving_stream).await?;
+    }
+
+    if request.is_dealer(server_id) {
+        let key_share = old_share.ok_or(anyhow!("This server has no share of the key to deal"))?;
+        let shares = deal(request, server_id, key_share, &received.receivers)?;
+        received.dealt.insert(server_id, shares.clone());
+        send(&mut outgoing_sink, server_id, ReshareMessage::Dealer(shares)).await?;
+    }
+
+    while received.dealt.len() < request.dealers.len() {
+        received.receive(request, &mut receiving_stream).await?;
+    }
+
+    let (_, combined) = verify_dealers(request, old_share, &received.dealt)?;
+    let new_share = match &keys {
+        None => None,
+        Some(keys) => Some(receive_share(request, server_id, keys, &received.receivers, &received.dealt, &combined)?),
+    };
+
+    let digest = public_digest(request, &combined, &received.receivers)?;
+    send(&mut outgoing_sink, server_id, ReshareMessage::Confirmation(digest.clone())).await?;
+
+    let other_participants = request.participants().len() - 1;
+    while received.confirmations.len() < other_participants {
+        received.receive(request, &mut receiving_stream).await?;
+    }
+    if let Some((party, _)) = received.confirmations.iter().find(|(_, other_digest)| **other_digest != digest) {
+        bail!("Server {} computed a different new key", party);
+    }
+
+    Ok(new_share)
+}
+
+async fn send(
+    outgoing_sink: &mut Pin<&mut impl Sink<Msg<ReshareMessage>, Error = Error>>,
+    server_id: u16,
+    body: ReshareMessage
+) -> Result<()> {
+    outgoing_sink.send(Msg { sender: server_id, receiver: None, body }).await
+}
+
+// the parties run at different speeds, so a message of a later step is kept until it is needed
+#[derive(Default)]
+struct Received {
+    receivers: BTreeMap<u16, ReceiverKeys>,
+    dealt: BTreeMap<u16, DealerShares>,
+    confirmations: BTreeMap<u16, String>,
+}
+
+impl Received {
+    async fn receive(
+        &mut self,
+        request: &ReshareRequest,
+        receiving_stream: &mut Pin<&mut impl Stream<Item = Result<Msg<ReshareMessage>>>>
+    ) -> Result<()> {
+        let message = receiving_stream.next().await
+            .ok_or(anyhow!("The resharing ended before all parties sent their messages"))??;
+        let sender = message.sender;
+
+        let duplicate = match message.body {
+            ReshareMessage::Receiver(_) if request.new_index(sender).is_none() => bail!("Server {} is not a receiver", sender),
+            ReshareMessage::Dealer(_) if !request.is_dealer(sender) => bail!("Server {} is not a dealer", sender),
+            ReshareMessage::Receiver(keys) => self.receivers.insert(sender, keys).is_some(),
+            ReshareMessage::Dealer(shares) => self.dealt.insert(sender, shares).is_some(),
+            ReshareMessage::Confirmation(digest) => self.confirmations.insert(sender, digest).is_some(),
+        };
+        if duplicate {
+            bail!("Server {} sent the same message twice", sender);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use curv::arithmetic::Converter;
+    use curv::BigInt;
+    use curv::elliptic::curves::Secp256k1;
+    use multi_party_ecdsa::protocols::multi_party_ecdsa::gg_2020::state_machine::keygen::LocalKey;
+    use multi_party_ecdsa::protocols::multi_party_ecdsa::gg_2020::state_machine::sign::{OfflineStage, SignManual};
+    use round_based::dev::Simulation;
+    use crate::mpc::operations::test_fixtures::simulate_keygen;
+    use crate::mpc::operations::check_signature::check_sig;
+    use crate::mpc::operations::key_resharing::{deal, public_digest, receive_share, receiver_keys, verify_dealers, ReshareRequest};
+    use crate::mpc::utils::local_share_utils::KeyShare;
+    use crate::mpc::utils::signature_formats::normalize_s;
+
+    fn request(dealers: Vec<u16>, receivers: Vec<u16>, threshold: u16) -> ReshareRequest {
+        ReshareRequest { key_id: None, dealers, receivers, threshold }
+    }
+
+    #[test]
+    fn invalid_requests() {
+        assert!(request(vec![1, 2], vec![2, 3, 4], 1).validate().is_ok());
+        assert!(request(vec![], vec![2, 3, 4], 1).validate().is_err());
+        assert!(request(vec![2, 1], vec![2, 3, 4], 1).validate().is_err());
+        assert!(request(vec![1, 2], vec![2, 4, 3], 1).validate().is_err());
+        assert!(request(vec![1, 2], vec![2, 3, 3], 1).validate().is_err());
+        assert!(request(vec![1, 2], vec![2, 3, 4], 3).validate().is_err());
+        assert!(request(vec![1, 2], vec![2, 3, 4], 0).validate().is_err());
+
+        let request = request(vec![1, 2], vec![2, 3, 4], 1);
+        assert_eq!(request.participants(), vec![1, 2, 3, 4]);
+        assert_eq!(request.new_index(3), Some(2));
+        assert_eq!(request.new_index(1), None);
+        assert!(request.is_dealer(1) && !request.is_dealer(3));
+    }
+
+    #[test]
+    fn reshare_to_new_parties_keeps_the_public_key() {
+        let old_shares: Vec<KeyShare> = simulate_keygen(1, 3).into_iter().map(KeyShare::generated).collect();
+        let request = request(vec![1, 2], vec![2, 3, 4, 5], 2);
+
+        let keys: BTreeMap<u16, _> = request.receivers.iter()
+            .map(|receiver| (*receiver, receiver_keys(request.new_index(*receiver).unwrap())))
+            .collect();
+        let receivers = keys.iter().map(|(receiver, (_, receiver_keys))| (*receiver, receiver_keys.clone())).collect();
+
+        let dealt: BTreeMap<u16, _> = request.dealers.iter()
+            .map(|dealer| (*dealer, deal(&request, *dealer, &old_shares[usize::from(*dealer) - 1], &receivers).unwrap()))
+            .collect();
+
+        let (public_key, combined) = verify_dealers(&request, Some(&old_shares[2]), &dealt).unwrap();
+        assert_eq!(public_key, old_shares[0].local_key().public_key());
+
+        let new_shares: Vec<LocalKey<Secp256k1>> = keys.iter()
+            .map(|(receiver, (keys, _))| receive_share(&request, *receiver, keys, &receivers, &dealt, &combined).unwrap())
+            .map(|key_share| {
+                assert_eq!(key_share.parties(), request.receivers.as_slice());
+                key_share.local_key().clone()
+            })
+            .collect();
+        for local_key in &new_shares {
+            assert_eq!(local_key.public_key(), public_key);
+            assert_eq!((local_key.t, local_key.n), (2, 4));
+        }
+        assert!(public_digest(&request, &combined, &receivers).is_ok());
+
+        // servers 3, 4 and 5 sign with the new shares of indices 2, 3 and 4
+        let mut simulation = Simulation::new();
+        simulation.enable_benchmarks(false);
+        for (i, index) in (1..).zip([2u16, 3, 4]) {
+            simulation.add_party(OfflineStage::new(i, vec![2, 3, 4], new_shares[usize::from(index) - 1].clone()).unwrap());
+        }
+        let message = BigInt::from_bytes(b"reshared log");
+        let (mut signings, partial_signatures): (Vec<SignManual>, Vec<_>) = simulation.run().unwrap().into_iter()
+            .map(|offline_stage| SignManual::new(message.clone(), offline_stage).unwrap())
+            .unzip();
+        let signature = normalize_s(&signings.remove(0).complete(&partial_signatures[1..]).unwrap());
+        assert!(check_sig(&signature.r, &signature.s, &message, &public_key));
+
+        // a dealer that shares something else than its share of the key is caught by the old parties
+        let mut forged = dealt.clone();
+        forged.insert(1, deal(&request, 1, &old_shares[2], &receivers).unwrap());
+        assert!(verify_dealers(&request, Some(&old_shares[0]), &forged).is_err());
+    }
+}
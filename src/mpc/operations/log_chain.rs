@@ -0,0 +1,512 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt, fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use curv::elliptic::curves::{Point, Secp256k1};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Notify;
+
+use crate::mpc::operations::check_signature::{signed_chain_digest, verify_signature};
+use crate::mpc::utils::signature_formats::SignatureEncoding;
+
+/// The previous digest of the first record of a stream
+pub const GENESIS_DIGEST: [u8; 32] = [0u8; 32];
+
+/// Stream ids are chosen by the clients, so only a safe subset of characters is allowed
+pub fn is_valid_stream_id(id: &str) -> bool {
+    !id.is_empty() && id.len() <= 64 && id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+}
+
+/// Position of a record in its stream. The servers fill in the sequence number and the digest
+/// of the previous record from their chain head, a client that gives them must name the same position
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ChainLink {
+    stream_id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sequence: Option<u64>,
+    // hex encoded signed digest of the previous record, zeros before the first record
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    previous: Option<String>,
+}
+
+impl ChainLink {
+    pub fn new(stream_id: &str, sequence: u64, previous: &[u8]) -> ChainLink {
+        ChainLink { stream_id: stream_id.to_string(), sequence: Some(sequence), previous: Some(hex::encode(previous)) }
+    }
+
+    /// The next record of the stream, its position is filled in from the chain head of the coordinator
+    pub fn next(stream_id: &str) -> ChainLink {
+        ChainLink { stream_id: stream_id.to_string(), sequence: None, previous: None }
+    }
+
+    pub fn stream_id(&self) -> &str {
+        &self.stream_id
+    }
+
+    /// The sequence number and the digest of the previous record, fails unless both are known
+    pub fn position(&self) -> Result<(u64, Vec<u8>)> {
+        if !is_valid_stream_id(&self.stream_id) {
+            bail!("Invalid stream id {}", self.stream_id);
+        }
+        match (self.sequence, &self.previous) {
+            (Some(sequence), Some(previous)) => match hex::decode(previous) {
+                Ok(previous) if previous.len() == 32 => Ok((sequence, previous)),
+                _ => bail!("The previous digest must be 32 hex encoded bytes"),
+            },
+            _ => bail!("The record of stream {} needs its sequence number and the digest of the previous record", self.stream_id),
+        }
+    }
+
+    /// The digest the servers signed for the record, given the digest of its data
+    pub fn signed_digest(&self, key_id: &str, entry_digest: &[u8], timestamp: &str) -> Result<Vec<u8>> {
+        let (sequence, previous) = self.position()?;
+        Ok(signed_chain_digest(key_id, &self.stream_id, sequence, &previous, entry_digest, timestamp))
+    }
+}
+
+/// The last record of a stream that this server signed, or that another server showed it signed
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChainHead {
+    sequence: u64,
+    // hex encoded signed digest
+    digest: String,
+    // lets another server check the head before it moves its own head to it, heads stored by older versions have none
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    record: Option<HeadRecord>,
+}
+
+/// What the signed digest of a chain head was computed from, and its signature
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HeadRecord {
+    // hex encoded signed digest of the record before the head
+    previous: String,
+    timestamp: String,
+    // hex encoded digest of the data of the record
+    entry_digest: String,
+    // hex encoded DER
+    signature: String,
+}
+
+impl HeadRecord {
+    pub fn new(previous: &[u8], timestamp: &str, entry_digest: &[u8], signature_der: &str) -> HeadRecord {
+        HeadRecord {
+            previous: hex::encode(previous),
+            timestamp: timestamp.to_string(),
+            entry_digest: hex::encode(entry_digest),
+            signature: signature_der.to_string(),
+        }
+    }
+}
+
+impl ChainHead {
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+
+    /// Fails unless the head carries its record and the record is signed with the key at the position of the head
+    pub fn check(&self, key_id: &str, stream_id: &str, public_key: &Point<Secp256k1>) -> Result<()> {
+        let record = self.record.as_ref().ok_or(anyhow!("The head of stream {} carries no signed record", stream_id))?;
+        let digest = signed_chain_digest(key_id, stream_id, self.sequence, &hex::decode(&record.previous)?,
+                                         &hex::decode(&record.entry_digest)?, &record.timestamp);
+        if hex::encode(&digest) != self.digest {
+            bail!("The head of stream {} is not the digest of its record", stream_id);
+        }
+        if !verify_signature(&hex::decode(&record.signature)?, Some(SignatureEncoding::Der), &digest, public_key)? {
+            bail!("The record of the head of stream {} is not signed with the key", stream_id);
+        }
+        Ok(())
+    }
+}
+
+/// The chain heads of the streams of every key. They are kept in a single file, which is rewritten
+/// whenever a head advances, so that a restarted server neither forks nor rewinds a stream
+pub struct ChainStore {
+    file: Option<PathBuf>,
+    // by key id and stream id
+    heads: BTreeMap<String, BTreeMap<String, ChainHead>>,
+    // the streams with a record that is being signed, by key id and stream id,
+    // the next record of the stream waits for the notification that it is finished
+    pending: HashMap<(String, String), Arc<Notify>>,
+}
+
+impl ChainStore {
+    /// Store that is not persisted
+    pub fn in_memory() -> ChainStore {
+        ChainStore { file: None, heads: BTreeMap::new(), pending: HashMap::new() }
+    }
+
+    /// Loads the chain heads from the file, there are none if it does not exist
+    pub fn load(file: &Path) -> Result<ChainStore> {
+        let heads = match fs::read(file) {
+            Ok(content) => serde_json::from_slice(&content).with_context(|| format!("parse chain heads {}", file.display()))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(anyhow!(e).context(format!("read chain heads {}", file.display()))),
+        };
+
+        Ok(ChainStore { file: Some(file.to_path_buf()), heads, pending: HashMap::new() })
+    }
+
+    pub fn head(&self, key_id: &str, stream_id: &str) -> Option<&ChainHead> {
+        self.heads.get(key_id)?.get(stream_id)
+    }
+
+    /// The link with the position right after the chain head. A stream without a head starts at sequence 0.
+    /// Fails if the link names another position, only the servers decide where a record goes
+    pub fn next_link(&self, key_id: &str, link: &ChainLink) -> Result<ChainLink> {
+        let (sequence, previous) = match self.head(key_id, &link.stream_id) {
+            None => (0, hex::encode(GENESIS_DIGEST)),
+            Some(head) => (head.sequence + 1, head.digest.clone()),
+        };
+
+        if link.sequence.is_some_and(|given| given != sequence) || link.previous.as_ref().is_some_and(|given| *given != previous) {
+            bail!("Stream {} continues at sequence {} after {}, the record names sequence {} after {}", link.stream_id, sequence, previous,
+                  link.sequence.map_or(String::from("none"), |given| given.to_string()), link.previous.as_deref().unwrap_or("none"));
+        }
+
+        Ok(ChainLink {
+            stream_id: link.stream_id.clone(),
+            sequence: Some(sequence),
+            previous: Some(previous),
+        })
+    }
+
+    /// Moves the head of the stream forward to the head of another server once its signed record is checked.
+    /// Returns false if the head is not ahead, or a record of the stream is being signed here
+    pub fn fast_forward(&mut self, key_id: &str, stream_id: &str, head: &ChainHead, public_key: &Point<Secp256k1>) -> Result<bool> {
+        if self.is_pending(key_id, stream_id) || self.head(key_id, stream_id).is_some_and(|own| own.sequence >= head.sequence) {
+            return Ok(false);
+        }
+        head.check(key_id, stream_id, public_key)?;
+
+        self.heads.entry(key_id.to_string()).or_default().insert(stream_id.to_string(), head.clone());
+        self.save()?;
+        Ok(true)
+    }
+
+    /// Reserves the stream for the record until it is committed or released.
+    /// Fails unless the record follows the head right away, or if another record of the stream is being signed
+    pub fn reserve(&mut self, key_id: &str, link: &ChainLink) -> Result<()> {
+        let pending = (key_id.to_string(), link.stream_id.clone());
+        if self.pending.contains_key(&pending) {
+            bail!("A record of stream {} is being signed", link.stream_id);
+        }
+        link.position()?;
+        self.next_link(key_id, link)?;
+
+        self.pending.insert(pending, Arc::new(Notify::new()));
+        Ok(())
+    }
+
+    /// Advances the chain head to the signed record
+    pub fn commit(&mut self, key_id: &str, link: &ChainLink, digest: &[u8], record: HeadRecord) -> Result<()> {
+        self.release(key_id, &link.stream_id);
+        let (sequence, _) = link.position()?;
+
+        let heads = self.heads.entry(key_id.to_string()).or_default();
+        if heads.get(&link.stream_id).is_some_and(|head| head.sequence >= sequence) {
+            return Ok(());
+        }
+        heads.insert(link.stream_id.clone(), ChainHead { sequence, digest: hex::encode(digest), record: Some(record) });
+        self.save()
+    }
+
+    /// True while a record of the stream is being signed
+    pub fn is_pending(&self, key_id: &str, stream_id: &str) -> bool {
+        self.pending.contains_key(&(key_id.to_string(), stream_id.to_string()))
+    }
+
+    /// Notified once the record of the stream that is being signed is finished, None if there is none
+    pub fn finished(&self, key_id: &str, stream_id: &str) -> Option<Arc<Notify>> {
+        self.pending.get(&(key_id.to_string(), stream_id.to_string())).cloned()
+    }
+
+    /// Frees the stream after a record could not be signed, the head stays where it was
+    pub fn release(&mut self, key_id: &str, stream_id: &str) {
+        if let Some(finished) = self.pending.remove(&(key_id.to_string(), stream_id.to_string())) {
+            finished.notify_waiters();
+        }
+    }
+
+    // written to a temporary file first, so that a crash does not leave a partial file
+    fn save(&self) -> Result<()> {
+        let file = match &self.file {
+            None => return Ok(()),
+            Some(file) => file,
+        };
+
+        let temporary = file.with_extension("json.tmp");
+        fs::write(&temporary, serde_json::to_vec_pretty(&self.heads)?)
+            .with_context(|| format!("write chain heads {}", temporary.display()))?;
+        fs::rename(&temporary, file).with_context(|| format!("write chain heads {}", file.display()))
+    }
+}
+
+/// A signed record of a log as the verifier sees it
+#[derive(Debug, Clone)]
+pub struct ChainRecord {
+    // line of the record in the log, counted from 1
+    line: usize,
+    stream_id: String,
+    sequence: u64,
+    previous: Vec<u8>,
+    digest: Vec<u8>,
+}
+
+impl ChainRecord {
+    pub fn new(line: usize, link: &ChainLink, digest: Vec<u8>) -> Result<ChainRecord> {
+        let (sequence, previous) = link.position()?;
+        Ok(ChainRecord { line, stream_id: link.stream_id.clone(), sequence, previous, digest })
+    }
+}
+
+/// What the verifier finds wrong with a log
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChainIssue {
+    // the record cannot be parsed, its signature is invalid or it is not chained
+    InvalidRecord { line: usize, reason: String },
+    // records between two records of the stream are missing
+    Gap { stream_id: String, after: u64, next: u64, line: usize },
+    // two different records with the same sequence number
+    Fork { stream_id: String, sequence: u64, line: usize, first_line: usize },
+    // the same record twice
+    Duplicate { stream_id: String, sequence: u64, line: usize, first_line: usize },
+    // a record after a record with a higher sequence number
+    Reordered { stream_id: String, sequence: u64, line: usize, after: u64 },
+    // the record does not commit to the record before it, which was replaced
+    BrokenLink { stream_id: String, sequence: u64, line: usize },
+}
+
+impl ChainIssue {
+    pub fn line(&self) -> usize {
+        match self {
+            ChainIssue::InvalidRecord { line, .. } | ChainIssue::Gap { line, .. } | ChainIssue::Fork { line, .. }
+            | ChainIssue::Duplicate { line, .. } | ChainIssue::Reordered { line, .. } | ChainIssue::BrokenLink { line, .. } => *line,
+        }
+    }
+}
+
+impl fmt::Display for ChainIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChainIssue::InvalidRecord { line, reason } => write!(f, "line {}: {}", line, reason),
+            ChainIssue::Gap { stream_id, after, next, line } if after + 2 == *next =>
+                write!(f, "line {}: stream {} is missing record {}", line, stream_id, after + 1),
+            ChainIssue::Gap { stream_id, after, next, line } =>
+                write!(f, "line {}: stream {} is missing the records {} to {}", line, stream_id, after + 1, next - 1),
+            ChainIssue::Fork { stream_id, sequence, line, first_line } =>
+                write!(f, "line {}: stream {} forks at sequence {}, line {} has another record", line, stream_id, sequence, first_line),
+            ChainIssue::Duplicate { stream_id, sequence, line, first_line } =>
+                write!(f, "line {}: record {} of stream {} repeats line {}", line, sequence, stream_id, first_line),
+            ChainIssue::Reordered { stream_id, sequence, line, after } =>
+                write!(f, "line {}: record {} of stream {} comes after record {}", line, sequence, stream_id, after),
+            ChainIssue::BrokenLink { stream_id, sequence, line } =>
+                write!(f, "line {}: record {} of stream {} does not follow the record before it", line, sequence, stream_id),
+        }
+    }
+}
+
+/// Checks that the records of every stream form an unbroken chain in the order of the log.
+/// Only the records between the first and the last record of a stream are checked, records cut off
+/// at either end are found by comparing with the previous log and the chain head of the servers
+pub fn check_chain(records: &[ChainRecord]) -> Vec<ChainIssue> {
+    let mut streams: BTreeMap<&str, Vec<&ChainRecord>> = BTreeMap::new();
+    for record in records {
+        streams.entry(&record.stream_id).or_default().push(record);
+    }
+
+    let mut issues = Vec::new();
+    for (stream_id, records) in streams {
+        let stream_id = stream_id.to_string();
+        let mut by_sequence: BTreeMap<u64, &ChainRecord> = BTreeMap::new();
+        let mut highest: Option<u64> = None;
+        for record in records {
+            let (sequence, line) = (record.sequence, record.line);
+            if let Some(first) = by_sequence.get(&sequence) {
+                issues.push(if first.digest == record.digest {
+                    ChainIssue::Duplicate { stream_id: stream_id.clone(), sequence, line, first_line: first.line }
+                } else {
+                    ChainIssue::Fork { stream_id: stream_id.clone(), sequence, line, first_line: first.line }
+                });
+                continue;
+            }
+            if let Some(after) = highest.filter(|highest| sequence < *highest) {
+                issues.push(ChainIssue::Reordered { stream_id: stream_id.clone(), sequence, line, after });
+            }
+            highest = highest.max(Some(sequence));
+            by_sequence.insert(sequence, record);
+        }
+
+        let mut previous: Option<&ChainRecord> = None;
+        for record in by_sequence.values() {
+            let (sequence, line) = (record.sequence, record.line);
+            match previous {
+                None if sequence == 0 && record.previous != GENESIS_DIGEST =>
+                    issues.push(ChainIssue::BrokenLink { stream_id: stream_id.clone(), sequence, line }),
+                Some(before) if sequence > before.sequence + 1 =>
+                    issues.push(ChainIssue::Gap { stream_id: stream_id.clone(), after: before.sequence, next: sequence, line }),
+                Some(before) if record.previous != before.digest =>
+                    issues.push(ChainIssue::BrokenLink { stream_id: stream_id.clone(), sequence, line }),
+                _ => {}
+            }
+            previous = Some(record);
+        }
+    }
+
+    issues.sort_by_key(|issue| issue.line());
+    issues
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use curv::elliptic::curves::{Point, Scalar, Secp256k1};
+    use secp256k1::{Message, PublicKey, SecretKey, SECP256K1};
+    use crate::mpc::operations::check_signature::signed_chain_digest;
+    use crate::mpc::operations::log_chain::{check_chain, ChainHead, ChainIssue, ChainLink, ChainRecord, ChainStore, HeadRecord, GENESIS_DIGEST};
+
+    fn link(sequence: u64, previous: &[u8]) -> ChainLink {
+        ChainLink::new("nginx", sequence, previous)
+    }
+
+    fn next(store: &ChainStore, key_id: &str) -> anyhow::Result<ChainLink> {
+        store.next_link(key_id, &ChainLink::next("nginx"))
+    }
+
+    // the record of the link signed with the secret key
+    fn signed_record(secret_key: &SecretKey, link: &ChainLink, entry_digest: &[u8]) -> (Vec<u8>, HeadRecord) {
+        let (sequence, previous) = link.position().unwrap();
+        let digest = signed_chain_digest("default", "nginx", sequence, &previous, entry_digest, "1681402350");
+        let signature = SECP256K1.sign(&Message::from_slice(&digest).unwrap(), secret_key).serialize_der();
+        (digest, HeadRecord::new(&previous, "1681402350", entry_digest, &hex::encode(&*signature)))
+    }
+
+    #[test]
+    fn heads_advance_without_forks() {
+        let mut store = ChainStore::in_memory();
+        let first = next(&store, "default").unwrap();
+        assert_eq!(first, link(0, &GENESIS_DIGEST));
+
+        store.reserve("default", &first).unwrap();
+        // one record of a stream at a time
+        assert!(store.is_pending("default", "nginx"));
+        assert!(store.reserve("default", &first).is_err());
+        store.release("default", "nginx");
+        store.reserve("default", &first).unwrap();
+        store.commit("default", &first, &[1; 32], HeadRecord::new(&GENESIS_DIGEST, "1", &[1; 32], "")).unwrap();
+
+        let second = next(&store, "default").unwrap();
+        assert_eq!(second, link(1, &[1; 32]));
+        assert_eq!(store.next_link("default", &second).unwrap(), second);
+        // a fork, a record that does not follow the head, a record ahead of the head, a first record with a previous one
+        // and the head signed again
+        assert!(store.reserve("default", &link(0, &GENESIS_DIGEST)).is_err());
+        assert!(store.reserve("default", &link(1, &[9; 32])).is_err());
+        assert!(store.reserve("default", &link(5, &[4; 32])).is_err());
+        assert!(store.next_link("default", &link(5, &[4; 32])).is_err());
+        assert!(store.reserve("other", &link(0, &[1; 32])).is_err());
+        assert!(store.reserve("default", &first).is_err());
+        assert!(store.reserve("default", &ChainLink { stream_id: String::from("a/b"), sequence: Some(0), previous: Some(hex::encode(GENESIS_DIGEST)) }).is_err());
+
+        store.reserve("default", &second).unwrap();
+        store.commit("default", &second, &[2; 32], HeadRecord::new(&[1; 32], "1", &[2; 32], "")).unwrap();
+        assert_eq!(next(&store, "default").unwrap(), link(2, &[2; 32]));
+    }
+
+    #[tokio::test]
+    async fn waiters_are_notified_when_a_record_is_finished() {
+        let mut store = ChainStore::in_memory();
+        let first = next(&store, "default").unwrap();
+        assert!(store.finished("default", "nginx").is_none());
+
+        store.reserve("default", &first).unwrap();
+        let finished = store.finished("default", "nginx").unwrap();
+        let notified = finished.notified();
+        store.commit("default", &first, &[1; 32], HeadRecord::new(&GENESIS_DIGEST, "1", &[1; 32], "")).unwrap();
+        tokio::time::timeout(std::time::Duration::from_secs(1), notified).await.unwrap();
+        assert!(store.finished("default", "nginx").is_none());
+    }
+
+    #[test]
+    fn heads_move_forward_to_signed_heads() {
+        let secret_key = SecretKey::from_slice(&[7u8; 32]).unwrap();
+        let public_key: Point<Secp256k1> = Point::from_bytes(&PublicKey::from_secret_key(SECP256K1, &secret_key).serialize()).unwrap();
+
+        // another set of participants signed the first two records
+        let mut ahead = ChainStore::in_memory();
+        for entry_digest in [[1u8; 32], [2u8; 32]] {
+            let link = next(&ahead, "default").unwrap();
+            let (digest, record) = signed_record(&secret_key, &link, &entry_digest);
+            ahead.reserve("default", &link).unwrap();
+            ahead.commit("default", &link, &digest, record).unwrap();
+        }
+        let head = ahead.head("default", "nginx").unwrap().clone();
+        head.check("default", "nginx", &public_key).unwrap();
+
+        let mut behind = ChainStore::in_memory();
+        // a head that is not signed with the key, or whose record was changed, is refused
+        let other_key = Point::generator() * Scalar::<Secp256k1>::random();
+        assert!(behind.fast_forward("default", "nginx", &head, &other_key).is_err());
+        let mut forged = head.clone();
+        forged.sequence = 7;
+        assert!(behind.fast_forward("default", "nginx", &forged, &public_key).is_err());
+        assert!(behind.fast_forward("default", "nginx", &ChainHead { record: None, ..head.clone() }, &public_key).is_err());
+
+        assert!(behind.fast_forward("default", "nginx", &head, &public_key).unwrap());
+        assert_eq!(next(&behind, "default").unwrap(), next(&ahead, "default").unwrap());
+        assert!(!behind.fast_forward("default", "nginx", &head, &public_key).unwrap());
+    }
+
+    #[test]
+    fn heads_survive_restarts() {
+        let file = std::env::temp_dir().join(format!("chain-heads-{}.json", std::process::id()));
+        let _ = fs::remove_file(&file);
+
+        let mut store = ChainStore::load(&file).unwrap();
+        store.reserve("default", &link(0, &GENESIS_DIGEST)).unwrap();
+        store.commit("default", &link(0, &GENESIS_DIGEST), &[1; 32], HeadRecord::new(&GENESIS_DIGEST, "1", &[1; 32], "30")).unwrap();
+
+        let store = ChainStore::load(&file).unwrap();
+        let record = Some(HeadRecord::new(&GENESIS_DIGEST, "1", &[1; 32], "30"));
+        assert_eq!(store.head("default", "nginx"), Some(&ChainHead { sequence: 0, digest: hex::encode([1; 32]), record }));
+        assert_eq!(next(&store, "default").unwrap(), link(1, &[1; 32]));
+        fs::remove_file(&file).unwrap();
+
+        // heads of older versions have no record
+        let old: ChainHead = serde_json::from_str(r#"{"sequence": 3, "digest": "0101"}"#).unwrap();
+        assert_eq!(old.record, None);
+    }
+
+    #[test]
+    fn gaps_forks_and_reordering_are_found() {
+        let record = |line, sequence, previous: u8, digest: u8| {
+            let previous = if previous == 0 { GENESIS_DIGEST } else { [previous; 32] };
+            ChainRecord::new(line, &link(sequence, &previous), vec![digest; 32]).unwrap()
+        };
+        let chain = [record(1, 0, 0, 1), record(2, 1, 1, 2), record(3, 2, 2, 3), record(4, 3, 3, 4)];
+        assert!(check_chain(&chain).is_empty());
+        // a log that continues an earlier one
+        assert!(check_chain(&chain[2..]).is_empty());
+
+        let dropped = [chain[0].clone(), chain[1].clone(), chain[3].clone()];
+        assert_eq!(check_chain(&dropped), vec![ChainIssue::Gap { stream_id: String::from("nginx"), after: 1, next: 3, line: 4 }]);
+        assert_eq!(check_chain(&dropped)[0].to_string(), "line 4: stream nginx is missing record 2");
+
+        let reordered = [chain[0].clone(), chain[2].clone(), chain[1].clone(), chain[3].clone()];
+        assert_eq!(check_chain(&reordered), vec![ChainIssue::Reordered { stream_id: String::from("nginx"), sequence: 1, line: 2, after: 2 }]);
+
+        let forked = [chain[0].clone(), chain[1].clone(), record(5, 1, 1, 7), chain[2].clone()];
+        assert_eq!(check_chain(&forked), vec![ChainIssue::Fork { stream_id: String::from("nginx"), sequence: 1, line: 5, first_line: 2 }]);
+
+        let replaced = [chain[0].clone(), record(2, 1, 1, 7), chain[2].clone()];
+        assert_eq!(check_chain(&replaced), vec![ChainIssue::BrokenLink { stream_id: String::from("nginx"), sequence: 2, line: 3 }]);
+
+        let repeated = [chain[0].clone(), chain[1].clone(), record(3, 1, 1, 2)];
+        assert_eq!(check_chain(&repeated)[0].to_string(), "line 3: record 1 of stream nginx repeats line 2");
+        assert_eq!(check_chain(&[record(1, 0, 9, 1)]).len(), 1);
+    }
+}
@@ -0,0 +1,320 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::{anyhow, Context, Result};
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::mpc::utils::sealing::{is_sealed, Sealer};
+
+const PARTICIPANTS_FILE_NAME: &str = "participants.json";
+
+/// Single-use presignatures (completed offline stages) for each set of participants.
+/// Every presignature is stored in its own file, so the pool survives restarts
+/// and a presignature that was taken out of the pool cannot be used again.
+/// The presignature files are encrypted like the key share.
+pub struct PresignaturePool<P> {
+    directory: Option<PathBuf>,
+    sealer: Option<Arc<Sealer>>,
+    presignatures: HashMap<String, VecDeque<(String, P)>>,
+    // all participants (including this server) of the sets that the pool is kept for
+    participants: HashMap<String, Vec<u16>>,
+}
+
+/// Presignature ids are chosen by other servers and used as file names, so only a safe subset of characters is allowed
+pub fn is_valid_presignature_id(id: &str) -> bool {
+    !id.is_empty() && id.len() <= 64 && id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+}
+
+impl<P: Serialize + DeserializeOwned> PresignaturePool<P> {
+    /// Pool that is not persisted
+    pub fn in_memory() -> Self {
+        PresignaturePool {
+            directory: None,
+            sealer: None,
+            presignatures: HashMap::new(),
+            participants: HashMap::new(),
+        }
+    }
+
+    /// Loads the pool from the directory, the directory is created if it does not exist.
+    /// Presignatures written before the encryption was introduced are encrypted in place
+    pub fn load(directory: &Path, sealer: Arc<Sealer>) -> Result<Self> {
+        fs::create_dir_all(directory)
+            .with_context(|| format!("create presignature directory {}", directory.display()))?;
+
+        let mut pool = PresignaturePool {
+            directory: Some(directory.to_path_buf()),
+            sealer: Some(sealer),
+            presignatures: HashMap::new(),
+            participants: HashMap::new(),
+        };
+
+        for set_dir in fs::read_dir(directory)? {
+            let set_dir = set_dir?.path();
+            if !set_dir.is_dir() {
+                continue;
+            }
+            let key = match set_dir.file_name().and_then(|n| n.to_str()) {
+                Some(k) => k.to_string(),
+                None => continue,
+            };
+
+            let mut files: Vec<PathBuf> = fs::read_dir(&set_dir)?
+                .filter_map(|entry| entry.ok().map(|e| e.path()))
+                .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+                .collect();
+            // ids start with the time of their creation, so the oldest presignatures are used first
+            files.sort();
+
+            let mut presignatures = VecDeque::new();
+            for file in files {
+                let content = fs::read(&file)
+                    .with_context(|| format!("read presignature {}", file.display()))?;
+
+                if file.file_name().is_some_and(|n| n == PARTICIPANTS_FILE_NAME) {
+                    // sets stored before the peer registry listed urls, they are registered again when used
+                    match serde_json::from_slice(&content) {
+                        Ok(participants) => { pool.participants.insert(key.clone(), participants); }
+                        Err(e) => println!("Ignoring participants {}: {}", file.display(), e),
+                    }
+                    continue;
+                }
+
+                let id = match file.file_stem().and_then(|s| s.to_str()) {
+                    Some(id) => id.to_string(),
+                    None => continue,
+                };
+                let presignature = pool.open(&file, &content)
+                    .with_context(|| format!("parse presignature {}", file.display()))?;
+                presignatures.push_back((id, presignature));
+            }
+
+            println!("Loaded {} presignatures for participants {}", presignatures.len(), key);
+            pool.presignatures.insert(key, presignatures);
+        }
+
+        Ok(pool)
+    }
+
+    pub fn insert(&mut self, participants: &str, id: &str, presignature: P) -> Result<()> {
+        if !is_valid_presignature_id(id) {
+            return Err(anyhow!("Invalid presignature id {}", id));
+        }
+
+        if let Some(set_dir) = self.set_directory(participants)? {
+            let file = set_dir.join(format!("{}.json", id));
+            fs::write(&file, self.seal(&presignature)?)
+                .with_context(|| format!("write presignature {}", file.display()))?;
+        }
+
+        self.presignatures.entry(participants.to_string()).or_default().push_back((id.to_string(), presignature));
+        Ok(())
+    }
+
+    /// Takes the presignature with the given id out of the pool
+    pub fn take(&mut self, participants: &str, id: &str) -> Option<P> {
+        let presignatures = self.presignatures.get_mut(participants)?;
+        let position = presignatures.iter().position(|(presignature_id, _)| presignature_id == id)?;
+        let (id, presignature) = presignatures.remove(position)?;
+
+        self.remove_file(participants, &id).then_some(presignature)
+    }
+
+    /// Takes the oldest presignature out of the pool
+    pub fn take_any(&mut self, participants: &str) -> Option<(String, P)> {
+        loop {
+            let (id, presignature) = self.presignatures.get_mut(participants)?.pop_front()?;
+            if self.remove_file(participants, &id) {
+                return Some((id, presignature));
+            }
+        }
+    }
+
+    /// Names of all the pools, including the ones that are not refilled
+    pub fn pool_names(&self) -> Vec<String> {
+        self.presignatures.keys().cloned().collect()
+    }
+
+    /// Removes all the presignatures of the pool
+    pub fn clear(&mut self, participants: &str) -> Result<()> {
+        let presignatures = self.presignatures.remove(participants).unwrap_or_default();
+        for (id, _) in presignatures {
+            if !self.remove_file(participants, &id) {
+                return Err(anyhow!("Unable to remove presignature {} of {}", id, participants));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn len(&self, participants: &str) -> usize {
+        self.presignatures.get(participants).map_or(0, |p| p.len())
+    }
+
+    /// Remembers all participants of the set, so that the pool can be refilled later
+    pub fn register_participants(&mut self, participants: &str, all_participants: &[u16]) -> Result<()> {
+        if self.participants.get(participants).is_some_and(|p| p.as_slice() == all_participants) {
+            return Ok(());
+        }
+
+        if let Some(set_dir) = self.set_directory(participants)? {
+            let file = set_dir.join(PARTICIPANTS_FILE_NAME);
+            fs::write(&file, serde_json::to_vec_pretty(all_participants)?)
+                .with_context(|| format!("write participants {}", file.display()))?;
+        }
+
+        self.participants.insert(participants.to_string(), all_participants.to_vec());
+        Ok(())
+    }
+
+    /// The registered sets with the name of their pool
+    pub fn participant_sets(&self) -> Vec<(String, Vec<u16>)> {
+        self.participants.iter()
+            .map(|(key, participants)| (key.clone(), participants.clone()))
+            .collect()
+    }
+
+    fn seal(&self, presignature: &P) -> Result<Vec<u8>> {
+        let content = serde_json::to_vec(presignature)?;
+        match &self.sealer {
+            Some(sealer) => sealer.seal(&content),
+            None => Ok(content),
+        }
+    }
+
+    fn open(&self, file: &Path, content: &[u8]) -> Result<P> {
+        if is_sealed(content) {
+            let sealer = self.sealer.as_ref().ok_or(anyhow!("No key to decrypt the presignature"))?;
+            return Ok(serde_json::from_slice(&sealer.unseal(content)?)?);
+        }
+
+        let presignature = serde_json::from_slice(content)?;
+        fs::write(file, self.seal(&presignature)?)?;
+        println!("Encrypted the unencrypted presignature {}", file.display());
+        Ok(presignature)
+    }
+
+    fn set_directory(&self, participants: &str) -> Result<Option<PathBuf>> {
+        match &self.directory {
+            None => Ok(None),
+            Some(directory) => {
+                let set_dir = directory.join(participants);
+                fs::create_dir_all(&set_dir)
+                    .with_context(|| format!("create presignature directory {}", set_dir.display()))?;
+                Ok(Some(set_dir))
+            }
+        }
+    }
+
+    // a presignature whose file cannot be removed must not be used, it would be loaded again after a restart
+    fn remove_file(&self, participants: &str, id: &str) -> bool {
+        let directory = match &self.directory {
+            None => return true,
+            Some(d) => d,
+        };
+
+        let file = directory.join(participants).join(format!("{}.json", id));
+        match fs::remove_file(&file) {
+            Ok(_) => true,
+            Err(e) => {
+                eprintln!("Unable to remove presignature {}: {}", file.display(), e);
+                false
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::PathBuf, sync::Arc};
+    use crate::mpc::operations::presignature_pool::{is_valid_presignature_id, PresignaturePool};
+    use crate::mpc::utils::sealing::Sealer;
+
+    fn sealer() -> Arc<Sealer> {
+        Arc::new(Sealer::with_params(b"passphrase".to_vec(), 64, 1, 1).unwrap())
+    }
+
+    fn test_directory(name: &str) -> PathBuf {
+        let directory = std::env::temp_dir().join(format!("presignature-pool-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        directory
+    }
+
+    #[test]
+    fn presignature_is_taken_only_once() {
+        let mut pool: PresignaturePool<String> = PresignaturePool::in_memory();
+        pool.insert("2-3", "1-a", String::from("first")).unwrap();
+
+        assert_eq!(pool.take("2-3", "1-a"), Some(String::from("first")));
+        assert_eq!(pool.take("2-3", "1-a"), None);
+        assert_eq!(pool.len("2-3"), 0);
+    }
+
+    #[test]
+    fn oldest_presignature_is_taken_first() {
+        let mut pool: PresignaturePool<String> = PresignaturePool::in_memory();
+        pool.insert("2-3", "1-a", String::from("first")).unwrap();
+        pool.insert("2-3", "2-a", String::from("second")).unwrap();
+        pool.insert("3-4", "3-a", String::from("other set")).unwrap();
+
+        assert_eq!(pool.take_any("2-3"), Some((String::from("1-a"), String::from("first"))));
+        assert_eq!(pool.take_any("2-3"), Some((String::from("2-a"), String::from("second"))));
+        assert_eq!(pool.take_any("2-3"), None);
+        assert_eq!(pool.len("3-4"), 1);
+    }
+
+    #[test]
+    fn invalid_ids_are_rejected() {
+        let mut pool: PresignaturePool<String> = PresignaturePool::in_memory();
+        assert!(pool.insert("2-3", "../local-share1", String::from("first")).is_err());
+        assert!(!is_valid_presignature_id(""));
+        assert!(is_valid_presignature_id("00001681402350000000000-1"));
+    }
+
+    #[test]
+    fn pool_survives_restart() {
+        let directory = test_directory("restart");
+        let participants = vec![1, 2];
+
+        {
+            let mut pool: PresignaturePool<String> = PresignaturePool::load(&directory, sealer()).unwrap();
+            pool.register_participants("2", &participants).unwrap();
+            pool.insert("2", "1-a", String::from("first")).unwrap();
+            pool.insert("2", "2-a", String::from("second")).unwrap();
+            assert_eq!(pool.take("2", "1-a"), Some(String::from("first")));
+        }
+
+        let mut pool: PresignaturePool<String> = PresignaturePool::load(&directory, sealer()).unwrap();
+        assert_eq!(pool.participant_sets(), vec![(String::from("2"), participants)]);
+        assert_eq!(pool.len("2"), 1);
+        assert_eq!(pool.take_any("2"), Some((String::from("2-a"), String::from("second"))));
+
+        pool.insert("2", "3-a", String::from("third")).unwrap();
+        pool.clear("2").unwrap();
+        assert_eq!(pool.len("2"), 0);
+        assert_eq!(PresignaturePool::<String>::load(&directory, sealer()).unwrap().len("2"), 0);
+
+        let _ = fs::remove_dir_all(&directory);
+    }
+
+    #[test]
+    fn presignatures_are_encrypted() {
+        let directory = test_directory("encrypted");
+        fs::create_dir_all(directory.join("2")).unwrap();
+        fs::write(directory.join("2").join("1-a.json"), "\"unencrypted\"").unwrap();
+
+        let mut pool: PresignaturePool<String> = PresignaturePool::load(&directory, sealer()).unwrap();
+        pool.insert("2", "2-a", String::from("secret")).unwrap();
+
+        for id in ["1-a", "2-a"] {
+            let content = fs::read_to_string(directory.join("2").join(format!("{}.json", id))).unwrap();
+            assert!(!content.contains("unencrypted") && !content.contains("secret"));
+        }
+        assert_eq!(pool.take_any("2"), Some((String::from("1-a"), String::from("unencrypted"))));
+
+        let _ = fs::remove_dir_all(&directory);
+    }
+}
@@ -1,66 +1,295 @@
-use std::path::Path;
+use std::collections::BTreeMap;
 use std::pin::Pin;
-use std::{thread, time};
-use std::collections::HashMap;
-use std::time::Duration;
+use std::sync::Arc;
 use anyhow::{anyhow, Context, Error, Result};
 use curv::arithmetic::Converter;
 use curv::BigInt;
-use curv::elliptic::curves::Secp256k1;
+use curv::elliptic::curves::{Point, Secp256k1};
 use futures::{Sink, SinkExt, Stream, StreamExt, TryStreamExt};
 use futures::stream::Fuse;
 use multi_party_ecdsa::protocols::multi_party_ecdsa::gg_2020::state_machine::keygen::LocalKey;
 use multi_party_ecdsa::protocols::multi_party_ecdsa::gg_2020::state_machine::sign::{CompletedOfflineStage, OfflineProtocolMessage, OfflineStage, PartialSignature, SignManual};
 use round_based::{AsyncProtocol, Msg};
-use crate::mpc::utils::local_share_utils::{read_file, file_to_local_key};
+use serde::Serialize;
+use crate::mpc::key_store::{is_valid_key_id, InMemoryKeyStore, KeyStore};
+use crate::mpc::operations::log_chain::ChainStore;
+use crate::mpc::operations::presignature_pool::PresignaturePool;
+use crate::mpc::utils::local_share_utils::{read_local_share, write_local_share, KeyShare};
+use crate::mpc::utils::sealing::Sealer;
+use crate::mpc::utils::signature_formats::SignatureFormats;
+
+/// The key that is used when a request does not name one, stored as `local-share<server id>` like before keys had ids
+pub const DEFAULT_KEY_ID: &str = "default";
+
+/// A key share of this server as it is listed to the clients
+#[derive(Debug, Clone, Serialize)]
+pub struct KeyInfo {
+    key_id: String,
+    // hex encoded, compressed
+    public_key: String,
+    threshold: u16,
+    number_of_parties: u16,
+    // server ids of the parties, ordered by their index in the key
+    parties: Vec<u16>,
+}
 
-/// The structure that holds current state for the offline stage with other parties
+/// The structure that holds the key shares and the presignatures for the offline stage with other parties.
+/// A completed offline stage must never be used for more than one signature, reusing its nonce leaks the key.
+/// The threshold and the parties of every key are the ones of its share, a reshared key may differ from the configuration
 pub struct Signer {
     my_index: u16,
-    offline_stage: HashMap<String, CompletedOfflineStage>,
-    threshold: u16,
-    n_of_participants: u16,
+    key_store: Arc<dyn KeyStore>,
+    // decrypted at startup, by key id
+    local_shares: BTreeMap<String, KeyShare>,
+    // encrypts the key shares and the presignatures on disk
+    sealer: Option<Arc<Sealer>>,
+    presignatures: PresignaturePool<CompletedOfflineStage>,
+    // heads of the chained streams of the keys
+    chains: ChainStore,
 }
 
 impl Signer {
-    pub fn new(mi: u16, t: u16, n: u16) -> Signer {
+    pub fn new(mi: u16) -> Signer {
         Signer {
             my_index: mi,
-            offline_stage: HashMap::new(),
-            threshold: t,
-            n_of_participants: n,
+            key_store: Arc::new(InMemoryKeyStore::new()),
+            local_shares: BTreeMap::new(),
+            sealer: None,
+            presignatures: PresignaturePool::in_memory(),
+            chains: ChainStore::in_memory(),
+        }
+    }
+
+    pub fn with_key_store(mut self, key_store: Arc<dyn KeyStore>) -> Signer {
+        self.key_store = key_store;
+        self
+    }
+
+    pub fn with_sealer(mut self, sealer: Arc<Sealer>) -> Signer {
+        self.sealer = Some(sealer);
+        self
+    }
+
+    // several servers may share the key store, so the id in the store contains the server id
+    fn store_id(&self, key_id: &str) -> Result<String> {
+        let store_id = if key_id == DEFAULT_KEY_ID {
+            format!("local-share{}", self.my_index)
+        } else {
+            format!("local-share{}-{}", self.my_index, key_id)
+        };
+
+        if !is_valid_key_id(key_id) || !is_valid_key_id(&store_id) {
+            return Err(anyhow!("Invalid key id {}", key_id));
+        }
+        Ok(store_id)
+    }
+
+    // the key id of a key share in the store, None if it belongs to another server or is not a key share
+    fn key_id_of(&self, store_id: &str) -> Option<String> {
+        let prefix = format!("local-share{}", self.my_index);
+        match store_id.strip_prefix(&prefix)? {
+            "" => Some(DEFAULT_KEY_ID.to_string()),
+            suffix => suffix.strip_prefix('-')
+                .filter(|key_id| *key_id != DEFAULT_KEY_ID)
+                .map(|key_id| key_id.to_string()),
         }
     }
 
+    /// Decrypts the key shares of this server that were generated already
+    pub fn load_local_shares(&mut self) -> Result<()> {
+        for store_id in self.key_store.list()? {
+            let key_id = match self.key_id_of(&store_id) {
+                None => continue,
+                Some(key_id) => key_id,
+            };
+            if let Some(local_share) = read_local_share(self.key_store.as_ref(), &store_id, self.sealer()?)? {
+                println!("Loaded key {}", key_id);
+                self.local_shares.insert(key_id, local_share);
+            }
+        }
+        Ok(())
+    }
+
+    /// Fails if the key share was generated already, a new key generation would replace it
+    pub fn check_no_local_share(&self, key_id: &str) -> Result<()> {
+        let store_id = self.store_id(key_id)?;
+        if self.local_shares.contains_key(key_id) || self.key_store.exists(&store_id)? {
+            return Err(anyhow!("Key {} already exists. If you want to generate keys, remove already existing ones.", key_id));
+        }
+        Ok(())
+    }
+
+    /// Encrypts the newly generated key share to the key store and uses it from now on
+    pub fn store_local_share(&mut self, key_id: &str, local_share: LocalKey<Secp256k1>) -> Result<()> {
+        let store_id = self.store_id(key_id)?;
+        let local_share = KeyShare::generated(local_share);
+        write_local_share(self.key_store.as_ref(), &store_id, &local_share, self.sealer()?)?;
+        println!("Generated key {} stored as {}", key_id, store_id);
+        self.local_shares.insert(key_id.to_string(), local_share);
+        Ok(())
+    }
+
+    /// Keeps the refreshed share next to the share it was refreshed from until every party confirmed the refresh.
+    /// The share in use may be the pending share of an interrupted refresh, which is rolled forward
+    pub fn stage_refreshed_share(&mut self, key_id: &str, active: LocalKey<Secp256k1>, pending: LocalKey<Secp256k1>) -> Result<()> {
+        let key_share = self.local_shares.get(key_id).ok_or(anyhow!("Key {} does not exist", key_id))?;
+        if active.public_key() != key_share.local_key().public_key() || pending.public_key() != active.public_key() {
+            return Err(anyhow!("The refreshed share of key {} belongs to another public key", key_id));
+        }
+
+        let staged = key_share.with_local_key(active).with_pending(pending);
+        let store_id = self.store_id(key_id)?;
+        write_local_share(self.key_store.as_ref(), &store_id, &staged, self.sealer()?)?;
+        self.local_shares.insert(key_id.to_string(), staged);
+
+        self.drop_presignatures(key_id)
+    }
+
+    /// Replaces the key share with its refreshed version. The presignatures of the key were computed
+    /// with the old share, so they are dropped
+    pub fn replace_local_share(&mut self, key_id: &str, refreshed: LocalKey<Secp256k1>) -> Result<()> {
+        let key_share = self.local_shares.get(key_id).ok_or(anyhow!("Key {} does not exist", key_id))?;
+        if refreshed.public_key() != key_share.local_key().public_key() {
+            return Err(anyhow!("The refreshed share of key {} belongs to another public key", key_id));
+        }
+
+        let refreshed = key_share.with_local_key(refreshed);
+        let store_id = self.store_id(key_id)?;
+        write_local_share(self.key_store.as_ref(), &store_id, &refreshed, self.sealer()?)?;
+        self.local_shares.insert(key_id.to_string(), refreshed);
+
+        self.drop_presignatures(key_id)?;
+        println!("Key {} was refreshed", key_id);
+        Ok(())
+    }
+
+    /// Stores the share this server received in a resharing, in place of its old share of the key if it had one
+    pub fn install_reshared_share(&mut self, key_id: &str, reshared: KeyShare) -> Result<()> {
+        if let Some(public_key) = self.public_key(key_id) {
+            if reshared.local_key().public_key() != public_key {
+                return Err(anyhow!("The reshared share of key {} belongs to another public key", key_id));
+            }
+        }
+
+        let store_id = self.store_id(key_id)?;
+        write_local_share(self.key_store.as_ref(), &store_id, &reshared, self.sealer()?)?;
+        self.local_shares.insert(key_id.to_string(), reshared);
+
+        self.drop_presignatures(key_id)?;
+        println!("Key {} was reshared, stored as {}", key_id, store_id);
+        Ok(())
+    }
+
+    /// Deletes the share of a server that is no longer a party of the key after a resharing
+    pub fn delete_local_share(&mut self, key_id: &str) -> Result<()> {
+        let store_id = self.store_id(key_id)?;
+        self.key_store.delete(&store_id)?;
+        self.local_shares.remove(key_id);
+
+        self.drop_presignatures(key_id)?;
+        println!("Key {} was handed over to other servers, deleted {}", key_id, store_id);
+        Ok(())
+    }
+
+    // the presignatures were computed with the old share of the key
+    fn drop_presignatures(&mut self, key_id: &str) -> Result<()> {
+        for pool in self.presignatures.pool_names() {
+            if Signer::key_id_of_pool(&pool) == key_id {
+                self.presignatures.clear(&pool)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn local_share(&self, key_id: &str) -> Option<&LocalKey<Secp256k1>> {
+        self.local_shares.get(key_id).map(|key_share| key_share.local_key())
+    }
+
+    pub fn key_share(&self, key_id: &str) -> Option<&KeyShare> {
+        self.local_shares.get(key_id)
+    }
+
+    pub fn has_key(&self, key_id: &str) -> bool {
+        self.local_shares.contains_key(key_id)
+    }
+
+    pub fn public_key(&self, key_id: &str) -> Option<Point<Secp256k1>> {
+        self.local_share(key_id).map(|local_share| local_share.public_key())
+    }
+
+    /// The keys of this server, sorted by their id
+    pub fn keys(&self) -> Vec<KeyInfo> {
+        self.local_shares.iter()
+            .map(|(key_id, key_share)| KeyInfo {
+                key_id: key_id.clone(),
+                public_key: hex::encode(&*key_share.local_key().public_key().to_bytes(true)),
+                threshold: key_share.local_key().t,
+                number_of_parties: key_share.local_key().n,
+                parties: key_share.parties().to_vec(),
+            })
+            .collect()
+    }
+
+    fn sealer(&self) -> Result<&Sealer> {
+        self.sealer.as_deref().ok_or(anyhow!("No passphrase or keyfile to encrypt the key share"))
+    }
+
+    pub fn with_presignatures(mut self, presignatures: PresignaturePool<CompletedOfflineStage>) -> Signer {
+        self.presignatures = presignatures;
+        self
+    }
+
+    pub fn with_chains(mut self, chains: ChainStore) -> Signer {
+        self.chains = chains;
+        self
+    }
+
+    pub fn chains(&self) -> &ChainStore {
+        &self.chains
+    }
+
+    pub fn chains_mut(&mut self) -> &mut ChainStore {
+        &mut self.chains
+    }
+
     fn vec_to_string(participants: &Vec<u16>) -> String {
         let mut participants = participants.clone();
-        let mut result: String = String::new();
         participants.sort();
-        for participant in participants {
-            result.push_str( participant.to_string().as_str());
+
+        participants.iter()
+            .map(|p| p.to_string())
+            .collect::<Vec<String>>()
+            .join("-")
+    }
+
+    // a presignature belongs to a key, the pools of the default key keep the names they had before keys had ids
+    fn pool_key(key_id: &str, participants: &Vec<u16>) -> String {
+        if key_id == DEFAULT_KEY_ID {
+            Signer::vec_to_string(participants)
+        } else {
+            format!("{}.{}", key_id, Signer::vec_to_string(participants))
         }
+    }
 
-        return result;
+    fn key_id_of_pool(pool_key: &str) -> &str {
+        match pool_key.split_once('.') {
+            None => DEFAULT_KEY_ID,
+            Some((key_id, _)) => key_id,
+        }
     }
 
-    pub async fn do_offline_stage(
-        &mut self,
-        receiving_stream: Pin<&mut Fuse<impl Stream<Item=Result<Msg<OfflineProtocolMessage>>>>>,
-        outgoing_sink: Pin<&mut impl Sink<Msg<OfflineProtocolMessage>, Error=Error>>,
-        participants: &Vec<u16>
-    ) -> Result<(), Error>
-    {
-        if !self.are_participants_valid(participants) {
+    /// The offline stage of this server with the participants, it holds a copy of the key share
+    /// so that the protocol runs without the signer
+    pub fn new_offline_stage(&self, key_id: &str, participants: &Vec<u16>) -> Result<OfflineStage, Error> {
+        let key_share = match self.local_shares.get(key_id) {
+            None => return Err(anyhow!("Key {} is missing. Generate it with the /key_gen endpoint first.", key_id)),
+            Some(key_share) => key_share,
+        };
+        if !self.are_participants_valid(key_id, participants) {
             return Err(anyhow!("Invalid participants provided"));
         }
         let participants_string = Signer::vec_to_string(participants);
 
-        let local_share = self.get_local_share();
-        if local_share.is_none() {
-            return Err(anyhow!("local-share{}.json is missing. Generate it with the /keygen endpoint first.", self.my_index));
-        }
-        let local_share: LocalKey<Secp256k1> = local_share.unwrap();
-
         let arbitrary_index = match self.real_to_arbitrary_index(participants) {
             None => return Err(anyhow!("Invalid participants")),
             Some(ai) => ai
@@ -70,56 +299,99 @@ impl Signer {
         println!("My real index: {}", self.my_index);
         println!("My other index: {}", arbitrary_index);
 
-        // wait for servers to synchronize
-        // TODO: do this synchronization in a better way then sleeping
-        let one_second:Duration = time::Duration::from_secs(1);
-        thread::sleep(one_second);
-
-        let signing =
-            OfflineStage::new(arbitrary_index,
-                              self.get_participants(&participants).unwrap(),
-                              local_share).unwrap();
+        OfflineStage::new(arbitrary_index,
+                          self.get_participants(key_share, participants).unwrap(),
+                          key_share.local_key().clone())
+            .map_err(|e| anyhow!("Unable to start the offline stage: {}", e))
+    }
 
+    pub async fn do_offline_stage(
+        signing: OfflineStage,
+        receiving_stream: Pin<&mut Fuse<impl Stream<Item=Result<Msg<OfflineProtocolMessage>>>>>,
+        outgoing_sink: Pin<&mut impl Sink<Msg<OfflineProtocolMessage>, Error=Error>>,
+    ) -> Result<CompletedOfflineStage, Error>
+    {
         let offline_stage = AsyncProtocol::new(signing, receiving_stream, outgoing_sink)
             .run()
             .await
             .map_err(|e| anyhow!("protocol execution terminated with error: {}", e));
 
-        self.offline_stage.insert(participants_string, offline_stage?);
+        let offline_stage = offline_stage?;
 
         println!("OFFLINE STAGE IS COMPLETED");
 
-        Ok(())
+        Ok(offline_stage)
     }
 
-    pub async fn sign_hash(
-        &self,
-        hash_to_sign: &String,
-        receiving_stream: Pin<&mut impl Stream<Item = Result<Msg<PartialSignature>, Error>>>,
-        mut outgoing_sink: Pin<&mut (impl Sink<Msg<PartialSignature>, Error=Error> + Sized)>,
-        participants: Vec<u16>
-    ) -> Result<String, Error> {
-        if !self.are_participants_valid(&participants) {
+    pub fn add_presignature(&mut self, key_id: &str, participants: &Vec<u16>, id: &str, offline_stage: CompletedOfflineStage) -> Result<()> {
+        self.presignatures.insert(&Signer::pool_key(key_id, participants), id, offline_stage)
+    }
+
+    pub fn take_presignature(&mut self, key_id: &str, participants: &Vec<u16>, id: &str) -> Option<CompletedOfflineStage> {
+        self.presignatures.take(&Signer::pool_key(key_id, participants), id)
+    }
+
+    pub fn take_any_presignature(&mut self, key_id: &str, participants: &Vec<u16>) -> Option<(String, CompletedOfflineStage)> {
+        self.presignatures.take_any(&Signer::pool_key(key_id, participants))
+    }
+
+    pub fn presignature_count(&self, key_id: &str, participants: &Vec<u16>) -> usize {
+        self.presignatures.len(&Signer::pool_key(key_id, participants))
+    }
+
+    /// Remembers the set of participants (including this server) so that the presignatures of the key can be precomputed
+    pub fn register_participant_set(&mut self, key_id: &str, all_participants: &[u16]) -> Result<()> {
+        if !self.has_key(key_id) {
+            return Err(anyhow!("Key {} does not exist", key_id));
+        }
+
+        let others = self.others(all_participants);
+        if !self.are_participants_valid(key_id, &others) {
             return Err(anyhow!("Invalid participants provided"));
         }
 
-        let participants_string = Signer::vec_to_string(&participants);
+        self.presignatures.register_participants(&Signer::pool_key(key_id, &others), all_participants)
+    }
 
-        let offline_stage = match self.offline_stage.get(participants_string.as_str()) {
-            None => return Err(anyhow!("Offline stage not completed")),
-            Some(os) => os.clone()
-        };
+    /// The keys and their sets of participants whose presignatures are precomputed.
+    /// Sets that are no longer valid after a resharing of their key are left out
+    pub fn participant_sets(&self) -> Vec<(String, Vec<u16>)> {
+        self.presignatures.participant_sets().into_iter()
+            .map(|(pool_key, participants)| (Signer::key_id_of_pool(&pool_key).to_string(), participants))
+            .filter(|(key_id, participants)| self.are_participants_valid(key_id, &self.others(participants)))
+            .collect()
+    }
 
+    fn others(&self, all_participants: &[u16]) -> Vec<u16> {
+        all_participants.iter()
+            .filter(|id| **id != self.my_index)
+            .copied()
+            .collect()
+    }
+
+    /// The index of this server in the online stage with the participants
+    pub fn online_index(&self, key_id: &str, participants: &Vec<u16>) -> Result<u16, Error> {
+        if !self.are_participants_valid(key_id, participants) {
+            return Err(anyhow!("Invalid participants provided"));
+        }
+
+        self.real_to_arbitrary_index(participants).ok_or(anyhow!("Invalid participants"))
+    }
+
+    /// Signs the hash with the offline stage, which is consumed so that it cannot be used again
+    pub async fn sign_hash(
+        arbitrary_index: u16,
+        hash_to_sign: &str,
+        offline_stage: CompletedOfflineStage,
+        receiving_stream: Pin<&mut impl Stream<Item = Result<Msg<PartialSignature>, Error>>>,
+        mut outgoing_sink: Pin<&mut (impl Sink<Msg<PartialSignature>, Error=Error> + Sized)>,
+        participants: Vec<u16>
+    ) -> Result<SignatureFormats, Error> {
         let (signing, partial_signature) = SignManual::new(
             BigInt::from_bytes(&hex::decode(hash_to_sign).unwrap()),
             offline_stage
         )?;
 
-        let arbitrary_index = match self.real_to_arbitrary_index(&participants) {
-            None => return Err(anyhow!("Invalid participants")),
-            Some(ai) => ai
-        };
-
         outgoing_sink
             .send(Msg {
                 sender: arbitrary_index,
@@ -128,7 +400,7 @@ impl Signer {
             }).await?;
 
         let partial_signatures: Vec<_> = receiving_stream
-            .take(self.threshold as usize)
+            .take(participants.len())
             .map_ok(|msg| msg.body)
             .try_collect()
             .await?;
@@ -136,14 +408,15 @@ impl Signer {
         let signature = signing
             .complete(&partial_signatures)
             .context("online stage failed")?;
-        let signature = serde_json::to_string(&signature).context("serialize signature").unwrap();
-        println!("SIGNATURE:\n{}", signature);
+        let signature = SignatureFormats::new(&signature);
+        println!("SIGNATURE:\n{}", serde_json::to_string(&signature).context("serialize signature")?);
 
         Ok(signature)
     }
 
+    // the position of this server among the sorted participants, the keys of the participants are checked separately
     pub fn real_to_arbitrary_index(&self, other_indices: &Vec<u16>) -> Option<u16> {
-        if !self.are_participants_valid(other_indices) {
+        if other_indices.contains(&self.my_index) || has_duplicates(other_indices) {
             return None;
         }
         let mut index: u16 = 1;
@@ -156,63 +429,199 @@ impl Signer {
         return Some(index)
     }
 
-    pub fn is_offline_stage_complete(&self, participants: &Vec<u16>) -> bool {
-        let participants_string = Signer::vec_to_string(participants);
-        self.offline_stage.contains_key(participants_string.as_str())
-    }
-
-    fn get_local_share(&self) -> Option<LocalKey<Secp256k1>> {
-        let file_name = format!("local-share{}.json", self.my_index);
-        let file_content = read_file(Path::new(file_name.as_str()))?;
-        Some(file_to_local_key(&file_content))
-    }
-
-    fn get_participants(&self, participants: &Vec<u16>) -> Result<Vec<u16>,&'static str>  {
-        let mut p = participants.clone();
-        let mut all_participants: Vec<u16> = vec![self.my_index];
-        all_participants.append(&mut p);
+    // the indices of the participants in the key. The parties of a key are sorted by their server ids,
+    // so the order of the indices is the order of the servers in the rooms
+    fn get_participants(&self, key_share: &KeyShare, participants: &[u16]) -> Result<Vec<u16>,&'static str>  {
+        let mut all_participants = Vec::new();
+        for server_id in participants.iter().chain(std::iter::once(&self.my_index)) {
+            all_participants.push(key_share.index_of(*server_id).ok_or("Participant is not a party of the key")?);
+        }
         all_participants.sort(); // both parties must provide indexes in the same order
         Ok(all_participants)
     }
 
-    fn are_participants_valid(&self, participants: &Vec<u16>) -> bool {
-        if participants.len() as u16 != self.threshold {
+    /// A signature of the key needs threshold other parties of the key besides this server
+    pub fn are_participants_valid(&self, key_id: &str, participants: &[u16]) -> bool {
+        let key_share = match self.local_shares.get(key_id) {
+            None => return false,
+            Some(key_share) => key_share,
+        };
+        if participants.len() != usize::from(key_share.local_key().t) || has_duplicates(participants) {
             return false;
         }
 
-        for participant in participants {
-            if participant == &self.my_index ||
-                participant > &self.n_of_participants {
-                return false;
-            }
-        }
-
-        true
+        participants.iter()
+            .all(|participant| *participant != self.my_index && key_share.index_of(*participant).is_some())
     }
 }
 
+fn has_duplicates(participants: &[u16]) -> bool {
+    let mut sorted = participants.to_vec();
+    sorted.sort();
+    sorted.windows(2).any(|pair| pair[0] == pair[1])
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::mpc::operations::signing::Signer;
+    use curv::arithmetic::Converter;
+    use curv::BigInt;
+    use curv::elliptic::curves::Secp256k1;
+    use multi_party_ecdsa::protocols::multi_party_ecdsa::gg_2020::party_i::SignatureRecid;
+    use multi_party_ecdsa::protocols::multi_party_ecdsa::gg_2020::state_machine::keygen::LocalKey;
+    use multi_party_ecdsa::protocols::multi_party_ecdsa::gg_2020::state_machine::sign::{CompletedOfflineStage, OfflineStage, PartialSignature, SignManual};
+    use std::sync::Arc;
+    use round_based::dev::Simulation;
+    use crate::mpc::operations::test_fixtures::{simulate_keygen, simulate_refresh};
+    use crate::mpc::key_store::{InMemoryKeyStore, KeyStore};
+    use crate::mpc::operations::check_signature::check_sig;
+    use crate::mpc::operations::signing::{Signer, DEFAULT_KEY_ID};
+    use crate::mpc::utils::local_share_utils::KeyShare;
+    use crate::mpc::utils::signature_formats::normalize_s;
+
+    fn simulate_offline_stage(local_keys: &[LocalKey<Secp256k1>], participants: &[u16]) -> Vec<CompletedOfflineStage> {
+        let mut simulation = Simulation::new();
+        simulation.enable_benchmarks(false);
+
+        for (i, participant) in (1..).zip(participants) {
+            let local_key = local_keys[usize::from(*participant) - 1].clone();
+            simulation.add_party(OfflineStage::new(i, participants.to_vec(), local_key).unwrap());
+        }
+
+        simulation.run().unwrap()
+    }
+
+    fn simulate_online_stage(offline_stages: Vec<CompletedOfflineStage>, message: &str) -> SignatureRecid {
+        let message = BigInt::from_bytes(message.as_bytes());
+        let (mut signings, partial_signatures): (Vec<SignManual>, Vec<PartialSignature>) = offline_stages
+            .into_iter()
+            .map(|offline_stage| SignManual::new(message.clone(), offline_stage).unwrap())
+            .unzip();
+
+        signings.remove(0).complete(&partial_signatures[1..]).unwrap()
+    }
+
+    #[test]
+    fn no_presignature_available() {
+        let mut s: Signer = Signer::new(1);
+
+        assert_eq!(s.presignature_count(DEFAULT_KEY_ID, &vec![2]), 0);
+        assert!(s.take_any_presignature(DEFAULT_KEY_ID, &vec![2]).is_none());
+    }
+
+    #[test]
+    fn existing_key_share_is_not_replaced() {
+        let key_store = Arc::new(InMemoryKeyStore::new());
+        let s: Signer = Signer::new(2).with_key_store(key_store.clone());
+        assert!(s.check_no_local_share(DEFAULT_KEY_ID).is_ok());
+
+        key_store.store("local-share2", b"sealed share").unwrap();
+        key_store.store("local-share2-tenant-a", b"sealed share").unwrap();
+        assert!(s.check_no_local_share(DEFAULT_KEY_ID).is_err());
+        assert!(s.check_no_local_share("tenant-a").is_err());
+        assert!(s.check_no_local_share("tenant-b").is_ok());
+        assert!(s.check_no_local_share("../tenant-b").is_err());
+        assert!(Signer::new(1).with_key_store(key_store).check_no_local_share(DEFAULT_KEY_ID).is_ok());
+    }
+
+    #[test]
+    fn key_ids_in_the_key_store() {
+        let s: Signer = Signer::new(1);
+        assert_eq!(s.store_id(DEFAULT_KEY_ID).unwrap(), "local-share1");
+        assert_eq!(s.store_id("tenant-a").unwrap(), "local-share1-tenant-a");
+        assert!(s.store_id(&"a".repeat(64)).is_err());
+
+        assert_eq!(s.key_id_of("local-share1").as_deref(), Some(DEFAULT_KEY_ID));
+        assert_eq!(s.key_id_of("local-share1-tenant-a").as_deref(), Some("tenant-a"));
+        assert_eq!(s.key_id_of("local-share12"), None);
+        assert_eq!(s.key_id_of("local-share12-tenant-a"), None);
+        assert_eq!(s.key_id_of("local-share1-default"), None);
+        assert_eq!(s.key_id_of("audit_log"), None);
+    }
 
     #[test]
-    fn offline_stage_complete_no() {
-        let s: Signer = Signer::new(1, 1, 3);
+    fn presignatures_belong_to_a_key() {
+        assert_eq!(Signer::pool_key(DEFAULT_KEY_ID, &vec![3, 2]), "2-3");
+        assert_eq!(Signer::pool_key("tenant-a", &vec![3, 2]), "tenant-a.2-3");
+        assert_eq!(Signer::key_id_of_pool("2-3"), DEFAULT_KEY_ID);
+        assert_eq!(Signer::key_id_of_pool("tenant-a.2-3"), "tenant-a");
+    }
+
+    #[test]
+    fn signatures_never_share_r() {
+        let local_keys = simulate_keygen(1, 2);
+        let mut signers = [Signer::new(1), Signer::new(2)];
+        let others = |s: &Signer| vec![3 - s.my_index];
+
+        for id in ["1-a", "2-a"] {
+            let offline_stages = simulate_offline_stage(&local_keys, &[1, 2]);
+            for (signer, offline_stage) in signers.iter_mut().zip(offline_stages) {
+                let participants = others(signer);
+                signer.add_presignature(DEFAULT_KEY_ID, &participants, id, offline_stage).unwrap();
+            }
+        }
+
+        let mut signatures = Vec::new();
+        for message in ["first log", "second log"] {
+            let offline_stages: Vec<CompletedOfflineStage> = signers.iter_mut()
+                .map(|signer| {
+                    let participants = others(signer);
+                    signer.take_any_presignature(DEFAULT_KEY_ID, &participants).unwrap().1
+                })
+                .collect();
+            signatures.push(simulate_online_stage(offline_stages, message));
+        }
 
-        assert!(!s.is_offline_stage_complete(&vec![2]));
+        assert_ne!(signatures[0].r, signatures[1].r);
+
+        // both presignatures were consumed, the next signature needs a new offline stage
+        for signer in signers.iter_mut() {
+            let participants = others(signer);
+            assert!(signer.take_any_presignature(DEFAULT_KEY_ID, &participants).is_none());
+        }
+    }
+
+    #[test]
+    fn refreshed_shares_sign_for_the_same_key() {
+        let local_keys = simulate_keygen(1, 3);
+        let refreshed = simulate_refresh(&local_keys);
+
+        let signature = normalize_s(&simulate_online_stage(simulate_offline_stage(&refreshed, &[1, 3]), "refreshed log"));
+        let message = BigInt::from_bytes("refreshed log".as_bytes());
+        assert!(check_sig(&signature.r, &signature.s, &message, &local_keys[0].public_key()));
+    }
+
+    #[test]
+    fn participants_are_parties_of_the_key() {
+        let local_keys = simulate_keygen(1, 3);
+        // index 2 of the key, held by server 4 after a resharing to servers 2, 4 and 7
+        let mut s: Signer = Signer::new(4);
+        s.local_shares.insert(DEFAULT_KEY_ID.to_string(), KeyShare::new(local_keys[1].clone(), vec![2, 4, 7]));
+
+        assert!(s.are_participants_valid(DEFAULT_KEY_ID, &[7]));
+        assert!(!s.are_participants_valid(DEFAULT_KEY_ID, &[3]));
+        assert!(!s.are_participants_valid(DEFAULT_KEY_ID, &[4]));
+        assert!(!s.are_participants_valid(DEFAULT_KEY_ID, &[2, 7]));
+        assert!(!s.are_participants_valid("tenant-a", &[7]));
+
+        let key_share = s.key_share(DEFAULT_KEY_ID).unwrap();
+        assert_eq!(s.get_participants(key_share, &[7]), Ok(vec![2, 3]));
+        assert_eq!(s.get_participants(key_share, &[2]), Ok(vec![1, 2]));
+        assert_eq!(s.real_to_arbitrary_index(&vec![7]), Some(1));
+        assert_eq!(s.real_to_arbitrary_index(&vec![2]), Some(2));
+        assert_eq!(s.real_to_arbitrary_index(&vec![4]), None);
     }
 
     #[test]
     fn arbitrary_index_conversion() {
-        let s: Signer = Signer::new(2, 1, 3);
+        let s: Signer = Signer::new(2);
         assert_eq!(s.real_to_arbitrary_index(&vec![1]), Some(2));
         assert_eq!(s.real_to_arbitrary_index(&vec![3]), Some(1));
 
-        let s: Signer = Signer::new(3, 1, 3);
+        let s: Signer = Signer::new(3);
         assert_eq!(s.real_to_arbitrary_index(&vec![1]), Some(2));
         assert_eq!(s.real_to_arbitrary_index(&vec![2]), Some(2));
 
-        let s: Signer = Signer::new(2, 2, 4);
+        let s: Signer = Signer::new(2);
         assert_eq!(s.real_to_arbitrary_index(&vec![3,4]), Some(1));
         assert_eq!(s.real_to_arbitrary_index(&vec![1,3]), Some(2));
     }
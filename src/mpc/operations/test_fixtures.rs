@@ -0,0 +1,55 @@
+use std::collections::BTreeMap;
+
+use curv::elliptic::curves::{Scalar, Secp256k1};
+use multi_party_ecdsa::protocols::multi_party_ecdsa::gg_2020::party_i::Keys;
+use multi_party_ecdsa::protocols::multi_party_ecdsa::gg_2020::state_machine::keygen::{Keygen, LocalKey};
+use round_based::dev::Simulation;
+
+use crate::mpc::operations::key_refresh::{apply_refresh, share_zero, RefreshShares};
+use crate::mpc::operations::key_resharing::{receiver_keys, ReceiverKeys};
+
+/// Key shares of parties 1 to n with threshold t, generated in memory without any network
+pub fn simulate_keygen(t: u16, n: u16) -> Vec<LocalKey<Secp256k1>> {
+    let mut simulation = Simulation::new();
+    simulation.enable_benchmarks(false);
+
+    for i in 1..=n {
+        simulation.add_party(Keygen::new(i, t, n).unwrap());
+    }
+
+    simulation.run().unwrap()
+}
+
+/// The fresh keys of all the parties with their public part by party, the coefficients of their polynomials
+/// and the shares they broadcast
+pub type RefreshRound = (Vec<Keys>, BTreeMap<u16, ReceiverKeys>, Vec<Vec<Scalar<Secp256k1>>>, Vec<RefreshShares>);
+
+pub fn simulate_refresh_messages(local_keys: &[LocalKey<Secp256k1>]) -> RefreshRound {
+    let (keys, receivers): (Vec<Keys>, BTreeMap<u16, ReceiverKeys>) = local_keys.iter()
+        .map(|local_key| {
+            let (keys, public_keys) = receiver_keys(local_key.i);
+            (keys, (local_key.i, public_keys))
+        })
+        .unzip();
+    let (coefficients, messages) = local_keys.iter()
+        .map(|local_key| share_zero(local_key, &receivers).unwrap())
+        .unzip();
+    (keys, receivers, coefficients, messages)
+}
+
+/// The shares that the party received from all the other parties
+pub fn received_by(party: u16, messages: &[RefreshShares]) -> BTreeMap<u16, RefreshShares> {
+    (1..).zip(messages.iter().cloned())
+        .filter(|(sender, _)| *sender != party)
+        .collect()
+}
+
+/// The refreshed shares of all the parties, refreshed in memory without any network
+pub fn simulate_refresh(local_keys: &[LocalKey<Secp256k1>]) -> Vec<LocalKey<Secp256k1>> {
+    let (keys, receivers, coefficients, messages) = simulate_refresh_messages(local_keys);
+    local_keys.iter().zip(&keys).zip(&coefficients)
+        .map(|((local_key, keys), coefficients)| {
+            apply_refresh(local_key, keys, &receivers, coefficients, &received_by(local_key.i, &messages)).unwrap()
+        })
+        .collect()
+}
@@ -1,4 +1,10 @@
-pub mod hex2string;
 pub mod local_share_utils;
 pub mod check_timestamp;
 pub mod parse_signature_json;
+pub mod public_key_formats;
+pub mod sealing;
+pub mod shares;
+pub mod signature_formats;
+pub mod parse_verify_json;
+pub mod merkle_tree;
+pub mod log_ingestion;
@@ -1,19 +1,140 @@
-use std::path::Path;
-use std::fs;
-use anyhow::Context;
+use anyhow::{Context, Result};
 use curv::elliptic::curves::Secp256k1;
 use multi_party_ecdsa::protocols::multi_party_ecdsa::gg_2020::state_machine::keygen::{LocalKey};
+use serde::{Deserialize, Serialize};
 
-pub fn read_file(file_name: &Path) -> Option<String> {
-    let file_as_string = fs::read_to_string(file_name);
-    return match file_as_string {
-        Ok(s) => Some(s),
-        Err(_) => None
+use crate::mpc::key_store::KeyStore;
+use crate::mpc::utils::sealing::{is_sealed, Sealer};
+
+/// The key share of a server together with the servers that hold the other shares.
+/// After a resharing the index of a party in the key is no longer its server id
+#[derive(Clone, Serialize, Deserialize)]
+pub struct KeyShare {
+    local_key: LocalKey<Secp256k1>,
+    // server ids of the parties, the party with the key index i is parties[i - 1]
+    parties: Vec<u16>,
+    // the refreshed share of a refresh that not every party confirmed yet, activated by the next refresh
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pending: Option<LocalKey<Secp256k1>>,
+}
+
+impl KeyShare {
+    /// A key share generated by the key generation, where every server id is the index of its party
+    pub fn generated(local_key: LocalKey<Secp256k1>) -> KeyShare {
+        let parties = (1..=local_key.n).collect();
+        KeyShare { local_key, parties, pending: None }
+    }
+
+    pub fn new(local_key: LocalKey<Secp256k1>, parties: Vec<u16>) -> KeyShare {
+        KeyShare { local_key, parties, pending: None }
+    }
+
+    pub fn local_key(&self) -> &LocalKey<Secp256k1> {
+        &self.local_key
+    }
+
+    /// The same parties with another share, after a refresh
+    pub fn with_local_key(&self, local_key: LocalKey<Secp256k1>) -> KeyShare {
+        KeyShare { local_key, parties: self.parties.clone(), pending: None }
+    }
+
+    /// The same share with the refreshed share that waits for the confirmations of the other parties
+    pub fn with_pending(mut self, pending: LocalKey<Secp256k1>) -> KeyShare {
+        self.pending = Some(pending);
+        self
+    }
+
+    pub fn pending(&self) -> Option<&LocalKey<Secp256k1>> {
+        self.pending.as_ref()
+    }
+
+    pub fn parties(&self) -> &[u16] {
+        &self.parties
+    }
+
+    /// The index of the server in the key, None if it holds no share of it
+    pub fn index_of(&self, server_id: u16) -> Option<u16> {
+        (1..).zip(&self.parties)
+            .find(|(_, party)| **party == server_id)
+            .map(|(index, _)| index)
+    }
+
+    pub fn server_id_of(&self, index: u16) -> Option<u16> {
+        self.parties.get(usize::from(index).checked_sub(1)?).copied()
+    }
+}
+
+// shares written before resharing was introduced are a plain local key
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StoredKeyShare {
+    KeyShare(KeyShare),
+    LocalKey(LocalKey<Secp256k1>),
+}
+
+fn parse_key_share(content: &[u8]) -> Result<KeyShare> {
+    match serde_json::from_slice(content).context("parse local share")? {
+        StoredKeyShare::KeyShare(key_share) => Ok(key_share),
+        StoredKeyShare::LocalKey(local_key) => Ok(KeyShare::generated(local_key)),
+    }
+}
+
+/// Reads and decrypts the key share, None if it was not generated yet.
+/// A share written before the encryption was introduced is encrypted in place
+pub fn read_local_share(key_store: &dyn KeyStore, key_id: &str, sealer: &Sealer) -> Result<Option<KeyShare>> {
+    let content = match key_store.load(key_id)? {
+        Some(content) => content,
+        None => return Ok(None),
     };
+
+    if is_sealed(&content) {
+        let plaintext = sealer.unseal(&content).with_context(|| format!("decrypt key share {}", key_id))?;
+        return Ok(Some(parse_key_share(&plaintext)?));
+    }
+
+    let local_share = parse_key_share(&content)?;
+    write_local_share(key_store, key_id, &local_share, sealer)?;
+    println!("Encrypted the unencrypted key share {}", key_id);
+
+    Ok(Some(local_share))
 }
 
-pub fn file_to_local_key(file_content: &String) -> LocalKey<Secp256k1> {
-    let local_share: LocalKey<Secp256k1> = serde_json::from_slice(file_content.as_bytes()).context("parse local share").unwrap();
+pub fn write_local_share(key_store: &dyn KeyStore, key_id: &str, local_share: &KeyShare, sealer: &Sealer) -> Result<()> {
+    let sealed = sealer.seal(&serde_json::to_vec(local_share)?)?;
+    key_store.store(key_id, &sealed)
+}
+
+#[cfg(test)]
+mod tests {
+    use multi_party_ecdsa::protocols::multi_party_ecdsa::gg_2020::state_machine::keygen::Keygen;
+    use round_based::dev::Simulation;
+    use crate::mpc::utils::local_share_utils::{parse_key_share, KeyShare};
+
+    #[test]
+    fn key_share_without_parties_is_read() {
+        let mut simulation = Simulation::new();
+        simulation.enable_benchmarks(false);
+        for i in 1..=2 {
+            simulation.add_party(Keygen::new(i, 1, 2).unwrap());
+        }
+        let local_key = simulation.run().unwrap().remove(1);
 
-    local_share
-}
\ No newline at end of file
+        let legacy = parse_key_share(&serde_json::to_vec(&local_key).unwrap()).unwrap();
+        assert_eq!(legacy.parties(), &[1, 2]);
+        assert_eq!(legacy.index_of(2), Some(2));
+        assert_eq!(legacy.server_id_of(3), None);
+
+        let reshared = KeyShare::new(local_key.clone(), vec![3, 5]);
+        let reshared = parse_key_share(&serde_json::to_vec(&reshared).unwrap()).unwrap();
+        assert_eq!(reshared.parties(), &[3, 5]);
+        assert_eq!(reshared.index_of(5), Some(2));
+        assert_eq!(reshared.index_of(2), None);
+        assert_eq!(reshared.server_id_of(1), Some(3));
+        assert!(reshared.pending().is_none());
+
+        let staged = reshared.with_local_key(local_key.clone()).with_pending(local_key);
+        let staged = parse_key_share(&serde_json::to_vec(&staged).unwrap()).unwrap();
+        assert!(staged.pending().is_some());
+        assert!(staged.with_local_key(staged.local_key().clone()).pending().is_none());
+    }
+}
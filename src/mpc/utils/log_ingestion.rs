@@ -0,0 +1,273 @@
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail, Context, Result};
+use curv::elliptic::curves::{Point, Secp256k1};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::mpc::operations::check_signature::{signed_digest, verify_signature, DigestVersion};
+use crate::mpc::operations::log_chain::{check_chain, ChainIssue, ChainLink, ChainRecord};
+use crate::mpc::utils::merkle_tree::InclusionProof;
+
+/// Format of an ingested log file, every line is a record
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    // a record is the line as it is, without the line break
+    #[default]
+    Text,
+    // a record is a JSON value, signed in its canonical form
+    Jsonl,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<LogFormat, String> {
+        serde_json::from_value(Value::from(name))
+            .map_err(|_| format!("Unknown log format {}, expected text or jsonl", name))
+    }
+}
+
+impl LogFormat {
+    /// The canonical form of the record on the line, which is what is signed. Blank lines are no records
+    pub fn canonical_record(&self, line: &str) -> Result<Option<Vec<u8>>, String> {
+        if line.trim().is_empty() {
+            return Ok(None);
+        }
+
+        match self {
+            LogFormat::Text => Ok(Some(line.as_bytes().to_vec())),
+            LogFormat::Jsonl => {
+                let value: Value = serde_json::from_str(line).map_err(|e| format!("The record is not JSON: {}", e))?;
+                Ok(Some(canonical_json(&value).into_bytes()))
+            }
+        }
+    }
+}
+
+/// JSON with the keys of every object sorted and without whitespace, so that the same record is signed
+/// however the logger ordered and spaced it. Strings and numbers are written as serde_json writes them
+pub fn canonical_json(value: &Value) -> String {
+    match value {
+        Value::Array(values) => format!("[{}]", values.iter().map(canonical_json).collect::<Vec<_>>().join(",")),
+        Value::Object(map) => {
+            let sorted: BTreeMap<&String, &Value> = map.iter().collect();
+            let members: Vec<String> = sorted.into_iter()
+                .map(|(key, value)| format!("{}:{}", Value::from(key.as_str()), canonical_json(value)))
+                .collect();
+            format!("{{{}}}", members.join(","))
+        }
+        scalar => scalar.to_string(),
+    }
+}
+
+/// A record of a log file, the line is counted from 1
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogRecord {
+    pub line: usize,
+    pub canonical: Vec<u8>,
+}
+
+/// The records of the log, fails at the first line that is not a record of the format
+pub fn parse_log(log: &str, format: LogFormat) -> Result<Vec<LogRecord>, String> {
+    let mut records = Vec::new();
+    for (index, line) in log.lines().enumerate() {
+        match format.canonical_record(line) {
+            Ok(Some(canonical)) => records.push(LogRecord { line: index + 1, canonical }),
+            Ok(None) => {}
+            Err(e) => return Err(format!("line {}: {}", index + 1, e)),
+        }
+    }
+
+    Ok(records)
+}
+
+/// The detached signature of a record, a line of the sidecar file of the log
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SidecarEntry {
+    // line of the record in the log
+    line: usize,
+    // hex encoded DER
+    signature: String,
+    timestamp: String,
+    key_id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    chain: Option<ChainLink>,
+    // the record was signed in a batch
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    proof: Option<InclusionProof>,
+}
+
+impl SidecarEntry {
+    pub fn new(line: usize, signature: &str, timestamp: &str, key_id: &str) -> SidecarEntry {
+        SidecarEntry {
+            line,
+            signature: signature.to_string(),
+            timestamp: timestamp.to_string(),
+            key_id: key_id.to_string(),
+            chain: None,
+            proof: None,
+        }
+    }
+
+    pub fn with_chain(mut self, chain: Option<ChainLink>) -> SidecarEntry {
+        self.chain = chain;
+        self
+    }
+
+    pub fn with_proof(mut self, proof: InclusionProof) -> SidecarEntry {
+        self.proof = Some(proof);
+        self
+    }
+
+    /// The digest the servers signed for the canonical record
+    pub fn signed_digest(&self, canonical: &[u8]) -> Result<Vec<u8>> {
+        let digest = signed_digest(DigestVersion::V1, &self.key_id, canonical, &self.timestamp);
+        match (&self.chain, &self.proof) {
+            (None, None) => Ok(digest),
+            (Some(link), None) => link.signed_digest(&self.key_id, &digest, &self.timestamp),
+            (None, Some(proof)) => proof.signed_digest(&self.key_id, &digest, &self.timestamp),
+            (Some(_), Some(_)) => bail!("Batches cannot be chained"),
+        }
+    }
+}
+
+/// The sidecar file, one JSON entry per line in the order of the records
+pub fn write_sidecar(entries: &[SidecarEntry]) -> Result<String> {
+    let mut sidecar = String::new();
+    for entry in entries {
+        sidecar.push_str(&serde_json::to_string(entry)?);
+        sidecar.push('\n');
+    }
+    Ok(sidecar)
+}
+
+/// Verifies a log against its sidecar with the public key. Every record of the log must be signed and every
+/// signature must match its record, chained records must form unbroken chains. Fails if the sidecar cannot be parsed
+pub fn verify_sidecar(log: &str, format: LogFormat, sidecar: &str, public_key: &Point<Secp256k1>) -> Result<Vec<ChainIssue>> {
+    let mut entries: BTreeMap<usize, Vec<SidecarEntry>> = BTreeMap::new();
+    for (index, line) in sidecar.lines().enumerate().filter(|(_, line)| !line.trim().is_empty()) {
+        let entry: SidecarEntry = serde_json::from_str(line).with_context(|| format!("Parse line {} of the sidecar", index + 1))?;
+        entries.entry(entry.line).or_default().push(entry);
+    }
+
+    let lines: Vec<&str> = log.lines().collect();
+    let mut issues = Vec::new();
+    for (index, line) in lines.iter().enumerate() {
+        let line_number = index + 1;
+        match format.canonical_record(line) {
+            Ok(Some(_)) if !entries.contains_key(&line_number) =>
+                issues.push(ChainIssue::InvalidRecord { line: line_number, reason: String::from("The record is not signed") }),
+            Err(reason) => issues.push(ChainIssue::InvalidRecord { line: line_number, reason }),
+            _ => {}
+        }
+    }
+
+    let mut records = Vec::new();
+    for (line_number, entry) in entries.iter().flat_map(|(line, entries)| entries.iter().map(move |entry| (*line, entry))) {
+        let checked = lines.get(line_number.wrapping_sub(1))
+            .and_then(|line| format.canonical_record(line).ok().flatten())
+            .ok_or(anyhow!("The sidecar signs line {}, which is no record of the log", line_number))
+            .and_then(|canonical| {
+                let digest = entry.signed_digest(&canonical)?;
+                let signature = hex::decode(&entry.signature).context("The signature is not hex encoded")?;
+                if !verify_signature(&signature, None, &digest, public_key)? {
+                    bail!("The signature does not match the record and the public key");
+                }
+                Ok(digest)
+            });
+
+        match (checked, &entry.chain) {
+            (Err(e), _) => issues.push(ChainIssue::InvalidRecord { line: line_number, reason: format!("{:#}", e) }),
+            (Ok(digest), Some(link)) => records.push(ChainRecord::new(line_number, link, digest)?),
+            (Ok(_), None) => {}
+        }
+    }
+
+    issues.extend(check_chain(&records));
+    issues.sort_by_key(|issue| issue.line());
+    Ok(issues)
+}
+
+#[cfg(test)]
+mod tests {
+    use curv::elliptic::curves::{Point, Secp256k1};
+    use secp256k1::{Message, PublicKey, SecretKey, SECP256K1};
+    use crate::mpc::operations::check_signature::signed_batch_digest;
+    use crate::mpc::operations::log_chain::{ChainIssue, ChainLink, GENESIS_DIGEST};
+    use crate::mpc::utils::log_ingestion::{parse_log, verify_sidecar, write_sidecar, LogFormat, SidecarEntry};
+    use crate::mpc::utils::merkle_tree::MerkleTree;
+
+    const NGINX_LOG: &str = r#"{"time": "2023-04-13T16:12:30+00:00", "status": 200, "request": {"method": "GET", "uri": "/"}}
+
+{"request": {"uri": "/favicon.ico", "method": "GET"}, "status": 404, "time": "2023-04-13T16:12:31+00:00"}
+"#;
+
+    fn sign(digest: &[u8]) -> (String, Point<Secp256k1>) {
+        let secret_key = SecretKey::from_slice(&[7u8; 32]).unwrap();
+        let public_key = Point::from_bytes(&PublicKey::from_secret_key(SECP256K1, &secret_key).serialize()).unwrap();
+        (hex::encode(&*SECP256K1.sign(&Message::from_slice(digest).unwrap(), &secret_key).serialize_der()), public_key)
+    }
+
+    #[test]
+    fn records_are_canonicalized() {
+        let records = parse_log(NGINX_LOG, LogFormat::Jsonl).unwrap();
+        assert_eq!(records.iter().map(|record| record.line).collect::<Vec<_>>(), vec![1, 3]);
+        assert_eq!(String::from_utf8(records[1].canonical.clone()).unwrap(),
+                   r#"{"request":{"method":"GET","uri":"/favicon.ico"},"status":404,"time":"2023-04-13T16:12:31+00:00"}"#);
+        assert_eq!(parse_log(r#"{"b": [1, {"d": null, "c": "é\""}], "a": 1.5}"#, LogFormat::Jsonl).unwrap()[0].canonical,
+                   r#"{"a":1.5,"b":[1,{"c":"é\"","d":null}]}"#.as_bytes());
+
+        let text = parse_log("GET / 200\r\n\nGET /favicon.ico 404 ", LogFormat::Text).unwrap();
+        assert_eq!(text[0].canonical, b"GET / 200");
+        assert_eq!(text[1].canonical, b"GET /favicon.ico 404 ");
+        assert_eq!(parse_log("{\"a\": 1}\nnot json", LogFormat::Jsonl).unwrap_err(), "line 2: The record is not JSON: expected ident at line 1 column 2");
+        assert!("csv".parse::<LogFormat>().is_err());
+    }
+
+    #[test]
+    fn sidecars_are_verified() {
+        let records = parse_log(NGINX_LOG, LogFormat::Jsonl).unwrap();
+        let digests: Vec<Vec<u8>> = records.iter()
+            .map(|record| SidecarEntry::new(record.line, "", "1681402350", "default").signed_digest(&record.canonical).unwrap())
+            .collect();
+
+        // signed one by one and chained
+        let mut entries = Vec::new();
+        let mut previous = GENESIS_DIGEST.to_vec();
+        for (sequence, (record, digest)) in records.iter().zip(&digests).enumerate() {
+            let link = ChainLink::new("nginx", sequence as u64, &previous);
+            previous = link.signed_digest("default", digest, "1681402350").unwrap();
+            let (signature, _) = sign(&previous);
+            entries.push(SidecarEntry::new(record.line, &signature, "1681402350", "default").with_chain(Some(link)));
+        }
+        let (_, public_key) = sign(&previous);
+        let sidecar = write_sidecar(&entries).unwrap();
+        assert!(verify_sidecar(NGINX_LOG, LogFormat::Jsonl, &sidecar, &public_key).unwrap().is_empty());
+        // the keys may be reordered and spaced differently, the values not
+        let reordered = NGINX_LOG.replacen(r#"{"time": "2023-04-13T16:12:30+00:00", "status": 200,"#, r#"{"status":200,"time":"2023-04-13T16:12:30+00:00","#, 1);
+        assert_ne!(reordered, NGINX_LOG);
+        assert!(verify_sidecar(&reordered, LogFormat::Jsonl, &sidecar, &public_key).unwrap().is_empty());
+        let altered = NGINX_LOG.replace("404", "200");
+        assert!(matches!(verify_sidecar(&altered, LogFormat::Jsonl, &sidecar, &public_key).unwrap()[..], [ChainIssue::InvalidRecord { line: 3, .. }]));
+
+        // the last record cut off together with its signature is found with the chain head, an unsigned record is reported
+        let shortened = format!("{}\n", NGINX_LOG.lines().next().unwrap());
+        assert!(verify_sidecar(&shortened, LogFormat::Jsonl, &write_sidecar(&entries[..1]).unwrap(), &public_key).unwrap().is_empty());
+        let appended = format!("{}{}\n", NGINX_LOG, r#"{"status": 500}"#);
+        assert!(matches!(verify_sidecar(&appended, LogFormat::Jsonl, &sidecar, &public_key).unwrap()[..], [ChainIssue::InvalidRecord { line: 4, .. }]));
+
+        // signed in a batch
+        let tree = MerkleTree::new(&digests).unwrap();
+        let (signature, _) = sign(&signed_batch_digest("default", &tree.root(), "1681402350"));
+        let batch: Vec<SidecarEntry> = records.iter().zip(tree.proofs())
+            .map(|(record, proof)| SidecarEntry::new(record.line, &signature, "1681402350", "default").with_proof(proof))
+            .collect();
+        assert!(verify_sidecar(NGINX_LOG, LogFormat::Jsonl, &write_sidecar(&batch).unwrap(), &public_key).unwrap().is_empty());
+        assert_eq!(verify_sidecar(&altered, LogFormat::Jsonl, &write_sidecar(&batch).unwrap(), &public_key).unwrap().len(), 1);
+        assert!(verify_sidecar(NGINX_LOG, LogFormat::Jsonl, "{\"line\": 1}", &public_key).is_err());
+    }
+}
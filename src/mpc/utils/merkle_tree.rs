@@ -0,0 +1,184 @@
+use anyhow::{anyhow, bail, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::mpc::operations::check_signature::signed_batch_digest;
+
+// leaves and inner nodes are hashed with different prefixes as in RFC 9162,
+// so that an inner node cannot be passed off as an entry
+const LEAF_PREFIX: u8 = 0;
+const NODE_PREFIX: u8 = 1;
+
+/// Merkle tree over the digests of the entries of a batch. An odd node at the end of a level
+/// is promoted to the next level, which gives the same tree as RFC 9162
+#[derive(Debug, Clone)]
+pub struct MerkleTree {
+    // the leaf hashes first, the root last
+    levels: Vec<Vec<[u8; 32]>>,
+}
+
+/// The sibling hashes from an entry up to the root, they prove that the entry is part of the signed batch
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct InclusionProof {
+    // position of the entry in the batch
+    index: usize,
+    // number of entries in the batch
+    tree_size: usize,
+    // hex encoded, the sibling of the leaf first
+    path: Vec<String>,
+}
+
+impl MerkleTree {
+    pub fn new(entry_digests: &[Vec<u8>]) -> Result<MerkleTree> {
+        if entry_digests.is_empty() {
+            bail!("A batch needs at least one entry");
+        }
+
+        let mut levels = vec![entry_digests.iter().map(|digest| hash(LEAF_PREFIX, &[digest])).collect::<Vec<_>>()];
+        while levels.last().unwrap().len() > 1 {
+            let next = levels.last().unwrap()
+                .chunks(2)
+                .map(|pair| match pair {
+                    [left, right] => hash(NODE_PREFIX, &[left, right]),
+                    [odd] => *odd,
+                    _ => unreachable!(),
+                })
+                .collect();
+            levels.push(next);
+        }
+
+        Ok(MerkleTree { levels })
+    }
+
+    pub fn root(&self) -> [u8; 32] {
+        self.levels.last().unwrap()[0]
+    }
+
+    pub fn tree_size(&self) -> usize {
+        self.levels[0].len()
+    }
+
+    /// The proof of the entry at the index, which must be below the tree size
+    pub fn proof(&self, index: usize) -> InclusionProof {
+        let mut path = Vec::new();
+        let mut position = index;
+        for level in &self.levels[..self.levels.len() - 1] {
+            // a promoted node has no sibling on its level
+            if let Some(sibling) = level.get(position ^ 1) {
+                path.push(hex::encode(sibling));
+            }
+            position >>= 1;
+        }
+
+        InclusionProof { index, tree_size: self.tree_size(), path }
+    }
+
+    pub fn proofs(&self) -> Vec<InclusionProof> {
+        (0..self.tree_size()).map(|index| self.proof(index)).collect()
+    }
+}
+
+impl InclusionProof {
+    /// The root that the proof leads to from the digest of the entry, as in RFC 9162, section 2.1.3.2
+    pub fn root(&self, entry_digest: &[u8]) -> Result<[u8; 32]> {
+        if self.index >= self.tree_size {
+            bail!("The entry {} is not part of a batch of {} entries", self.index, self.tree_size);
+        }
+
+        let mut node = self.index;
+        let mut last_node = self.tree_size - 1;
+        let mut root = hash(LEAF_PREFIX, &[entry_digest]);
+        for sibling in &self.path {
+            let sibling = hex::decode(sibling).map_err(|_| anyhow!("The proof is not hex encoded"))?;
+            if last_node == 0 {
+                bail!("The proof is longer than the path to the root");
+            }
+            if node & 1 == 1 || node == last_node {
+                root = hash(NODE_PREFIX, &[&sibling, &root]);
+                // skips the levels the node was promoted over
+                while node & 1 == 0 && node != 0 {
+                    node >>= 1;
+                    last_node >>= 1;
+                }
+            } else {
+                root = hash(NODE_PREFIX, &[&root, &sibling]);
+            }
+            node >>= 1;
+            last_node >>= 1;
+        }
+
+        if last_node != 0 {
+            bail!("The proof is shorter than the path to the root");
+        }
+        Ok(root)
+    }
+
+    /// The digest the servers signed for the batch of the entry, given the digest of the entry
+    pub fn signed_digest(&self, key_id: &str, entry_digest: &[u8], timestamp: &str) -> Result<Vec<u8>> {
+        Ok(signed_batch_digest(key_id, &self.root(entry_digest)?, timestamp))
+    }
+}
+
+fn hash(prefix: u8, parts: &[&[u8]]) -> [u8; 32] {
+    let mut encoded = vec![prefix];
+    for part in parts {
+        encoded.extend_from_slice(part);
+    }
+
+    let mut digest = [0u8; 32];
+    hex::decode_to_slice(sha256::digest(encoded.as_slice()), &mut digest).unwrap();
+    digest
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mpc::utils::merkle_tree::{hash, MerkleTree, LEAF_PREFIX, NODE_PREFIX};
+
+    fn digests(count: u8) -> Vec<Vec<u8>> {
+        (0..count).map(|i| vec![i; 32]).collect()
+    }
+
+    #[test]
+    fn every_entry_is_proven() {
+        for count in 1..=9 {
+            let entries = digests(count);
+            let tree = MerkleTree::new(&entries).unwrap();
+            for (entry, proof) in entries.iter().zip(tree.proofs()) {
+                assert_eq!(proof.root(entry).unwrap(), tree.root());
+            }
+        }
+
+        let tree = MerkleTree::new(&digests(3)).unwrap();
+        let leaf = |i: u8| hash(LEAF_PREFIX, &[&[i; 32]]);
+        let node = |left: [u8; 32], right: [u8; 32]| hash(NODE_PREFIX, &[&left, &right]);
+        assert_eq!(tree.root(), node(node(leaf(0), leaf(1)), leaf(2)));
+        assert_eq!(tree.proof(2).path, vec![hex::encode(node(leaf(0), leaf(1)))]);
+        assert!(MerkleTree::new(&[]).is_err());
+    }
+
+    #[test]
+    fn wrong_proofs_are_rejected() {
+        let entries = digests(5);
+        let tree = MerkleTree::new(&entries).unwrap();
+        let proof = tree.proof(1);
+
+        // another entry, another position or another batch size
+        assert_ne!(proof.root(&entries[2]).unwrap(), tree.root());
+        let mut moved = proof.clone();
+        moved.index = 0;
+        assert_ne!(moved.root(&entries[1]).unwrap(), tree.root());
+        let mut resized = proof.clone();
+        resized.tree_size = 9;
+        assert!(resized.root(&entries[1]).is_err());
+        resized.index = 5;
+        resized.tree_size = 5;
+        assert!(resized.root(&entries[1]).is_err());
+
+        let mut truncated = proof.clone();
+        truncated.path.pop();
+        assert!(truncated.root(&entries[1]).is_err());
+        let mut extended = proof;
+        extended.path.push(hex::encode([0u8; 32]));
+        assert!(extended.root(&entries[1]).is_err());
+    }
+}
@@ -1,45 +1,245 @@
 extern crate serde;
 extern crate serde_json;
 
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Deserialize, Serialize)]
-struct Participant {
-    server_id: u16,
-    url: String,
+use crate::mpc::operations::check_signature::{signed_batch_digest, signed_digest, signed_prehashed_digest, DigestVersion};
+use crate::mpc::operations::log_chain::{ChainHead, ChainLink};
+use crate::mpc::operations::signing::DEFAULT_KEY_ID;
+use crate::mpc::utils::merkle_tree::{InclusionProof, MerkleTree};
+use crate::mpc::utils::signature_formats::SignatureFormats;
+
+/// How the data to sign is encoded and what is signed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SigningMode {
+    // hex encoded bytes, hashed together with the key id and the timestamp
+    #[default]
+    Hex,
+    // base64 encoded bytes, hashed together with the key id and the timestamp
+    Base64,
+    // a hex encoded sha256 digest computed by the client, hashed together with the key id and the timestamp
+    Digest,
+}
+
+impl SigningMode {
+    /// The digest that is signed for the data in this mode, a digest of the client is only signed with the v1 digest
+    pub fn signed_digest(&self, version: DigestVersion, key_id: &str, data: &str, timestamp: &str) -> Result<Vec<u8>, String> {
+        match self {
+            SigningMode::Hex => {
+                let payload = hex::decode(data).map_err(|_| "The data is not hex encoded")?;
+                Ok(signed_digest(version, key_id, &payload, timestamp))
+            }
+            SigningMode::Base64 => {
+                let payload = STANDARD.decode(data).map_err(|_| "The data is not base64 encoded")?;
+                Ok(signed_digest(version, key_id, &payload, timestamp))
+            }
+            SigningMode::Digest => match version {
+                DigestVersion::Legacy => Err(String::from("Digests of the client are only signed with the v1 digest")),
+                DigestVersion::V1 => Ok(signed_prehashed_digest(key_id, &decode_digest(data)?, timestamp)),
+            },
+        }
+    }
+}
+
+/// A hex encoded sha256 digest
+pub fn decode_digest(digest: &str) -> Result<Vec<u8>, String> {
+    match hex::decode(digest) {
+        Ok(digest) if digest.len() == 32 => Ok(digest),
+        _ => Err(String::from("The digest must be 32 hex encoded bytes")),
+    }
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct EndpointSignatureData {
-    participants: Vec<Participant>,
-    data_to_sign: String,
+    // server ids, their addresses come from the peer registry
+    participants: Vec<u16>,
+    // a single signature signs the data, a batch the root of the entries
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    data_to_sign: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    entries: Option<Vec<String>>,
     timestamp: String,
+    // the key to sign with, the default key if not given
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    key_id: Option<String>,
+    // hex if not given
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    mode: Option<SigningMode>,
+    // chains the record to the previous record of its stream
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    chain: Option<ChainLink>,
+    // set by the coordinator when the participants sign with a precomputed presignature
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    presignature_id: Option<String>,
+    // set by the coordinator of a chained record, the participants that are behind catch up with it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    chain_head: Option<ChainHead>,
 }
 
 impl EndpointSignatureData {
-    pub fn data_to_sign(&self) -> &str {
-        &self.data_to_sign
+    /// Request to sign the bytes with the key, as the client would send it in the hex mode
+    pub fn for_data(participants: &[u16], data: &[u8], timestamp: &str, key_id: &str) -> EndpointSignatureData {
+        EndpointSignatureData {
+            participants: participants.to_vec(),
+            data_to_sign: Some(hex::encode(data)),
+            entries: None,
+            timestamp: timestamp.to_string(),
+            key_id: Some(key_id.to_string()),
+            mode: Some(SigningMode::Hex),
+            chain: None,
+            presignature_id: None,
+            chain_head: None,
+        }
+    }
+
+    /// Request to sign the entries as a batch, in the hex mode
+    pub fn for_entries(participants: &[u16], entries: &[&[u8]], timestamp: &str, key_id: &str) -> EndpointSignatureData {
+        EndpointSignatureData {
+            data_to_sign: None,
+            entries: Some(entries.iter().map(hex::encode).collect()),
+            ..EndpointSignatureData::for_data(participants, &[], timestamp, key_id)
+        }
+    }
+
+    pub fn participants(&self) -> &[u16] {
+        &self.participants
+    }
+    pub fn data_to_sign(&self) -> Option<&str> {
+        self.data_to_sign.as_deref()
+    }
+    pub fn is_batch(&self) -> bool {
+        self.entries.is_some()
     }
     pub fn timestamp(&self) -> &str {
         &self.timestamp
     }
+    pub fn key_id(&self) -> &str {
+        self.key_id.as_deref().unwrap_or(DEFAULT_KEY_ID)
+    }
+    pub fn mode(&self) -> SigningMode {
+        self.mode.unwrap_or_default()
+    }
+    pub fn chain(&self) -> Option<&ChainLink> {
+        self.chain.as_ref()
+    }
+    pub fn presignature_id(&self) -> Option<&str> {
+        self.presignature_id.as_deref()
+    }
+    pub fn chain_head(&self) -> Option<&ChainHead> {
+        self.chain_head.as_ref()
+    }
 
-    pub fn participant_ids(&self) -> Vec<u16> {
-        let mut res: Vec<u16> = Vec::new();
-        for participant in &self.participants {
-            res.push(participant.server_id);
-        }
+    /// The Merkle tree over the digests of the entries, which are encoded as in the mode
+    pub fn batch_tree(&self, version: DigestVersion) -> Result<MerkleTree, String> {
+        let entries = self.entries.as_ref().ok_or("A batch needs entries")?;
+        let digests = entries.iter()
+            .map(|entry| self.mode().signed_digest(version, self.key_id(), entry, &self.timestamp))
+            .collect::<Result<Vec<_>, String>>()?;
 
-        res
+        MerkleTree::new(&digests).map_err(|e| e.to_string())
     }
 
-    pub fn participant_urls(&self) -> Vec<String> {
-        let mut res: Vec<String> = Vec::new();
-        for participant in &self.participants {
-            res.push(participant.url.clone());
+    /// The digest of the data or of the root of the batch, without the position of a chained record
+    pub fn entry_digest(&self, version: DigestVersion) -> Result<Vec<u8>, String> {
+        match (&self.data_to_sign, &self.entries) {
+            (Some(data), None) => self.mode().signed_digest(version, self.key_id(), data, &self.timestamp),
+            (None, Some(_)) => Ok(signed_batch_digest(self.key_id(), &self.batch_tree(version)?.root(), &self.timestamp)),
+            _ => Err(String::from("Either data_to_sign or entries must be given")),
         }
+    }
 
-        res
+    /// The digest that is signed, of the data or of the root of the batch. A chained record
+    /// commits to its position in the stream as well, which has to be filled in
+    pub fn signed_digest(&self, version: DigestVersion) -> Result<Vec<u8>, String> {
+        let digest = self.entry_digest(version)?;
+
+        match (&self.chain, &self.entries) {
+            (None, _) => Ok(digest),
+            (Some(_), Some(_)) => Err(String::from("Batches cannot be chained")),
+            (Some(link), None) => link.signed_digest(self.key_id(), &digest, &self.timestamp).map_err(|e| e.to_string()),
+        }
+    }
+
+    pub fn with_presignature_id(&self, presignature_id: Option<String>) -> EndpointSignatureData {
+        let mut result = self.clone();
+        result.presignature_id = presignature_id;
+
+        result
+    }
+
+    pub fn with_chain(&self, chain: ChainLink) -> EndpointSignatureData {
+        let mut result = self.clone();
+        result.chain = Some(chain);
+
+        result
+    }
+
+    pub fn with_chain_head(&self, chain_head: Option<ChainHead>) -> EndpointSignatureData {
+        let mut result = self.clone();
+        result.chain_head = chain_head;
+
+        result
+    }
+
+    pub fn participant_ids(&self) -> Vec<u16> {
+        self.participants.clone()
+    }
+
+    /// True if the given server is listed among the participants,
+    /// i.e. the server was asked to coordinate the signing
+    pub fn contains_participant(&self, server_id: u16) -> bool {
+        self.participants.contains(&server_id)
+    }
+
+    /// Copy of the signature data without the given server,
+    /// which is the view of the signing that the server itself works with
+    pub fn without_participant(&self, server_id: u16) -> EndpointSignatureData {
+        let mut result = self.clone();
+        result.participants.retain(|p| *p != server_id);
+
+        result
+    }
+}
+
+/// The signature returned to the client, tagged with the mode the data was signed in and the version of the digest
+#[derive(Debug, Clone, Serialize)]
+pub struct SignResponse {
+    #[serde(flatten)]
+    signature: SignatureFormats,
+    mode: SigningMode,
+    digest_version: DigestVersion,
+    // the position of a chained record in its stream
+    #[serde(skip_serializing_if = "Option::is_none")]
+    chain: Option<ChainLink>,
+}
+
+impl SignResponse {
+    pub fn new(signature: SignatureFormats, mode: SigningMode, digest_version: DigestVersion) -> SignResponse {
+        SignResponse { signature, mode, digest_version, chain: None }
+    }
+
+    pub fn with_chain(mut self, chain: Option<ChainLink>) -> SignResponse {
+        self.chain = chain;
+        self
+    }
+}
+
+/// The signature of the root of a batch, with the proofs of the entries in their order
+#[derive(Debug, Clone, Serialize)]
+pub struct BatchSignResponse {
+    #[serde(flatten)]
+    signature: SignResponse,
+    // hex encoded
+    root: String,
+    proofs: Vec<InclusionProof>,
+}
+
+impl BatchSignResponse {
+    pub fn new(signature: SignResponse, tree: &MerkleTree) -> BatchSignResponse {
+        BatchSignResponse { signature, root: hex::encode(tree.root()), proofs: tree.proofs() }
     }
 }
 
@@ -51,16 +251,7 @@ mod tests {
     fn get_testing_data() -> String {
         let data = r#"
         {
-            "participants": [
-                {
-                    "server_id": 1,
-                    "url": "http://127.0.0.1:3001"
-                },
-                {
-                    "server_id": 2,
-                    "url": "http://127.0.0.1:3002"
-                }
-            ],
+            "participants": [1, 2],
             "data_to_sign": "7b736f6d652c6172626974726172792c646174612c746f2c7369676e7d",
             "timestamp": "16816533390"
         }
@@ -76,10 +267,11 @@ mod tests {
 
         if let Ok(json_data) = serde_json::from_str::<EndpointSignatureData>(json_str.as_str()) {
             assert_eq!(json_data.participants.len(), 2);
-            assert_eq!(json_data.participants[0].server_id, 1);
-            assert_eq!(json_data.participants[1].server_id, 2);
-            assert_eq!(json_data.data_to_sign, "7b736f6d652c6172626974726172792c646174612c746f2c7369676e7d");
+            assert_eq!(json_data.participants[0], 1);
+            assert_eq!(json_data.participants[1], 2);
+            assert_eq!(json_data.data_to_sign(), Some("7b736f6d652c6172626974726172792c646174612c746f2c7369676e7d"));
             assert_eq!(json_data.timestamp, "16816533390");
+            assert_eq!(json_data.key_id(), "default");
         }
         else {
             assert!(false);
@@ -101,16 +293,147 @@ mod tests {
     }
 
     #[test]
-    fn test_urls() {
+    fn test_urls_are_rejected() {
+        let json_str = r#"
+        {
+            "participants": [{"server_id": 1, "url": "http://127.0.0.1:3001"}],
+            "data_to_sign": "7b736f6d652c6172626974726172792c646174612c746f2c7369676e7d",
+            "timestamp": "16816533390"
+        }
+        "#;
+
+        assert!(serde_json::from_str::<EndpointSignatureData>(json_str).is_err());
+    }
+
+    #[test]
+    fn test_key_id() {
+        let json_str = r#"
+        {
+            "participants": [1, 2],
+            "data_to_sign": "7b736f6d652c6172626974726172792c646174612c746f2c7369676e7d",
+            "timestamp": "16816533390",
+            "key_id": "tenant-a"
+        }
+        "#;
+        let json_data = serde_json::from_str::<EndpointSignatureData>(json_str).unwrap();
+
+        assert_eq!(json_data.key_id(), "tenant-a");
+        assert_eq!(json_data.without_participant(1).key_id(), "tenant-a");
+    }
+
+    #[test]
+    fn test_contains_participant() {
         let json_str = get_testing_data();
+        let json_data = serde_json::from_str::<EndpointSignatureData>(json_str.as_str()).unwrap();
 
-        if let Ok(json_data) = serde_json::from_str::<EndpointSignatureData>(json_str.as_str()) {
-            let urls = json_data.participant_urls();
-            assert_eq!(urls[0], "http://127.0.0.1:3001");
-            assert_eq!(urls[1], "http://127.0.0.1:3002");
+        assert!(json_data.contains_participant(1));
+        assert!(json_data.contains_participant(2));
+        assert!(!json_data.contains_participant(3));
+    }
+
+    #[test]
+    fn test_without_participant() {
+        let json_str = get_testing_data();
+        let json_data = serde_json::from_str::<EndpointSignatureData>(json_str.as_str()).unwrap();
+
+        let others = json_data.without_participant(1);
+        assert_eq!(others.participant_ids(), vec![2]);
+        assert_eq!(others.data_to_sign(), json_data.data_to_sign());
+        assert_eq!(others.timestamp(), json_data.timestamp());
+    }
+
+    #[test]
+    fn test_modes() {
+        let json_str = r#"
+        {
+            "participants": [1, 2],
+            "data_to_sign": "AP8=",
+            "timestamp": "16816533390",
+            "mode": "base64"
         }
-        else {
-            assert!(false);
+        "#;
+        let json_data = serde_json::from_str::<EndpointSignatureData>(json_str).unwrap();
+        assert_eq!(json_data.mode(), SigningMode::Base64);
+        assert_eq!(json_data.without_participant(1).mode(), SigningMode::Base64);
+
+        // binary data is hashed as bytes in both encodings, text as before
+        let v1 = DigestVersion::V1;
+        let binary = SigningMode::Base64.signed_digest(v1, "default", "AP8=", "16816533390").unwrap();
+        assert_eq!(SigningMode::Hex.signed_digest(v1, "default", "00ff", "16816533390").unwrap(), binary);
+        assert_eq!(hex::encode(SigningMode::Hex.signed_digest(DigestVersion::Legacy, "default", "6c6f67", "1").unwrap()), sha256::digest("log1"));
+
+        // a digest of the client is signed with the key and the timestamp, never as it is
+        let digest = sha256::digest("log1");
+        let prehashed = SigningMode::Digest.signed_digest(v1, "default", &digest, "1").unwrap();
+        assert_eq!(prehashed, signed_prehashed_digest("default", &hex::decode(&digest).unwrap(), "1"));
+        assert_ne!(hex::encode(&prehashed), digest);
+        assert_ne!(SigningMode::Digest.signed_digest(v1, "default", &digest, "2").unwrap(), prehashed);
+        assert!(SigningMode::Digest.signed_digest(DigestVersion::Legacy, "default", &digest, "1").is_err());
+        assert!(SigningMode::Digest.signed_digest(v1, "default", "00ff", "1").is_err());
+        assert!(SigningMode::Hex.signed_digest(v1, "default", "AP8=", "1").is_err());
+        assert!(SigningMode::Base64.signed_digest(v1, "default", "00f", "1").is_err());
+        assert_eq!(serde_json::from_str::<EndpointSignatureData>(get_testing_data().as_str()).unwrap().mode(), SigningMode::Hex);
+    }
+
+    #[test]
+    fn test_batch() {
+        let json_str = r#"
+        {
+            "participants": [1, 2],
+            "entries": ["6c6f6731", "6c6f6732", "6c6f6733"],
+            "timestamp": "16816533390"
         }
+        "#;
+        let json_data = serde_json::from_str::<EndpointSignatureData>(json_str).unwrap();
+        assert!(json_data.is_batch());
+        assert!(json_data.without_participant(1).is_batch());
+
+        // the entries are hashed as single signatures would hash them and the root is signed
+        let v1 = DigestVersion::V1;
+        let tree = json_data.batch_tree(v1).unwrap();
+        let entry = signed_digest(v1, "default", b"log2", "16816533390");
+        let proof = &tree.proofs()[1];
+        assert_eq!(proof.root(&entry).unwrap(), tree.root());
+        assert_eq!(proof.signed_digest("default", &entry, "16816533390").unwrap(), json_data.signed_digest(v1).unwrap());
+
+        let single = serde_json::from_str::<EndpointSignatureData>(get_testing_data().as_str()).unwrap();
+        assert!(!single.is_batch());
+        assert!(single.batch_tree(v1).is_err());
+
+        for invalid in [
+            r#"{"participants": [1, 2], "timestamp": "16816533390"}"#,
+            r#"{"participants": [1, 2], "data_to_sign": "00", "entries": ["00"], "timestamp": "16816533390"}"#,
+            r#"{"participants": [1, 2], "entries": [], "timestamp": "16816533390"}"#,
+            r#"{"participants": [1, 2], "entries": ["zz"], "timestamp": "16816533390"}"#,
+        ] {
+            assert!(serde_json::from_str::<EndpointSignatureData>(invalid).unwrap().signed_digest(v1).is_err());
+        }
+    }
+
+    #[test]
+    fn test_chain() {
+        let json_str = r#"
+        {
+            "participants": [1, 2],
+            "data_to_sign": "6c6f6731",
+            "timestamp": "16816533390",
+            "chain": {"stream_id": "nginx"}
+        }
+        "#;
+        let json_data = serde_json::from_str::<EndpointSignatureData>(json_str).unwrap();
+        assert_eq!(json_data.chain().unwrap().stream_id(), "nginx");
+        // the position is filled in by the servers
+        assert!(json_data.signed_digest(DigestVersion::V1).is_err());
+
+        let link = ChainLink::new("nginx", 0, &[0; 32]);
+        let chained = json_data.with_chain(link.clone());
+        let entry = signed_digest(DigestVersion::V1, "default", b"log1", "16816533390");
+        assert_eq!(chained.signed_digest(DigestVersion::V1).unwrap(), link.signed_digest("default", &entry, "16816533390").unwrap());
+        assert_ne!(chained.signed_digest(DigestVersion::V1).unwrap(), entry);
+        assert_eq!(chained.without_participant(1).chain(), Some(&link));
+
+        let batch = r#"{"participants": [1, 2], "entries": ["00"], "timestamp": "16816533390", "chain": {"stream_id": "nginx"}}"#;
+        let batch = serde_json::from_str::<EndpointSignatureData>(batch).unwrap().with_chain(link);
+        assert!(batch.signed_digest(DigestVersion::V1).is_err());
     }
 }
@@ -0,0 +1,350 @@
+use curv::elliptic::curves::{Point, Secp256k1};
+use serde::{Deserialize, Serialize};
+
+use crate::mpc::operations::check_signature::{verify_signature, DigestVersion};
+use crate::mpc::operations::log_chain::{check_chain, ChainIssue, ChainLink, ChainRecord};
+use crate::mpc::operations::signing::DEFAULT_KEY_ID;
+use crate::mpc::utils::merkle_tree::InclusionProof;
+use crate::mpc::utils::parse_signature_json::{decode_digest, SigningMode};
+use crate::mpc::utils::signature_formats::SignatureEncoding;
+
+/// A signature to verify, either of the data and the timestamp that were signed or of their digest
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct VerifyRequest {
+    // hex encoded
+    signature: String,
+    // detected from the signature if not given
+    #[serde(default)]
+    encoding: Option<SignatureEncoding>,
+    // encoded as in the mode, signed together with the timestamp
+    #[serde(default)]
+    data: Option<String>,
+    // the mode the data was signed in, hex if not given
+    #[serde(default)]
+    mode: Option<SigningMode>,
+    // v1 if not given, legacy for the signatures of older versions
+    #[serde(default)]
+    digest_version: Option<DigestVersion>,
+    #[serde(default)]
+    timestamp: Option<String>,
+    // hex encoded digest the servers computed for the data in the digest version, i.e. for v1 the sha256
+    // of the length-prefixed domain tag, version, key id, timestamp and data. A batch or chain wraps it further
+    #[serde(default)]
+    digest: Option<String>,
+    #[serde(default)]
+    key_id: Option<String>,
+    // proof of an entry of a batch, the signature is of the root of the batch then
+    #[serde(default)]
+    proof: Option<InclusionProof>,
+    // position of a chained record in its stream, as returned by /sign
+    #[serde(default)]
+    chain: Option<ChainLink>,
+}
+
+/// The result of a verification, the reason is set unless the signature is valid
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct VerifyResponse {
+    valid: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    key_id: Option<String>,
+    // hex encoded, compressed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    public_key: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<String>,
+}
+
+impl VerifyRequest {
+    /// Parses the JSON request, or the `{signature},{data},{timestamp}` of older versions,
+    /// which verifies their legacy digest
+    pub fn parse(body: &str) -> Result<VerifyRequest, String> {
+        if body.trim_start().starts_with('{') {
+            return serde_json::from_str(body).map_err(|e| format!("Unable to parse json data: {}", e));
+        }
+
+        match body.trim().split(',').collect::<Vec<&str>>()[..] {
+            [signature, data, timestamp] => Ok(VerifyRequest {
+                signature: signature.to_string(),
+                encoding: None,
+                data: Some(data.to_string()),
+                mode: None,
+                digest_version: Some(DigestVersion::Legacy),
+                timestamp: Some(timestamp.to_string()),
+                digest: None,
+                key_id: None,
+                proof: None,
+                chain: None,
+            }),
+            _ => Err(String::from("Expected a JSON request or {signature},{data},{timestamp}")),
+        }
+    }
+
+    /// The key that signed, the body names it before the query and the default key is used without either
+    pub fn key_id<'a>(&'a self, query_key_id: Option<&'a str>) -> &'a str {
+        self.key_id.as_deref().or(query_key_id).unwrap_or(DEFAULT_KEY_ID)
+    }
+
+    /// The digest that was signed with the key, the data is hashed as in its mode unless the digest is given.
+    /// The digest of an entry of a batch leads to the root of the batch, whose digest was signed
+    /// A chained record commits to its position in the stream as well
+    pub fn signed_digest(&self, key_id: &str) -> Result<Vec<u8>, String> {
+        let digest = self.entry_digest(key_id)?;
+        if self.proof.is_none() && self.chain.is_none() {
+            return Ok(digest);
+        }
+
+        if self.digest_version == Some(DigestVersion::Legacy) {
+            return Err(String::from("Batches and chains are only signed with the v1 digest"));
+        }
+        let timestamp = self.timestamp.as_deref().ok_or("The batch or the chain is signed together with a timestamp, which is missing")?;
+        match (&self.proof, &self.chain) {
+            (Some(proof), None) => proof.signed_digest(key_id, &digest, timestamp),
+            (None, Some(link)) => link.signed_digest(key_id, &digest, timestamp),
+            _ => return Err(String::from("Batches cannot be chained")),
+        }.map_err(|e| format!("{:#}", e))
+    }
+
+    pub fn chain(&self) -> Option<&ChainLink> {
+        self.chain.as_ref()
+    }
+
+    fn entry_digest(&self, key_id: &str) -> Result<Vec<u8>, String> {
+        let mode = self.mode.unwrap_or_default();
+        let version = self.digest_version.unwrap_or_default();
+        match (&self.data, &self.timestamp, &self.digest) {
+            (Some(data), Some(timestamp), None) => mode.signed_digest(version, key_id, data, timestamp),
+            (Some(_), None, None) => Err(String::from("The data is signed together with a timestamp, which is missing")),
+            (None, None, Some(digest)) => decode_digest(digest),
+            // the timestamp of a batch is signed with its root, the one of a chained record with its position
+            (None, Some(_), Some(digest)) if self.proof.is_some() || self.chain.is_some() => decode_digest(digest),
+            _ => Err(String::from("Either the data and the timestamp or the digest must be given")),
+        }
+    }
+
+    /// Checks the signature with the public key, the request was parsed before
+    pub fn verify(&self, key_id: &str, public_key: &Point<Secp256k1>) -> VerifyResponse {
+        let checked = self.signed_digest(key_id).and_then(|digest| {
+            let signature = hex::decode(&self.signature).map_err(|_| "The signature is not hex encoded")?;
+            verify_signature(&signature, self.encoding, &digest, public_key).map_err(|e| format!("{:#}", e))
+        });
+
+        let reason = match checked {
+            Ok(true) => None,
+            Ok(false) => Some(String::from("The signature does not match the data and the public key")),
+            Err(e) => Some(e),
+        };
+        VerifyResponse {
+            valid: reason.is_none(),
+            key_id: Some(key_id.to_string()),
+            public_key: Some(hex::encode(&*public_key.to_bytes(true))),
+            reason,
+        }
+    }
+}
+
+/// Verifies a log of chained records, one JSON verify request per line as for /verify, each with the `chain`
+/// returned by /sign. Every signature is checked with the public key and the records of every stream must form
+/// an unbroken chain, the issues are sorted by their line
+pub fn verify_chained_log(log: &str, key_id: &str, public_key: &Point<Secp256k1>) -> Vec<ChainIssue> {
+    let mut issues = Vec::new();
+    let mut records = Vec::new();
+    for (index, line) in log.lines().enumerate() {
+        let line_number = index + 1;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let request = match VerifyRequest::parse(line) {
+            Ok(request) => request,
+            Err(reason) => {
+                issues.push(ChainIssue::InvalidRecord { line: line_number, reason });
+                continue;
+            }
+        };
+        let key_id = request.key_id(Some(key_id));
+        let record = match (request.verify(key_id, public_key).reason, request.chain()) {
+            (Some(reason), _) => Err(reason),
+            (None, None) => Err(String::from("The record is not chained")),
+            (None, Some(link)) => request.signed_digest(key_id)
+                .and_then(|digest| ChainRecord::new(line_number, link, digest).map_err(|e| e.to_string())),
+        };
+        match record {
+            Ok(record) => records.push(record),
+            Err(reason) => issues.push(ChainIssue::InvalidRecord { line: line_number, reason }),
+        }
+    }
+
+    issues.extend(check_chain(&records));
+    issues.sort_by_key(|issue| issue.line());
+    issues
+}
+
+impl VerifyResponse {
+    /// A request that could not be verified at all
+    pub fn rejected(reason: String) -> VerifyResponse {
+        VerifyResponse { valid: false, key_id: None, public_key: None, reason: Some(reason) }
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.valid
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use curv::elliptic::curves::{Point, Secp256k1};
+    use secp256k1::{Message, PublicKey, SecretKey, SECP256K1};
+    use crate::mpc::operations::check_signature::{signed_batch_digest, signed_digest, signed_prehashed_digest, DigestVersion};
+    use crate::mpc::operations::log_chain::{ChainIssue, ChainLink, GENESIS_DIGEST};
+    use crate::mpc::utils::merkle_tree::MerkleTree;
+    use crate::mpc::utils::parse_verify_json::{verify_chained_log, VerifyRequest};
+
+    fn signed(digest: &[u8]) -> (String, Point<Secp256k1>) {
+        let secret_key = SecretKey::from_slice(&[7u8; 32]).unwrap();
+        let public_key = Point::from_bytes(&PublicKey::from_secret_key(SECP256K1, &secret_key).serialize()).unwrap();
+        let signature = SECP256K1.sign(&Message::from_slice(digest).unwrap(), &secret_key);
+
+        (hex::encode(&*signature.serialize_der()), public_key)
+    }
+
+    #[test]
+    fn data_and_digest_are_verified() {
+        let digest = signed_digest(DigestVersion::V1, "tenant-a", b"log line", "1681402350");
+        let (signature, public_key) = signed(&digest);
+        let data = hex::encode("log line");
+
+        let request = VerifyRequest::parse(&format!(r#"{{"signature": "{}", "data": "{}", "timestamp": "1681402350", "key_id": "tenant-a"}}"#, signature, data)).unwrap();
+        let response = request.verify(request.key_id(None), &public_key);
+        assert!(response.is_valid());
+        assert_eq!(response.key_id.as_deref(), Some("tenant-a"));
+        assert_eq!(response.reason, None);
+        // the key id is signed
+        assert!(!request.verify("default", &public_key).is_valid());
+
+        let request = VerifyRequest::parse(&format!(r#"{{"signature": "{}", "encoding": "der", "digest": "{}"}}"#, signature, hex::encode(&digest))).unwrap();
+        assert_eq!(request.key_id(Some("tenant-b")), "tenant-b");
+        assert!(request.verify("default", &public_key).is_valid());
+
+        let base64 = VerifyRequest::parse(&format!(r#"{{"signature": "{}", "mode": "base64", "data": "bG9nIGxpbmU=", "timestamp": "1681402350"}}"#, signature)).unwrap();
+        assert!(base64.verify("tenant-a", &public_key).is_valid());
+
+        // a digest of the client is signed with the key and the timestamp
+        let client_digest = sha256::digest("log line");
+        let (prehashed, _) = signed(&signed_prehashed_digest("tenant-a", &hex::decode(&client_digest).unwrap(), "1681402350"));
+        let request = format!(r#"{{"signature": "{}", "mode": "digest", "data": "{}""#, prehashed, client_digest);
+        assert!(VerifyRequest::parse(&format!(r#"{}, "timestamp": "1681402350"}}"#, request)).unwrap().verify("tenant-a", &public_key).is_valid());
+        assert!(!VerifyRequest::parse(&format!(r#"{}, "timestamp": "1681402351"}}"#, request)).unwrap().verify("tenant-a", &public_key).is_valid());
+        assert!(!VerifyRequest::parse(&format!("{}}}", request)).unwrap().verify("tenant-a", &public_key).is_valid());
+        // the digest of a record is not valid as a digest of the client
+        let record = VerifyRequest::parse(&format!(r#"{{"signature": "{}", "mode": "digest", "data": "{}", "timestamp": "1681402350"}}"#, signature, hex::encode(&digest))).unwrap();
+        assert!(!record.verify("tenant-a", &public_key).is_valid());
+
+        let other_timestamp = VerifyRequest::parse(&format!(r#"{{"signature": "{}", "data": "{}", "timestamp": "1681402351"}}"#, signature, data)).unwrap();
+        let response = other_timestamp.verify("tenant-a", &public_key);
+        assert!(!response.is_valid());
+        assert!(response.public_key.is_some());
+    }
+
+    #[test]
+    fn legacy_digests_are_verified() {
+        let (signature, public_key) = signed(&signed_digest(DigestVersion::Legacy, "default", b"log line", "1681402350"));
+        let data = hex::encode("log line");
+
+        let legacy = VerifyRequest::parse(&format!("{},{},1681402350", signature, data)).unwrap();
+        assert!(legacy.verify("default", &public_key).is_valid());
+
+        let request = format!(r#"{{"signature": "{}", "data": "{}", "timestamp": "1681402350""#, signature, data);
+        let legacy = VerifyRequest::parse(&format!(r#"{}, "digest_version": "legacy"}}"#, request)).unwrap();
+        assert!(legacy.verify("default", &public_key).is_valid());
+        let v1 = VerifyRequest::parse(&format!("{}}}", request)).unwrap();
+        assert!(!v1.verify("default", &public_key).is_valid());
+    }
+
+    #[test]
+    fn malformed_requests_are_rejected() {
+        let (signature, public_key) = signed(&signed_digest(DigestVersion::V1, "default", b"log line", "1681402350"));
+        let data = hex::encode("log line");
+
+        assert!(VerifyRequest::parse("only,two").is_err());
+        assert!(VerifyRequest::parse(r#"{"signature": "00", "unknown": 1}"#).is_err());
+        assert!(VerifyRequest::parse(r#"{"signature": "00", "encoding": "pem"}"#).is_err());
+        assert!(VerifyRequest::parse(r#"{"signature": "00", "digest_version": "v2"}"#).is_err());
+
+        for body in [
+            format!("zz,{},1681402350", data),
+            format!("{},zz,1681402350", signature),
+            format!(r#"{{"signature": "{}", "data": "{}"}}"#, signature, data),
+            format!(r#"{{"signature": "{}", "data": "{}", "timestamp": "1", "digest": "00"}}"#, signature, data),
+            format!(r#"{{"signature": "{}", "digest": "00"}}"#, signature),
+            format!(r#"{{"signature": "{}", "encoding": "compact", "data": "{}", "timestamp": "1681402350"}}"#, signature, data),
+        ] {
+            let response = VerifyRequest::parse(&body).unwrap().verify("default", &public_key);
+            assert!(!response.is_valid());
+            assert!(response.reason.is_some());
+        }
+    }
+
+    #[test]
+    fn batch_entries_are_verified() {
+        let entries: Vec<Vec<u8>> = ["log1", "log2", "log3"].iter()
+            .map(|entry| signed_digest(DigestVersion::V1, "default", entry.as_bytes(), "1681402350"))
+            .collect();
+        let tree = MerkleTree::new(&entries).unwrap();
+        let (signature, public_key) = signed(&signed_batch_digest("default", &tree.root(), "1681402350"));
+        let proof = serde_json::to_string(&tree.proof(1)).unwrap();
+
+        let request = |fields: &str| VerifyRequest::parse(&format!(r#"{{"signature": "{}", "proof": {}, {}}}"#, signature, proof, fields)).unwrap();
+        assert!(request(&format!(r#""data": "{}", "timestamp": "1681402350""#, hex::encode("log2"))).verify("default", &public_key).is_valid());
+        assert!(request(&format!(r#""digest": "{}", "timestamp": "1681402350""#, hex::encode(&entries[1]))).verify("default", &public_key).is_valid());
+
+        for fields in [
+            format!(r#""data": "{}", "timestamp": "1681402350""#, hex::encode("log3")),
+            format!(r#""data": "{}", "timestamp": "1681402351""#, hex::encode("log2")),
+            format!(r#""digest": "{}""#, hex::encode(&entries[1])),
+            format!(r#""data": "{}", "timestamp": "1681402350", "digest_version": "legacy""#, hex::encode("log2")),
+        ] {
+            let response = request(&fields).verify("default", &public_key);
+            assert!(!response.is_valid());
+            assert!(response.reason.is_some());
+        }
+        // the root is not valid as a single signature
+        let single = VerifyRequest::parse(&format!(r#"{{"signature": "{}", "data": "{}", "timestamp": "1681402350"}}"#, signature, hex::encode(tree.root()))).unwrap();
+        assert!(!single.verify("default", &public_key).is_valid());
+    }
+
+    #[test]
+    fn chained_logs_are_verified() {
+        // records of a stream as the client stores them next to its log lines
+        let mut lines = Vec::new();
+        let mut previous = GENESIS_DIGEST.to_vec();
+        let mut public_key = None;
+        for (sequence, entry) in ["log1", "log2", "log3", "log4"].iter().enumerate() {
+            let link = ChainLink::new("nginx", sequence as u64, &previous);
+            let digest = link.signed_digest("default", &signed_digest(DigestVersion::V1, "default", entry.as_bytes(), "1681402350"), "1681402350").unwrap();
+            let (signature, key) = signed(&digest);
+            lines.push(format!(r#"{{"signature": "{}", "data": "{}", "timestamp": "1681402350", "chain": {}}}"#,
+                               signature, hex::encode(entry), serde_json::to_string(&link).unwrap()));
+            previous = digest;
+            public_key = Some(key);
+        }
+        let public_key = public_key.unwrap();
+
+        let request = VerifyRequest::parse(&lines[1]).unwrap();
+        assert!(request.verify("default", &public_key).is_valid());
+        assert_eq!(request.chain().unwrap().stream_id(), "nginx");
+        assert!(verify_chained_log(&lines.join("\n"), "default", &public_key).is_empty());
+
+        // a dropped record, two records swapped and a record that is not chained
+        let dropped = [&lines[0], &lines[1], &lines[3]].map(|line| line.as_str()).join("\n");
+        assert!(matches!(verify_chained_log(&dropped, "default", &public_key)[..], [ChainIssue::Gap { after: 1, next: 3, .. }]));
+        let swapped = [&lines[0], &lines[2], &lines[1], &lines[3]].map(|line| line.as_str()).join("\n");
+        assert!(matches!(verify_chained_log(&swapped, "default", &public_key)[..], [ChainIssue::Reordered { sequence: 1, .. }]));
+        let unchained = format!("{}\n{}}}", lines[0], lines[1].split(r#", "chain""#).next().unwrap());
+        assert!(matches!(verify_chained_log(&unchained, "default", &public_key)[..], [ChainIssue::InvalidRecord { line: 2, .. }]));
+
+        // the position is signed, a record moved to another position is invalid
+        let moved = lines[1].replace(r#""sequence":1"#, r#""sequence":2"#);
+        assert!(!VerifyRequest::parse(&moved).unwrap().verify("default", &public_key).is_valid());
+    }
+}
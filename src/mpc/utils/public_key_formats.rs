@@ -0,0 +1,142 @@
+use anyhow::{anyhow, bail, Result};
+use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
+use base64::Engine;
+use curv::elliptic::curves::{Point, Secp256k1};
+use serde::Serialize;
+use sha3::{Digest, Keccak256};
+
+// DER of the SubjectPublicKeyInfo up to the point: id-ecPublicKey with the secp256k1 curve and a 65 byte bit string
+const SPKI_PREFIX: [u8; 23] = [
+    0x30, 0x56, 0x30, 0x10, 0x06, 0x07, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01,
+    0x06, 0x05, 0x2b, 0x81, 0x04, 0x00, 0x0a, 0x03, 0x42, 0x00,
+];
+
+/// The public key of a key in the formats that verifiers usually expect
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PublicKeyFormats {
+    // SEC1, hex encoded
+    compressed: String,
+    uncompressed: String,
+    // SubjectPublicKeyInfo
+    pem: String,
+    jwk: Jwk,
+    // EIP-55 checksummed
+    ethereum_address: String,
+}
+
+/// JSON Web Key of a secp256k1 public key (RFC 7517, RFC 8812)
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Jwk {
+    kty: &'static str,
+    crv: &'static str,
+    // base64url encoded coordinates
+    x: String,
+    y: String,
+}
+
+impl PublicKeyFormats {
+    pub fn new(public_key: &Point<Secp256k1>) -> PublicKeyFormats {
+        let uncompressed = uncompressed_bytes(public_key);
+        PublicKeyFormats {
+            compressed: hex::encode(&*public_key.to_bytes(true)),
+            uncompressed: hex::encode(uncompressed),
+            pem: pem(&uncompressed),
+            jwk: Jwk {
+                kty: "EC",
+                crv: "secp256k1",
+                x: URL_SAFE_NO_PAD.encode(&uncompressed[1..33]),
+                y: URL_SAFE_NO_PAD.encode(&uncompressed[33..]),
+            },
+            ethereum_address: ethereum_address(&uncompressed),
+        }
+    }
+}
+
+/// Parses a public key from the PEM returned by the public key endpoint or from its hex encoded SEC1 form
+pub fn parse_public_key(text: &str) -> Result<Point<Secp256k1>> {
+    let text = text.trim();
+    let bytes = match text.strip_prefix("-----BEGIN PUBLIC KEY-----") {
+        Some(pem) => {
+            let encoded: String = pem.trim_end().strip_suffix("-----END PUBLIC KEY-----")
+                .ok_or(anyhow!("The PEM has no end line"))?
+                .split_whitespace()
+                .collect();
+            let der = STANDARD.decode(encoded)?;
+            match der.strip_prefix(&SPKI_PREFIX[..]) {
+                Some(point) => point.to_vec(),
+                None => bail!("The PEM is not a secp256k1 public key"),
+            }
+        }
+        None => hex::decode(text).map_err(|_| anyhow!("The public key is neither PEM nor hex encoded"))?,
+    };
+
+    Point::from_bytes(&bytes).map_err(|_| anyhow!("The public key is not a point of secp256k1"))
+}
+
+// 0x04 followed by the coordinates
+fn uncompressed_bytes(public_key: &Point<Secp256k1>) -> [u8; 65] {
+    let bytes = public_key.to_bytes(false);
+    let mut uncompressed = [4u8; 65];
+    uncompressed[65 - bytes.len()..].copy_from_slice(&bytes);
+    uncompressed
+}
+
+fn pem(uncompressed: &[u8; 65]) -> String {
+    let der = [&SPKI_PREFIX[..], &uncompressed[..]].concat();
+    let encoded = STANDARD.encode(der);
+
+    let mut pem = String::from("-----BEGIN PUBLIC KEY-----\n");
+    for line in encoded.as_bytes().chunks(64) {
+        pem.push_str(std::str::from_utf8(line).unwrap());
+        pem.push('\n');
+    }
+    pem.push_str("-----END PUBLIC KEY-----\n");
+    pem
+}
+
+// the last 20 bytes of the Keccak-256 of the coordinates, a hex digit is upper case if the hash of the address has a high nibble there
+fn ethereum_address(uncompressed: &[u8; 65]) -> String {
+    let address = hex::encode(&Keccak256::digest(&uncompressed[1..])[12..]);
+    let checksum = hex::encode(Keccak256::digest(address.as_bytes()));
+
+    let checksummed: String = address.chars().zip(checksum.chars())
+        .map(|(c, h)| if h >= '8' { c.to_ascii_uppercase() } else { c })
+        .collect();
+    format!("0x{}", checksummed)
+}
+
+#[cfg(test)]
+mod tests {
+    use curv::elliptic::curves::{Point, Secp256k1};
+    use crate::mpc::utils::public_key_formats::{parse_public_key, PublicKeyFormats};
+
+    #[test]
+    fn generator_in_all_formats() {
+        // the public key of the private key 1
+        let formats = PublicKeyFormats::new(&Point::<Secp256k1>::generator().to_point());
+
+        assert_eq!(formats.compressed, "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798");
+        assert_eq!(formats.uncompressed, "0479be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798\
+                                          483ada7726a3c4655da4fbfc0e1108a8fd17b448a68554199c47d08ffb10d4b8");
+        assert_eq!(formats.ethereum_address, "0x7E5F4552091A69125d5DfCb7b8C2659029395Bdf");
+        assert_eq!(formats.jwk.x, "eb5mfvncu6xVoGKVzocLBwKb_NstzijZWfKBWxb4F5g");
+        assert_eq!(formats.jwk.y, "SDradyajxGVdpPv8DhEIqP0XtEimhVQZnEfQj_sQ1Lg");
+        assert_eq!(formats.pem, "-----BEGIN PUBLIC KEY-----\n\
+                                 MFYwEAYHKoZIzj0CAQYFK4EEAAoDQgAEeb5mfvncu6xVoGKVzocLBwKb/NstzijZ\n\
+                                 WfKBWxb4F5hIOtp3JqPEZV2k+/wOEQio/Re0SKaFVBmcR9CP+xDUuA==\n\
+                                 -----END PUBLIC KEY-----\n");
+    }
+
+    #[test]
+    fn exported_public_key_is_parsed() {
+        let generator = Point::<Secp256k1>::generator().to_point();
+        let formats = PublicKeyFormats::new(&generator);
+
+        for exported in [&formats.pem, &formats.compressed, &formats.uncompressed] {
+            assert_eq!(parse_public_key(exported).unwrap(), generator);
+        }
+        assert!(parse_public_key(&formats.pem.replace("-----END PUBLIC KEY-----\n", "")).is_err());
+        assert!(parse_public_key(&formats.jwk.x).is_err());
+        assert!(parse_public_key("02").is_err());
+    }
+}
@@ -0,0 +1,184 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::{
+    aead::{rand_core::RngCore, Aead, AeadCore, KeyInit, OsRng},
+    XChaCha20Poly1305, XNonce,
+};
+use serde::{Deserialize, Serialize};
+
+const ENVELOPE_VERSION: u32 = 1;
+const KDF_ALGORITHM: &str = "argon2id";
+const CIPHER: &str = "xchacha20poly1305";
+const SALT_LENGTH: usize = 16;
+const KEY_LENGTH: usize = 32;
+
+/// Parameters of the key derivation. They are stored in every sealed file,
+/// so files sealed with other parameters can still be opened when the defaults change
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+#[serde(deny_unknown_fields)]
+pub struct KdfParams {
+    algorithm: String,
+    memory_kib: u32,
+    iterations: u32,
+    parallelism: u32,
+    // hex encoded
+    salt: String,
+}
+
+// the sealed form of a file, stored as JSON
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Envelope {
+    version: u32,
+    kdf: KdfParams,
+    cipher: String,
+    // hex encoded
+    nonce: String,
+    ciphertext: String,
+}
+
+/// Encrypts key shares and presignatures with a key derived from a passphrase or the content of a keyfile
+pub struct Sealer {
+    secret: Vec<u8>,
+    // parameters used for sealing, with a salt chosen at startup
+    kdf: KdfParams,
+    // the derivation is slow on purpose, so the keys are derived once per salt
+    keys: Mutex<HashMap<KdfParams, [u8; KEY_LENGTH]>>,
+}
+
+impl Sealer {
+    /// Sealer with the default Argon2id parameters
+    pub fn new(secret: Vec<u8>) -> Result<Sealer> {
+        Sealer::with_params(secret, Params::DEFAULT_M_COST, Params::DEFAULT_T_COST, Params::DEFAULT_P_COST)
+    }
+
+    pub fn with_params(secret: Vec<u8>, memory_kib: u32, iterations: u32, parallelism: u32) -> Result<Sealer> {
+        if secret.is_empty() {
+            bail!("The passphrase or keyfile of the key shares is empty");
+        }
+
+        let mut salt = [0u8; SALT_LENGTH];
+        OsRng.fill_bytes(&mut salt);
+        let sealer = Sealer {
+            secret,
+            kdf: KdfParams {
+                algorithm: KDF_ALGORITHM.to_string(),
+                memory_kib,
+                iterations,
+                parallelism,
+                salt: hex::encode(salt),
+            },
+            keys: Mutex::new(HashMap::new()),
+        };
+        // fails early on invalid parameters
+        sealer.key(&sealer.kdf)?;
+
+        Ok(sealer)
+    }
+
+    pub fn seal(&self, plaintext: &[u8]) -> Result<Vec<u8>> {
+        let cipher = XChaCha20Poly1305::new(&self.key(&self.kdf)?.into());
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = cipher.encrypt(&nonce, plaintext)
+            .map_err(|_| anyhow!("Unable to encrypt"))?;
+
+        let envelope = Envelope {
+            version: ENVELOPE_VERSION,
+            kdf: self.kdf.clone(),
+            cipher: CIPHER.to_string(),
+            nonce: hex::encode(nonce),
+            ciphertext: hex::encode(ciphertext),
+        };
+        Ok(serde_json::to_vec_pretty(&envelope)?)
+    }
+
+    pub fn unseal(&self, sealed: &[u8]) -> Result<Vec<u8>> {
+        let envelope: Envelope = serde_json::from_slice(sealed).context("Not a sealed file")?;
+        if envelope.version != ENVELOPE_VERSION {
+            bail!("Unsupported version {} of the sealed file", envelope.version);
+        }
+        if envelope.cipher != CIPHER {
+            bail!("Unsupported cipher {}", envelope.cipher);
+        }
+
+        let nonce = hex::decode(&envelope.nonce).context("Invalid nonce")?;
+        if nonce.len() != 24 {
+            bail!("Invalid nonce length {}", nonce.len());
+        }
+        let ciphertext = hex::decode(&envelope.ciphertext).context("Invalid ciphertext")?;
+
+        let cipher = XChaCha20Poly1305::new(&self.key(&envelope.kdf)?.into());
+        cipher.decrypt(XNonce::from_slice(&nonce), ciphertext.as_slice())
+            .map_err(|_| anyhow!("Unable to decrypt, the passphrase or keyfile is wrong or the file was modified"))
+    }
+
+    fn key(&self, kdf: &KdfParams) -> Result<[u8; KEY_LENGTH]> {
+        if let Some(key) = self.keys.lock().unwrap().get(kdf) {
+            return Ok(*key);
+        }
+
+        if kdf.algorithm != KDF_ALGORITHM {
+            bail!("Unsupported key derivation {}", kdf.algorithm);
+        }
+        let salt = hex::decode(&kdf.salt).context("Invalid salt")?;
+        let params = Params::new(kdf.memory_kib, kdf.iterations, kdf.parallelism, Some(KEY_LENGTH))
+            .map_err(|e| anyhow!("Invalid key derivation parameters: {}", e))?;
+
+        let mut key = [0u8; KEY_LENGTH];
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password_into(&self.secret, &salt, &mut key)
+            .map_err(|e| anyhow!("Key derivation failed: {}", e))?;
+
+        self.keys.lock().unwrap().insert(kdf.clone(), key);
+        Ok(key)
+    }
+}
+
+/// Whether the content is a sealed file, files written before the encryption are plain JSON
+pub fn is_sealed(content: &[u8]) -> bool {
+    serde_json::from_slice::<Envelope>(content).is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mpc::utils::sealing::{is_sealed, Sealer};
+
+    fn sealer(secret: &str) -> Sealer {
+        Sealer::with_params(secret.as_bytes().to_vec(), 64, 1, 1).unwrap()
+    }
+
+    #[test]
+    fn sealed_content_is_unsealed() {
+        let sealer = sealer("correct horse");
+        let sealed = sealer.seal(b"{\"share\":1}").unwrap();
+
+        assert!(is_sealed(&sealed));
+        assert!(!is_sealed(b"{\"share\":1}"));
+        assert!(!String::from_utf8_lossy(&sealed).contains("share"));
+        assert_eq!(sealer.unseal(&sealed).unwrap(), b"{\"share\":1}");
+
+        // another run derives the key again from the parameters stored in the file
+        assert_eq!(self::sealer("correct horse").unseal(&sealed).unwrap(), b"{\"share\":1}");
+    }
+
+    #[test]
+    fn wrong_secret_or_modified_file_is_rejected() {
+        let sealed = sealer("correct horse").seal(b"share").unwrap();
+        assert!(sealer("battery staple").unseal(&sealed).is_err());
+
+        let mut envelope: serde_json::Value = serde_json::from_slice(&sealed).unwrap();
+        let mut ciphertext = hex::decode(envelope["ciphertext"].as_str().unwrap()).unwrap();
+        ciphertext[0] ^= 1;
+        envelope["ciphertext"] = serde_json::Value::from(hex::encode(ciphertext));
+        assert!(sealer("correct horse").unseal(&serde_json::to_vec(&envelope).unwrap()).is_err());
+
+        envelope["version"] = serde_json::Value::from(2);
+        assert!(sealer("correct horse").unseal(&serde_json::to_vec(&envelope).unwrap()).is_err());
+        assert!(Sealer::new(Vec::new()).is_err());
+    }
+}
@@ -0,0 +1,143 @@
+use anyhow::{anyhow, bail, Context, Result};
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+    XChaCha20Poly1305, XNonce,
+};
+use curv::elliptic::curves::{Point, Scalar, Secp256k1};
+use serde::{Deserialize, Serialize};
+
+/// The value of a polynomial for a party, encrypted to a public key of the party.
+/// Every party receives all the messages of a room, so the values are never sent in plain
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct EncryptedShare {
+    receiver: u16,
+    ephemeral_key: Point<Secp256k1>,
+    // hex encoded
+    nonce: String,
+    ciphertext: String,
+}
+
+impl EncryptedShare {
+    pub fn receiver(&self) -> u16 {
+        self.receiver
+    }
+}
+
+/// The value of the polynomial with the given coefficients, the first one is the constant term
+pub fn evaluate(coefficients: &[Scalar<Secp256k1>], index: u16) -> Scalar<Secp256k1> {
+    let x = Scalar::from(index);
+    let mut power = Scalar::from(1u16);
+    let mut value = Scalar::zero();
+    for coefficient in coefficients {
+        value = &value + &(coefficient * &power);
+        power = &power * &x;
+    }
+    value
+}
+
+/// The generator times the value of the polynomial, computed from the commitments to its coefficients
+pub fn evaluate_commitments(commitments: &[Point<Secp256k1>], index: u16) -> Point<Secp256k1> {
+    let x = Scalar::from(index);
+    let mut power = Scalar::from(1u16);
+    let mut value = Point::zero();
+    for commitment in commitments {
+        value = &value + &(commitment * &power);
+        power = &power * &x;
+    }
+    value
+}
+
+/// The Lagrange coefficient of the party at the index, to interpolate the constant term from the values at the indices
+pub fn lagrange_coefficient(index: u16, indices: &[u16]) -> Result<Scalar<Secp256k1>> {
+    let mut sorted = indices.to_vec();
+    sorted.sort();
+    sorted.dedup();
+    if sorted.len() != indices.len() || !indices.contains(&index) || indices.contains(&0) {
+        bail!("Invalid indices {:?} to interpolate at {}", indices, index);
+    }
+
+    let x = Scalar::from(index);
+    let mut coefficient = Scalar::from(1u16);
+    for other in indices.iter().filter(|other| **other != index) {
+        let x_other = Scalar::from(*other);
+        let denominator = (&x_other - &x).invert().ok_or(anyhow!("Index {} is used twice", other))?;
+        coefficient = &coefficient * &(&x_other * &denominator);
+    }
+    Ok(coefficient)
+}
+
+/// ECIES with an ephemeral key, the protocol, the sender and the receiver are bound to the ciphertext
+pub fn encrypt_share(protocol: &str, sender: u16, receiver: u16, public_key: &Point<Secp256k1>, share: &Scalar<Secp256k1>) -> Result<EncryptedShare> {
+    let ephemeral_secret = Scalar::<Secp256k1>::random();
+    let cipher = share_cipher(&(public_key * &ephemeral_secret))?;
+    let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let share_bytes = share.to_bytes();
+    let ciphertext = cipher.encrypt(&nonce, Payload { msg: &share_bytes, aad: &associated_data(protocol, sender, receiver) })
+        .map_err(|_| anyhow!("Unable to encrypt the share"))?;
+
+    Ok(EncryptedShare {
+        receiver,
+        ephemeral_key: Point::generator() * &ephemeral_secret,
+        nonce: hex::encode(nonce),
+        ciphertext: hex::encode(ciphertext),
+    })
+}
+
+/// Decrypts the share with the secret key of its receiver
+pub fn decrypt_share(protocol: &str, sender: u16, secret_key: &Scalar<Secp256k1>, encrypted: &EncryptedShare) -> Result<Scalar<Secp256k1>> {
+    let nonce = hex::decode(&encrypted.nonce).context("Invalid nonce")?;
+    if nonce.len() != 24 {
+        bail!("Invalid nonce length {}", nonce.len());
+    }
+    let ciphertext = hex::decode(&encrypted.ciphertext).context("Invalid ciphertext")?;
+
+    let cipher = share_cipher(&(&encrypted.ephemeral_key * secret_key))?;
+    let aad = associated_data(protocol, sender, encrypted.receiver);
+    let share = cipher.decrypt(XNonce::from_slice(&nonce), Payload { msg: &ciphertext, aad: &aad })
+        .map_err(|_| anyhow!("Unable to decrypt the share"))?;
+
+    Scalar::from_bytes(&share).map_err(|e| anyhow!("Invalid share: {}", e))
+}
+
+fn share_cipher(shared_point: &Point<Secp256k1>) -> Result<XChaCha20Poly1305> {
+    let key = hex::decode(sha256::digest(&*shared_point.to_bytes(true)))?;
+    XChaCha20Poly1305::new_from_slice(&key).map_err(|_| anyhow!("Invalid share key"))
+}
+
+fn associated_data(protocol: &str, sender: u16, receiver: u16) -> Vec<u8> {
+    format!("{} share from {} to {}", protocol, sender, receiver).into_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use curv::elliptic::curves::{Point, Scalar, Secp256k1};
+    use crate::mpc::utils::shares::{decrypt_share, encrypt_share, evaluate, evaluate_commitments, lagrange_coefficient};
+
+    #[test]
+    fn encrypted_share_is_only_readable_by_its_receiver() {
+        let secret_key = Scalar::<Secp256k1>::random();
+        let coefficients = vec![Scalar::<Secp256k1>::random(), Scalar::random()];
+        let commitments: Vec<Point<Secp256k1>> = coefficients.iter().map(|a| Point::generator() * a).collect();
+        let share = evaluate(&coefficients, 3);
+        assert_eq!(Point::generator() * &share, evaluate_commitments(&commitments, 3));
+
+        let encrypted = encrypt_share("reshare", 1, 3, &(Point::generator() * &secret_key), &share).unwrap();
+        assert_eq!(decrypt_share("reshare", 1, &secret_key, &encrypted).unwrap(), share);
+        assert!(decrypt_share("reshare", 1, &Scalar::random(), &encrypted).is_err());
+        assert!(decrypt_share("reshare", 2, &secret_key, &encrypted).is_err());
+        assert!(decrypt_share("refresh", 1, &secret_key, &encrypted).is_err());
+    }
+
+    #[test]
+    fn constant_term_is_interpolated() {
+        let coefficients = vec![Scalar::<Secp256k1>::random(), Scalar::random(), Scalar::random()];
+        let indices = [1, 3, 4];
+        let mut constant = Scalar::zero();
+        for index in indices {
+            constant = &constant + &(&lagrange_coefficient(index, &indices).unwrap() * &evaluate(&coefficients, index));
+        }
+        assert_eq!(constant, coefficients[0]);
+        assert!(lagrange_coefficient(1, &[1, 3, 3]).is_err());
+        assert!(lagrange_coefficient(2, &[1, 3]).is_err());
+    }
+}
@@ -0,0 +1,196 @@
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail, Context, Result};
+use curv::elliptic::curves::{Scalar, Secp256k1};
+use multi_party_ecdsa::protocols::multi_party_ecdsa::gg_2020::party_i::SignatureRecid;
+use secp256k1::Signature;
+use serde::{Deserialize, Serialize};
+
+use crate::mpc::operations::check_signature::extract_rs;
+
+/// A signature in the encodings that verifiers usually expect. The scalars of curv are kept
+/// next to them, so that clients which parse them keep working
+#[derive(Debug, Clone, Serialize)]
+pub struct SignatureFormats {
+    #[serde(flatten)]
+    signature: SignatureRecid,
+    // hex encoded
+    der: String,
+    // r || s
+    compact: String,
+    // r || s || v, where v is the recovery id 0 or 1
+    recoverable: String,
+}
+
+impl SignatureFormats {
+    pub fn new(signature: &SignatureRecid) -> SignatureFormats {
+        let signature = normalize_s(signature);
+        let compact = compact_bytes(&signature.r, &signature.s);
+        // r and s are scalars, so they are in range
+        let der = Signature::from_compact(&compact).unwrap().serialize_der();
+
+        SignatureFormats {
+            der: hex::encode(&*der),
+            compact: hex::encode(compact),
+            recoverable: hex::encode([&compact[..], &[signature.recid]].concat()),
+            signature,
+        }
+    }
+
+    pub fn der(&self) -> &str {
+        &self.der
+    }
+}
+
+/// The same signature with s in the lower half of the group order, which flips the recovery id
+pub fn normalize_s(signature: &SignatureRecid) -> SignatureRecid {
+    if is_low_s(&signature.s) {
+        return signature.clone();
+    }
+
+    SignatureRecid {
+        r: signature.r.clone(),
+        s: -&signature.s,
+        recid: signature.recid ^ 1,
+    }
+}
+
+/// True if s is in the lower half of the group order, the other s of the same signature is in the upper half
+pub fn is_low_s(s: &Scalar<Secp256k1>) -> bool {
+    s.to_bigint() <= (-s).to_bigint()
+}
+
+/// The encodings of a signature that are accepted for verification
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SignatureEncoding {
+    Der,
+    Compact,
+    Recoverable,
+    // the JSON of curv returned by older versions
+    Json,
+}
+
+impl SignatureEncoding {
+    /// The encoding of the signature as far as its bytes tell
+    pub fn detect(bytes: &[u8]) -> Result<SignatureEncoding> {
+        if bytes.first() == Some(&b'{') {
+            return Ok(SignatureEncoding::Json);
+        }
+        if Signature::from_der(bytes).is_ok() {
+            return Ok(SignatureEncoding::Der);
+        }
+        match bytes.len() {
+            64 => Ok(SignatureEncoding::Compact),
+            65 => Ok(SignatureEncoding::Recoverable),
+            length => bail!("A signature of {} bytes is neither DER, compact, recoverable nor JSON", length),
+        }
+    }
+}
+
+impl FromStr for SignatureEncoding {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<SignatureEncoding, String> {
+        serde_json::from_value(serde_json::Value::from(name))
+            .map_err(|_| format!("Unknown signature encoding {}, expected der, compact, recoverable or json", name))
+    }
+}
+
+/// r and s of a signature in the given encoding. A signature with a high s is rejected, since it is
+/// the malleated twin of the low-S signature that the servers return
+pub fn parse_signature(bytes: &[u8], encoding: SignatureEncoding) -> Result<(Scalar<Secp256k1>, Scalar<Secp256k1>)> {
+    let signature = match encoding {
+        SignatureEncoding::Json => None,
+        SignatureEncoding::Der => Some(Signature::from_der(bytes).context("Parse DER signature")?),
+        SignatureEncoding::Compact if bytes.len() == 64 => Some(Signature::from_compact(bytes)?),
+        // Ethereum adds 27 to the recovery id
+        SignatureEncoding::Recoverable if bytes.len() == 65 => match bytes[64] {
+            0 | 1 | 27 | 28 => Some(Signature::from_compact(&bytes[..64])?),
+            recid => bail!("Invalid recovery id {}", recid),
+        },
+        _ => bail!("A {:?} signature cannot have {} bytes", encoding, bytes.len()),
+    };
+
+    let (r, s) = match signature {
+        None => extract_rs(std::str::from_utf8(bytes)?)?,
+        Some(signature) => {
+            let compact = signature.serialize_compact();
+            let scalar = |bytes: &[u8]| Scalar::from_bytes(bytes).map_err(|_| anyhow!("r and s must be below the group order"));
+            (scalar(&compact[..32])?, scalar(&compact[32..])?)
+        }
+    };
+    if !is_low_s(&s) {
+        bail!("The s of the signature is in the upper half of the group order, only low-S signatures are valid");
+    }
+    Ok((r, s))
+}
+
+/// r || s, both 32 bytes big endian
+pub fn compact_bytes(r: &Scalar<Secp256k1>, s: &Scalar<Secp256k1>) -> [u8; 64] {
+    let mut compact = [0u8; 64];
+    compact[..32].copy_from_slice(&r.to_bytes());
+    compact[32..].copy_from_slice(&s.to_bytes());
+    compact
+}
+
+#[cfg(test)]
+mod tests {
+    use curv::arithmetic::Converter;
+    use curv::elliptic::curves::{Point, Scalar, Secp256k1};
+    use curv::BigInt;
+    use multi_party_ecdsa::protocols::multi_party_ecdsa::gg_2020::party_i::SignatureRecid;
+    use secp256k1::{Message, PublicKey, SecretKey, SECP256K1};
+    use crate::mpc::operations::check_signature::check_sig;
+    use crate::mpc::utils::signature_formats::{parse_signature, SignatureEncoding, SignatureFormats};
+
+    #[test]
+    fn all_encodings_verify() {
+        let secret_key = SecretKey::from_slice(&[7u8; 32]).unwrap();
+        let public_key: Point<Secp256k1> = Point::from_bytes(&PublicKey::from_secret_key(SECP256K1, &secret_key).serialize()).unwrap();
+        let hash = [42u8; 32];
+        let compact = SECP256K1.sign(&Message::from_slice(&hash).unwrap(), &secret_key).serialize_compact();
+        let r = Scalar::<Secp256k1>::from_bytes(&compact[..32]).unwrap();
+        let s = Scalar::<Secp256k1>::from_bytes(&compact[32..]).unwrap();
+
+        // the same signature with the high s, which libsecp256k1 rejects
+        let high_s = SignatureRecid { r: r.clone(), s: -&s, recid: 1 };
+        let formats = SignatureFormats::new(&high_s);
+        assert_eq!(formats.signature.s, s);
+        assert_eq!(formats.signature.recid, 0);
+        assert_eq!(formats.compact, hex::encode(compact));
+        assert_eq!(formats.recoverable, format!("{}00", hex::encode(compact)));
+        assert!(formats.der.starts_with("30"));
+
+        let legacy = serde_json::to_vec(&high_s).unwrap();
+        let high_s_compact = [&compact[..32], &*(-&s).to_bytes()].concat();
+        let mut ethereum = hex::decode(&formats.recoverable).unwrap();
+        ethereum[64] = 27;
+
+        let message = BigInt::from_bytes(&hash);
+        let low_s_legacy = serde_json::to_vec(&formats.signature).unwrap();
+        for encoded in [hex::decode(&formats.der).unwrap(), compact.to_vec(), hex::decode(&formats.recoverable).unwrap(),
+                        ethereum, low_s_legacy] {
+            let encoding = SignatureEncoding::detect(&encoded).unwrap();
+            let (parsed_r, parsed_s) = parse_signature(&encoded, encoding).unwrap();
+            assert_eq!((&parsed_r, &parsed_s), (&r, &s));
+            assert!(check_sig(&parsed_r, &parsed_s, &message, &public_key));
+        }
+
+        // the malleated signature is rejected in every encoding, and not verified either
+        for (encoded, encoding) in [(legacy, SignatureEncoding::Json), (high_s_compact.clone(), SignatureEncoding::Compact),
+                                    ([&high_s_compact[..], &[1]].concat(), SignatureEncoding::Recoverable)] {
+            assert!(parse_signature(&encoded, encoding).is_err());
+        }
+        let high_s_der = secp256k1::Signature::from_compact(&high_s_compact).unwrap().serialize_der();
+        assert!(parse_signature(&high_s_der, SignatureEncoding::Der).is_err());
+        assert!(!check_sig(&r, &-&s, &message, &public_key));
+
+        let mut invalid_recovery_id = hex::decode(&formats.recoverable).unwrap();
+        invalid_recovery_id[64] = 2;
+        assert!(parse_signature(&invalid_recovery_id, SignatureEncoding::Recoverable).is_err());
+        assert!(parse_signature(&compact, SignatureEncoding::Recoverable).is_err());
+        assert!(SignatureEncoding::detect(&compact[..63]).is_err());
+        assert!(parse_signature(b"{\"r\": 1}", SignatureEncoding::Json).is_err());
+    }
+}
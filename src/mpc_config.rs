@@ -1,8 +1,64 @@
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::communication::phase::Phase;
+
+/// Deadlines for joining a room and for running each phase of the protocol
+#[derive(Clone, Debug)]
+pub struct ProtocolTimeouts {
+    join: Duration,
+    keygen: Duration,
+    offline: Duration,
+    online: Duration,
+}
+
+impl ProtocolTimeouts {
+    pub fn new(join: Duration, keygen: Duration, offline: Duration, online: Duration) -> ProtocolTimeouts {
+        ProtocolTimeouts {
+            join,
+            keygen,
+            offline,
+            online,
+        }
+    }
+
+    pub fn join(&self) -> Duration {
+        self.join
+    }
+
+    /// The longest a signature can take: joining the rooms of the offline and the online stage and running both
+    pub fn signing(&self) -> Duration {
+        self.join * 2 + self.offline + self.online
+    }
+
+    pub fn for_phase(&self, phase: Phase) -> Duration {
+        match phase {
+            Phase::KeyGen | Phase::KeyConfirmation | Phase::Refresh | Phase::Reshare => self.keygen,
+            Phase::Offline | Phase::Presign => self.offline,
+            Phase::Online => self.online,
+        }
+    }
+}
+
+impl Default for ProtocolTimeouts {
+    fn default() -> Self {
+        ProtocolTimeouts::new(
+            Duration::from_secs(30),
+            Duration::from_secs(300),
+            Duration::from_secs(60),
+            Duration::from_secs(30),
+        )
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct MPCconfig {
     server_id: u16,
     threshold: u16,
     number_of_parties: u16,
+    timeouts: ProtocolTimeouts,
+    presignature_pool_depth: usize,
+    key_share_dir: PathBuf,
 }
 
 impl MPCconfig {
@@ -10,10 +66,28 @@ impl MPCconfig {
         MPCconfig{
             server_id: si,
             threshold: t,
-            number_of_parties: n
+            number_of_parties: n,
+            timeouts: ProtocolTimeouts::default(),
+            presignature_pool_depth: 4,
+            key_share_dir: PathBuf::from("."),
         }
     }
 
+    pub fn with_timeouts(mut self, timeouts: ProtocolTimeouts) -> MPCconfig {
+        self.timeouts = timeouts;
+        self
+    }
+
+    pub fn with_presignature_pool_depth(mut self, depth: usize) -> MPCconfig {
+        self.presignature_pool_depth = depth;
+        self
+    }
+
+    pub fn with_key_share_dir(mut self, dir: PathBuf) -> MPCconfig {
+        self.key_share_dir = dir;
+        self
+    }
+
     pub fn server_id(&self) -> u16 {
         self.server_id
     }
@@ -23,4 +97,21 @@ impl MPCconfig {
     pub fn number_of_parties(&self) -> u16 {
         self.number_of_parties
     }
-}
\ No newline at end of file
+    pub fn timeouts(&self) -> &ProtocolTimeouts {
+        &self.timeouts
+    }
+    pub fn presignature_pool_depth(&self) -> usize {
+        self.presignature_pool_depth
+    }
+    pub fn key_share_dir(&self) -> &Path {
+        &self.key_share_dir
+    }
+    /// Directory with the presignatures of this server
+    pub fn presignature_dir(&self) -> PathBuf {
+        self.key_share_dir.join(format!("presignatures{}", self.server_id))
+    }
+    /// File with the chain heads of the streams this server signed
+    pub fn chain_file(&self) -> PathBuf {
+        self.key_share_dir.join(format!("chains{}.json", self.server_id))
+    }
+}
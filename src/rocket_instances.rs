@@ -1,9 +1,11 @@
 // First code snippet
+use std::path::Path;
 use std::sync::{Arc, Mutex};
 use rocket::Build;
 use rocket::config::{TlsConfig, MutualTls};
 
-use crate::endpoints::pub_endpoints::{key_gen, sign, verify, receive_broadcast};
+use crate::endpoints::pub_endpoints::{key_gen, keys, public_key, refresh, reshare, sign, sign_batch, ingest, chain_head, verify, receive_broadcast, ready, sign_invitation};
+use crate::endpoints::presigning::presign_invitation;
 
 use crate::communication::create_communication_channel;
 
@@ -40,12 +42,13 @@ pub fn rocket_with_client_auth(
     config: MPCconfig,
     db: SharedDb,
     port: u16,
+    cert_dir: &Path,
 ) -> rocket::Rocket<Build> {
-    let public_cert = format!("certs/public/cert_{}.pem", config.server_id());
-    let private_cert = format!("certs/private/private_key_{}.pem", config.server_id());
+    let public_cert = cert_dir.join(format!("public/cert_{}.pem", config.server_id()));
+    let private_cert = cert_dir.join(format!("private/private_key_{}.pem", config.server_id()));
 
     let tls_config = TlsConfig::from_paths(public_cert, private_cert)
-        .with_mutual(MutualTls::from_path("certs/ca_cert.pem").mandatory(true))
+        .with_mutual(MutualTls::from_path(cert_dir.join("ca_cert.pem")).mandatory(true))
     ;
 
 
@@ -55,7 +58,7 @@ pub fn rocket_with_client_auth(
         .merge(("port", port));
 
     rocket::custom(figment)
-        .mount("/", rocket::routes![receive_broadcast])
+        .mount("/", rocket::routes![receive_broadcast, ready, sign_invitation, presign_invitation, chain_head])
         .manage(ServerConfigState { config: Mutex::new(config)})
         .manage(db)
 }
@@ -70,7 +73,7 @@ pub fn rocket_without_client_auth(
 
     rocket::custom(figment)
         .mount("/",
-               rocket::routes![key_gen, sign, verify])
+               rocket::routes![key_gen, keys, public_key, refresh, reshare, sign, sign_batch, ingest, chain_head, verify])
         .manage(ServerConfigState { config: Mutex::new(config)})
         .manage(db)
 }
\ No newline at end of file
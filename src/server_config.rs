@@ -0,0 +1,509 @@
+use std::{
+    collections::HashSet,
+    net::{IpAddr, Ipv4Addr},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use clap::Parser;
+use rocket::data::ByteUnit;
+use serde::Deserialize;
+
+use crate::communication::phase::Phase;
+use crate::mpc_config::{MPCconfig, ProtocolTimeouts};
+
+const DEFAULT_PRESIGNATURE_POOL_DEPTH: usize = 4;
+const DEFAULT_WORKERS: usize = 4;
+const DEFAULT_JSON_LIMIT: &str = "1 GiB";
+
+/// Command line of the server. Every option can also be set by its environment variable
+/// or in the configuration file, the command line wins over the environment and the environment over the file.
+#[derive(Parser, Debug, Default)]
+#[command(name = "log-signing-mpc", version, about = "Threshold ECDSA signing server")]
+pub struct Cli {
+    /// Path to the TOML configuration file
+    #[arg(short, long, env = "MPC_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    /// Id of this server, from 1 to the number of parties
+    #[arg(long, env = "MPC_SERVER_ID")]
+    pub server_id: Option<u16>,
+
+    /// Port of the public HTTP interface [default: 8000 + server id]
+    #[arg(long, env = "MPC_PORT")]
+    pub port: Option<u16>,
+
+    /// Port of the mutual TLS interface used by the other servers [default: 3000 + server id]
+    #[arg(long, env = "MPC_TLS_PORT")]
+    pub tls_port: Option<u16>,
+
+    /// Threshold of the signing, threshold + 1 parties are needed to sign
+    #[arg(long, env = "MPC_THRESHOLD")]
+    pub threshold: Option<u16>,
+
+    /// Number of parties that share the key
+    #[arg(long, env = "MPC_NUMBER_OF_PARTIES")]
+    pub number_of_parties: Option<u16>,
+
+    /// Address both interfaces bind to [default: 0.0.0.0]
+    #[arg(long, env = "MPC_BIND_ADDRESS")]
+    pub bind_address: Option<IpAddr>,
+
+    /// Number of worker threads of each interface [default: 4]
+    #[arg(long, env = "MPC_WORKERS")]
+    pub workers: Option<usize>,
+
+    /// Size limit of the JSON bodies, e.g. "10 MiB" [default: 1 GiB]
+    #[arg(long, env = "MPC_JSON_LIMIT")]
+    pub json_limit: Option<String>,
+
+    /// Directory with the CA, server and client certificates [default: certs]
+    #[arg(long, env = "MPC_CERT_DIR")]
+    pub cert_dir: Option<PathBuf>,
+
+    /// Directory with the key shares and the presignatures [default: .]
+    #[arg(long, env = "MPC_KEY_SHARE_DIR")]
+    pub key_share_dir: Option<PathBuf>,
+
+    /// Passphrase the key share and the presignatures are encrypted with, prefer the environment variable.
+    /// Not allowed in the configuration file
+    #[arg(long, env = "MPC_KEY_SHARE_PASSPHRASE", hide_env_values = true, conflicts_with = "key_share_keyfile")]
+    pub key_share_passphrase: Option<String>,
+
+    /// File whose content the key share and the presignatures are encrypted with, instead of a passphrase
+    #[arg(long, env = "MPC_KEY_SHARE_KEYFILE")]
+    pub key_share_keyfile: Option<PathBuf>,
+
+    /// Seconds to wait for the other parties to join a room [default: 30]
+    #[arg(long, env = "MPC_JOIN_TIMEOUT")]
+    pub join_timeout: Option<u64>,
+
+    /// Seconds the key generation may take [default: 300]
+    #[arg(long, env = "MPC_KEYGEN_TIMEOUT")]
+    pub keygen_timeout: Option<u64>,
+
+    /// Seconds the offline stage of the signing may take [default: 60]
+    #[arg(long, env = "MPC_OFFLINE_TIMEOUT")]
+    pub offline_timeout: Option<u64>,
+
+    /// Seconds the online stage of the signing may take [default: 30]
+    #[arg(long, env = "MPC_ONLINE_TIMEOUT")]
+    pub online_timeout: Option<u64>,
+
+    /// Number of presignatures kept for each set of participants, 0 turns the precomputation off [default: 4]
+    #[arg(long, env = "MPC_PRESIGNATURE_POOL_DEPTH")]
+    pub presignature_pool_depth: Option<usize>,
+
+    /// Another server as ID=ADDRESS, e.g. 2=127.0.0.1:3002, replaces the peers of the configuration file.
+    /// Its certificate is expected in public/cert_ID.pem of the certificate directory
+    #[arg(long = "peer", env = "MPC_PEERS", value_delimiter = ',', value_parser = parse_peer)]
+    pub peers: Vec<PeerConfig>,
+}
+
+/// Content of the configuration file, every field is optional
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigFile {
+    server_id: Option<u16>,
+    port: Option<u16>,
+    tls_port: Option<u16>,
+    threshold: Option<u16>,
+    number_of_parties: Option<u16>,
+    bind_address: Option<IpAddr>,
+    workers: Option<usize>,
+    json_limit: Option<String>,
+    cert_dir: Option<PathBuf>,
+    key_share_dir: Option<PathBuf>,
+    key_share_keyfile: Option<PathBuf>,
+    presignature_pool_depth: Option<usize>,
+    #[serde(default)]
+    timeouts: TimeoutsFile,
+    #[serde(default)]
+    peers: Vec<PeerConfig>,
+}
+
+/// Deadlines in seconds, the `[timeouts]` table of the configuration file
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+struct TimeoutsFile {
+    join: Option<u64>,
+    keygen: Option<u64>,
+    offline: Option<u64>,
+    online: Option<u64>,
+}
+
+/// Another server of the deployment, a `[[peers]]` entry of the configuration file
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct PeerConfig {
+    server_id: u16,
+    // host:port of its mutual TLS interface
+    address: String,
+    // PEM file with the certificate the peer presents, public/cert_<id>.pem of the certificate directory by default
+    #[serde(default)]
+    certificate: Option<PathBuf>,
+}
+
+impl PeerConfig {
+    pub fn server_id(&self) -> u16 {
+        self.server_id
+    }
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+    pub fn certificate(&self) -> Option<&Path> {
+        self.certificate.as_deref()
+    }
+}
+
+fn parse_peer(value: &str) -> Result<PeerConfig, String> {
+    let (server_id, address) = value.split_once('=')
+        .ok_or(format!("'{}' is not in the ID=ADDRESS format", value))?;
+    let server_id = server_id.trim().parse::<u16>()
+        .map_err(|_| format!("'{}' is not a server id", server_id))?;
+
+    Ok(PeerConfig {
+        server_id,
+        address: address.trim().to_string(),
+        certificate: None,
+    })
+}
+
+/// The secret the key shares are encrypted with
+#[derive(Clone, PartialEq)]
+pub enum KeyShareSecret {
+    Passphrase(String),
+    Keyfile(PathBuf),
+}
+
+impl KeyShareSecret {
+    /// The passphrase or the content of the keyfile
+    pub fn read(&self) -> Result<Vec<u8>> {
+        match self {
+            KeyShareSecret::Passphrase(passphrase) => Ok(passphrase.as_bytes().to_vec()),
+            KeyShareSecret::Keyfile(path) => std::fs::read(path)
+                .with_context(|| format!("Unable to read the key share keyfile {}", path.display())),
+        }
+    }
+}
+
+// keeps the passphrase out of the logs
+impl std::fmt::Debug for KeyShareSecret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            KeyShareSecret::Passphrase(_) => write!(f, "Passphrase(..)"),
+            KeyShareSecret::Keyfile(path) => write!(f, "Keyfile({})", path.display()),
+        }
+    }
+}
+
+/// Validated configuration of the server
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    mpc: MPCconfig,
+    port: u16,
+    tls_port: u16,
+    bind_address: IpAddr,
+    workers: usize,
+    json_limit: ByteUnit,
+    cert_dir: PathBuf,
+    key_share_secret: KeyShareSecret,
+    peers: Vec<PeerConfig>,
+}
+
+impl ServerConfig {
+    /// Reads the configuration file given on the command line, if any, and merges it with the command line
+    pub fn load(cli: Cli) -> Result<ServerConfig> {
+        let file = match &cli.config {
+            Some(path) => ConfigFile::read(path)?,
+            None => ConfigFile::default(),
+        };
+
+        let config = ServerConfig::merge(cli, file)?;
+        if !config.cert_dir.is_dir() {
+            bail!("Certificate directory {} does not exist", config.cert_dir.display());
+        }
+        if !config.mpc.key_share_dir().is_dir() {
+            bail!("Key share directory {} does not exist", config.mpc.key_share_dir().display());
+        }
+
+        Ok(config)
+    }
+
+    /// Merges the command line with the configuration file and validates the result
+    pub fn merge(cli: Cli, file: ConfigFile) -> Result<ServerConfig> {
+        let server_id = required(cli.server_id.or(file.server_id), "server_id")?;
+        let threshold = required(cli.threshold.or(file.threshold), "threshold")?;
+        let number_of_parties = required(cli.number_of_parties.or(file.number_of_parties), "number_of_parties")?;
+
+        let default_timeouts = ProtocolTimeouts::default();
+        let timeouts = ProtocolTimeouts::new(
+            timeout(cli.join_timeout.or(file.timeouts.join), default_timeouts.join(), "join")?,
+            timeout(cli.keygen_timeout.or(file.timeouts.keygen), default_timeouts.for_phase(Phase::KeyGen), "keygen")?,
+            timeout(cli.offline_timeout.or(file.timeouts.offline), default_timeouts.for_phase(Phase::Offline), "offline")?,
+            timeout(cli.online_timeout.or(file.timeouts.online), default_timeouts.for_phase(Phase::Online), "online")?,
+        );
+
+        let json_limit = cli.json_limit.or(file.json_limit).unwrap_or(String::from(DEFAULT_JSON_LIMIT));
+        let json_limit = json_limit.parse::<ByteUnit>()
+            .map_err(|_| anyhow!("json_limit '{}' is not a size, use e.g. \"10 MiB\"", json_limit))?;
+
+        // a passphrase given on the command line replaces the keyfile of the file
+        let key_share_secret = match (cli.key_share_passphrase, cli.key_share_keyfile.or(file.key_share_keyfile)) {
+            (Some(passphrase), _) => KeyShareSecret::Passphrase(passphrase),
+            (None, Some(keyfile)) => KeyShareSecret::Keyfile(keyfile),
+            (None, None) => bail!("The key shares are encrypted, set MPC_KEY_SHARE_PASSPHRASE or key_share_keyfile"),
+        };
+
+        let mpc = MPCconfig::new(server_id, threshold, number_of_parties)
+            .with_timeouts(timeouts)
+            .with_presignature_pool_depth(cli.presignature_pool_depth
+                .or(file.presignature_pool_depth)
+                .unwrap_or(DEFAULT_PRESIGNATURE_POOL_DEPTH))
+            .with_key_share_dir(cli.key_share_dir.or(file.key_share_dir).unwrap_or(PathBuf::from(".")));
+
+        let config = ServerConfig {
+            port: cli.port.or(file.port).unwrap_or(8000u16.saturating_add(server_id)),
+            tls_port: cli.tls_port.or(file.tls_port).unwrap_or(3000u16.saturating_add(server_id)),
+            bind_address: cli.bind_address.or(file.bind_address).unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
+            workers: cli.workers.or(file.workers).unwrap_or(DEFAULT_WORKERS),
+            json_limit,
+            cert_dir: cli.cert_dir.or(file.cert_dir).unwrap_or(PathBuf::from("certs")),
+            key_share_secret,
+            peers: if cli.peers.is_empty() { file.peers } else { cli.peers },
+            mpc,
+        };
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<()> {
+        let n = self.mpc.number_of_parties();
+        if n < 2 {
+            bail!("number_of_parties must be at least 2, got {}", n);
+        }
+        if self.mpc.threshold() == 0 || self.mpc.threshold() >= n {
+            bail!("threshold must be between 1 and number_of_parties - 1 ({}), got {}", n - 1, self.mpc.threshold());
+        }
+        // servers above number_of_parties do not take part in the key generation, they receive keys by resharing
+        if self.mpc.server_id() == 0 {
+            bail!("server_id must be at least 1");
+        }
+        if self.port == 0 || self.tls_port == 0 {
+            bail!("port and tls_port must not be 0");
+        }
+        if self.port == self.tls_port {
+            bail!("port and tls_port must differ, both are {}", self.port);
+        }
+        if self.workers == 0 {
+            bail!("workers must be at least 1");
+        }
+        if self.json_limit == ByteUnit::from(0) {
+            bail!("json_limit must not be 0");
+        }
+        if self.key_share_secret == KeyShareSecret::Passphrase(String::new()) {
+            bail!("key_share_passphrase must not be empty");
+        }
+
+        let mut peer_ids = HashSet::new();
+        for peer in &self.peers {
+            if peer.server_id == 0 {
+                bail!("peer server_id must be at least 1");
+            }
+            if peer.server_id == self.mpc.server_id() {
+                bail!("peer {} is this server, list only the other servers", peer.server_id);
+            }
+            if !peer_ids.insert(peer.server_id) {
+                bail!("peer {} is listed more than once", peer.server_id);
+            }
+            if peer.address.trim().is_empty() {
+                bail!("peer {} has an empty address", peer.server_id);
+            }
+        }
+
+        let missing_peers: Vec<u16> = (1..=n)
+            .filter(|id| *id != self.mpc.server_id() && !peer_ids.contains(id))
+            .collect();
+        if !missing_peers.is_empty() {
+            bail!("peers {:?} are missing, every other server must be listed among the peers", missing_peers);
+        }
+
+        Ok(())
+    }
+
+    pub fn mpc(&self) -> &MPCconfig {
+        &self.mpc
+    }
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+    pub fn tls_port(&self) -> u16 {
+        self.tls_port
+    }
+    pub fn bind_address(&self) -> IpAddr {
+        self.bind_address
+    }
+    pub fn workers(&self) -> usize {
+        self.workers
+    }
+    pub fn json_limit(&self) -> ByteUnit {
+        self.json_limit
+    }
+    pub fn cert_dir(&self) -> &Path {
+        &self.cert_dir
+    }
+    pub fn key_share_secret(&self) -> &KeyShareSecret {
+        &self.key_share_secret
+    }
+    pub fn peers(&self) -> &[PeerConfig] {
+        &self.peers
+    }
+}
+
+impl ConfigFile {
+    pub fn read(path: &Path) -> Result<ConfigFile> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Unable to read the configuration file {}", path.display()))?;
+        toml::from_str(&content)
+            .with_context(|| format!("Invalid configuration file {}", path.display()))
+    }
+}
+
+fn required<T>(value: Option<T>, name: &str) -> Result<T> {
+    value.ok_or(anyhow!("{} is missing, set it with --{} or in the configuration file", name, name.replace('_', "-")))
+}
+
+fn timeout(seconds: Option<u64>, default: Duration, name: &str) -> Result<Duration> {
+    match seconds {
+        None => Ok(default),
+        Some(0) => bail!("{} timeout must not be 0", name),
+        Some(s) => Ok(Duration::from_secs(s)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use clap::Parser;
+    use rocket::data::ByteUnit;
+    use crate::communication::phase::Phase;
+    use crate::server_config::{Cli, ConfigFile, KeyShareSecret, ServerConfig};
+
+    const CONFIG: &str = r#"
+        server_id = 2
+        threshold = 1
+        number_of_parties = 3
+        workers = 8
+        json_limit = "10 MiB"
+        key_share_dir = "/var/lib/mpc"
+        key_share_keyfile = "/etc/mpc/key-share.key"
+
+        [timeouts]
+        keygen = 600
+
+        [[peers]]
+        server_id = 1
+        address = "10.0.0.1:3001"
+
+        [[peers]]
+        server_id = 3
+        address = "10.0.0.3:3003"
+    "#;
+
+    fn cli(args: &[&str]) -> Cli {
+        Cli::try_parse_from(std::iter::once("log-signing-mpc").chain(args.iter().copied())).unwrap()
+    }
+
+    fn file(content: &str) -> ConfigFile {
+        toml::from_str(content).unwrap()
+    }
+
+    #[test]
+    fn config_file_with_defaults() {
+        let config = ServerConfig::merge(Cli::default(), file(CONFIG)).unwrap();
+
+        assert_eq!(config.mpc().server_id(), 2);
+        assert_eq!(config.port(), 8002);
+        assert_eq!(config.tls_port(), 3002);
+        assert_eq!(config.workers(), 8);
+        assert_eq!(config.json_limit(), ByteUnit::Mebibyte(10));
+        assert_eq!(config.mpc().key_share_dir().to_str(), Some("/var/lib/mpc"));
+        assert_eq!(config.mpc().timeouts().for_phase(Phase::KeyGen), Duration::from_secs(600));
+        assert_eq!(config.mpc().timeouts().for_phase(Phase::Online), Duration::from_secs(30));
+        assert_eq!(config.peers().len(), 2);
+        assert_eq!(config.peers()[1].address(), "10.0.0.3:3003");
+        assert_eq!(config.key_share_secret(), &KeyShareSecret::Keyfile("/etc/mpc/key-share.key".into()));
+    }
+
+    #[test]
+    fn example_config_is_valid() {
+        let config = ServerConfig::merge(Cli::default(), file(include_str!("../examples/config.toml"))).unwrap();
+
+        assert_eq!(config.mpc().server_id(), 1);
+        assert_eq!(config.peers().len(), 2);
+    }
+
+    #[test]
+    fn command_line_overrides_config_file() {
+        let config = ServerConfig::merge(cli(&["--server-id", "3", "--port", "9000", "--keygen-timeout", "5"]),
+                                         file(CONFIG));
+
+        // server 3 is listed among the peers of the file
+        assert!(config.is_err());
+
+        let config = ServerConfig::merge(cli(&["--port", "9000", "--keygen-timeout", "5"]), file(CONFIG)).unwrap();
+        assert_eq!(config.port(), 9000);
+        assert_eq!(config.mpc().timeouts().for_phase(Phase::KeyGen), Duration::from_secs(5));
+
+        let config = ServerConfig::merge(cli(&["--peer", "1=la1:3001,3=la3:3003"]), file(CONFIG)).unwrap();
+        assert_eq!(config.peers().len(), 2);
+        assert_eq!(config.peers()[0].address(), "la1:3001");
+        assert!(config.peers()[0].certificate().is_none());
+
+        let config = ServerConfig::merge(cli(&["--key-share-passphrase", "secret"]), file(CONFIG)).unwrap();
+        assert_eq!(config.key_share_secret(), &KeyShareSecret::Passphrase(String::from("secret")));
+        assert_eq!(format!("{:?}", config.key_share_secret()), "Passphrase(..)");
+    }
+
+    #[test]
+    fn invalid_config_is_rejected() {
+        let valid = "server_id = 1\nthreshold = 1\nnumber_of_parties = 3\nkey_share_keyfile = \"key\"\n";
+        let peer_2 = "[[peers]]\nserver_id = 2\naddress = \"b\"\n";
+        let peer_3 = "[[peers]]\nserver_id = 3\naddress = \"c\"\n";
+        let with = |settings: &str, peers: &str| format!("{}{}{}{}", valid, settings, peer_2, peers);
+
+        assert!(ServerConfig::merge(Cli::default(), file(&with("", peer_3))).is_ok());
+        // a server added later receives its keys by resharing
+        assert!(ServerConfig::merge(Cli::default(), file(&with("", &format!("{}[[peers]]\nserver_id = 4\naddress = \"d\"\n", peer_3)))).is_ok());
+
+        let missing = ServerConfig::merge(Cli::default(), file("server_id = 1\nthreshold = 1\n")).unwrap_err();
+        assert!(missing.to_string().contains("number_of_parties"));
+
+        for invalid in [with("", ""),
+                        with("", &format!("{}{}", peer_3, peer_3)),
+                        with("", &format!("{}[[peers]]\nserver_id = 1\naddress = \"a\"\n", peer_3)),
+                        with("", &format!("{}[[peers]]\nserver_id = 0\naddress = \"d\"\n", peer_3)),
+                        with("", "[[peers]]\nserver_id = 3\naddress = \" \"\n"),
+                        with("workers = 0\n", peer_3),
+                        with("port = 3001\n", peer_3),
+                        with("json_limit = \"lots\"\n", peer_3),
+                        with("[timeouts]\njoin = 0\n", peer_3),
+                        with("", peer_3).replace("threshold = 1", "threshold = 3"),
+                        with("", peer_3).replace("threshold = 1", "threshold = 0"),
+                        with("", peer_3).replace("server_id = 1\n", "server_id = 4\n"),
+                        with("", peer_3).replace("server_id = 1\n", "server_id = 0\n")] {
+            assert!(ServerConfig::merge(Cli::default(), file(&invalid)).is_err(), "accepted: {}", invalid);
+        }
+
+        let without_secret = with("", peer_3).replace("key_share_keyfile = \"key\"\n", "");
+        assert!(ServerConfig::merge(Cli::default(), file(&without_secret)).is_err());
+        assert!(ServerConfig::merge(cli(&["--key-share-passphrase", ""]), file(&without_secret)).is_err());
+
+        assert!(toml::from_str::<ConfigFile>("servr_id = 1").is_err());
+        assert!(toml::from_str::<ConfigFile>("key_share_passphrase = \"secret\"").is_err());
+        assert!(Cli::try_parse_from(["log-signing-mpc", "--server-id", "one"]).is_err());
+        assert!(Cli::try_parse_from(["log-signing-mpc", "--peer", "127.0.0.1:3002"]).is_err());
+    }
+}